@@ -0,0 +1,185 @@
+use std::path::Path;
+
+use clap::{ArgEnum, Args};
+use image::{imageops, DynamicImage, Rgba32FImage};
+use tracing::info;
+
+use crate::{
+    compressor::compress_to_container,
+    cubemap::{faces_from_cross, faces_from_equirect, FACE_COUNT},
+    image::open_image,
+    Backend, CompressionFormat, CompressionOptions, ContainerFormat, MipWrap, TextureShape,
+    TextureType,
+};
+
+/// How the faces of a cubemap are laid out in the input images
+#[derive(Clone, Copy, Debug, ArgEnum, strum::Display, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+pub enum Layout {
+    /// One image per face, layer or slice
+    Separate,
+    /// A single image with the faces in a horizontal (4x3) or vertical (3x4) cross
+    Cross,
+    /// A single equirectangular (latitude-longitude) panorama
+    Equirect,
+}
+
+#[derive(Args, Debug)]
+pub struct Assemble {
+    /// Input images, in +X, -X, +Y, -Y, +Z, -Z order for separate cubemap faces
+    #[clap(required = true)]
+    pub inputs: Vec<String>,
+    /// Kind of texture to assemble
+    #[clap(long, arg_enum)]
+    shape: TextureShape,
+    /// How cubemap faces are laid out in the inputs
+    #[clap(long, arg_enum, default_value_t = Layout::Separate)]
+    layout: Layout,
+    /// Size to resample each cubemap face to, for cubemaps only. Defaults to the size of the input
+    /// faces, or a quarter of the width of an equirectangular panorama
+    #[clap(long)]
+    face_size: Option<u32>,
+    /// How to treat the image data. Defaults to hdr for floating point inputs and srgb otherwise
+    #[clap(long, arg_enum)]
+    texture_type: Option<TextureType>,
+    /// Path to write the compressed texture to
+    #[clap(short, long)]
+    pub output: String,
+}
+
+impl Assemble {
+    pub fn process(
+        &self,
+        backend: Backend,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<(), String> {
+        let images = self
+            .inputs
+            .iter()
+            .map(|input| open_image(Path::new(input)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let texture_type = self.texture_type.unwrap_or_else(|| {
+            if images.iter().any(|image| {
+                matches!(
+                    image,
+                    DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+                )
+            }) {
+                TextureType::Hdr
+            } else {
+                TextureType::Srgb
+            }
+        });
+        let layers = self.layers(images.iter().map(|image| image.to_rgba32f()).collect())?;
+
+        let mut options = options.clone();
+        if matches!(self.shape, TextureShape::Cubemap) && options.mipmaps.mip_wrap.is_none() {
+            options.mipmaps.mip_wrap = Some(MipWrap::Clamp);
+        }
+
+        info!(
+            "Assembling {} {}x{} layers into a {} at {}",
+            layers.len(),
+            layers[0].width(),
+            layers[0].height(),
+            self.shape,
+            self.output
+        );
+        let working_dir = std::env::current_dir()
+            .map_err(|e| format!("Failed to get the current directory - {}", e))?;
+        let compressor = backend.compressor()?;
+        let commands = compress_to_container(
+            &working_dir,
+            Path::new(&self.output),
+            compression_format,
+            container_format,
+            &options,
+            compressor.writes_dds(),
+            |dst_path, container_format, options| {
+                compressor.compress_layers(
+                    &working_dir,
+                    &layers,
+                    self.shape,
+                    dst_path,
+                    texture_type,
+                    compression_format,
+                    container_format,
+                    options,
+                )
+            },
+        )?;
+        for command in commands {
+            info!("Ran {}", command);
+        }
+        Ok(())
+    }
+
+    /// Splits or resamples the inputs into layers of equal size
+    fn layers(&self, mut images: Vec<Rgba32FImage>) -> Result<Vec<Rgba32FImage>, String> {
+        if self.face_size.is_some() && !matches!(self.shape, TextureShape::Cubemap) {
+            return Err(String::from("--face-size is only supported for cubemaps"));
+        }
+        let layers = match self.layout {
+            Layout::Separate => images,
+            _ if !matches!(self.shape, TextureShape::Cubemap) => {
+                return Err(format!(
+                    "The {} layout is only supported for cubemaps",
+                    self.layout
+                ));
+            }
+            _ if images.len() != 1 => {
+                return Err(format!(
+                    "The {} layout takes a single input image, got {}",
+                    self.layout,
+                    images.len()
+                ));
+            }
+            Layout::Cross => faces_from_cross(&images[0])?,
+            Layout::Equirect => {
+                let image = images.remove(0);
+                let face_size = self.face_size.unwrap_or(image.width() / 4).max(1);
+                faces_from_equirect(&image, face_size)
+            }
+        };
+        let layers = match (self.layout, self.face_size) {
+            (Layout::Separate | Layout::Cross, Some(face_size)) => layers
+                .iter()
+                .map(|layer| {
+                    imageops::resize(layer, face_size, face_size, imageops::FilterType::Triangle)
+                })
+                .collect(),
+            _ => layers,
+        };
+        let (width, height) = layers[0].dimensions();
+        if let Some(layer) = layers
+            .iter()
+            .find(|layer| layer.dimensions() != (width, height))
+        {
+            return Err(format!(
+                "All layers must be the same size, got {}x{} and {}x{}",
+                width,
+                height,
+                layer.width(),
+                layer.height()
+            ));
+        }
+        if matches!(self.shape, TextureShape::Cubemap) {
+            if layers.len() != FACE_COUNT {
+                return Err(format!(
+                    "A cubemap needs {} faces, got {}",
+                    FACE_COUNT,
+                    layers.len()
+                ));
+            }
+            if width != height {
+                return Err(format!(
+                    "Cubemap faces must be square, got {}x{}",
+                    width, height
+                ));
+            }
+        }
+        Ok(layers)
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use image::RgbaImage;
+use tracing::warn;
+use which::which;
+
+use crate::{
+    coverage::full_mip_levels,
+    image::open_image,
+    ktx::{astc_dfd, read_ktx2_level0, write_ktx2},
+    resize::resize_image,
+    run_command, write_layers, CompressError, CompressionFormat, CompressionOptions, Compressor,
+    ContainerFormat, TextureType,
+};
+
+/// Names of the astcenc executable, which release builds suffix with the instruction set
+const CLI_NAMES: [&str; 5] = [
+    "astcenc",
+    "astcenc-avx2",
+    "astcenc-sse4.1",
+    "astcenc-sse2",
+    "astcenc-neon",
+];
+
+/// 2D ASTC block sizes in the order of their Vulkan formats
+const BLOCKS: [(u32, u32); 14] = [
+    (4, 4),
+    (5, 4),
+    (5, 5),
+    (6, 5),
+    (6, 6),
+    (8, 5),
+    (8, 6),
+    (8, 8),
+    (10, 5),
+    (10, 6),
+    (10, 8),
+    (10, 10),
+    (12, 10),
+    (12, 12),
+];
+const VK_FORMAT_ASTC_4X4_UNORM_BLOCK: u32 = 157;
+const VK_FORMAT_ASTC_4X4_SFLOAT_BLOCK: u32 = 1000066000;
+
+const ASTC_MAGIC: [u8; 4] = [0x13, 0xAB, 0xA1, 0x5C];
+const ASTC_HEADER_SIZE: usize = 16;
+
+pub struct Astcenc {
+    cli_path: PathBuf,
+}
+
+impl Astcenc {
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            cli_path: CLI_NAMES
+                .iter()
+                .find_map(|name| which(name).ok())
+                .ok_or_else(|| {
+                    String::from(
+                        "Failed to find the astcenc CLI tool. Make sure it is in your PATH.",
+                    )
+                })?,
+        })
+    }
+}
+
+fn vk_format(block: (u32, u32), texture_type: TextureType, hdr: bool) -> u32 {
+    let index = BLOCKS.iter().position(|b| *b == block).unwrap() as u32;
+    if hdr {
+        VK_FORMAT_ASTC_4X4_SFLOAT_BLOCK + index
+    } else {
+        VK_FORMAT_ASTC_4X4_UNORM_BLOCK
+            + index * 2
+            + matches!(texture_type, TextureType::Srgb) as u32
+    }
+}
+
+/// Reads the block data of a .astc file
+fn read_astc(path: &Path) -> Result<Vec<u8>, String> {
+    let mut bytes = std::fs::read(path)
+        .map_err(|e| format!("Failed to read ASTC file {} - {}", path.display(), e))?;
+    if !bytes.starts_with(&ASTC_MAGIC) || bytes.len() < ASTC_HEADER_SIZE {
+        return Err(format!("{} is not an ASTC file", path.display()));
+    }
+    Ok(bytes.split_off(ASTC_HEADER_SIZE))
+}
+
+fn write_astc(
+    path: &Path,
+    (block_width, block_height): (u32, u32),
+    (width, height): (u32, u32),
+    data: &[u8],
+) -> Result<(), String> {
+    let mut bytes = ASTC_MAGIC.to_vec();
+    bytes.extend_from_slice(&[block_width as u8, block_height as u8, 1]);
+    for dimension in [width, height, 1] {
+        bytes.extend_from_slice(&dimension.to_le_bytes()[..3]);
+    }
+    bytes.extend_from_slice(data);
+    std::fs::write(path, bytes)
+        .map_err(|e| format!("Failed to write ASTC file {} - {}", path.display(), e))
+}
+
+impl Compressor for Astcenc {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let block = match compression_format.astc_block() {
+            Some(block) if matches!(container_format, ContainerFormat::Ktx2) => block,
+            _ => {
+                return Err(format!(
+                "Unsupported format {:?} {:?} - must be an ASTC codec with a block size, and {}",
+                compression_format,
+                container_format,
+                ContainerFormat::Ktx2,
+            )
+                .into())
+            }
+        };
+        let hdr = compression_format.is_hdr();
+        if matches!(texture_type, TextureType::Hdr) && !hdr {
+            return Err(format!(
+                "HDR images need an HDR codec rather than {}",
+                compression_format
+            )
+            .into());
+        }
+        if options
+            .supercompression
+            .zstd_level
+            .is_some_and(|level| level > 0)
+        {
+            warn!("Zstandard supercompression is not supported by astcenc and is ignored");
+        }
+
+        // astcenc compresses a single image, so klafsa builds the mip chain
+        let image = open_image(&working_dir.join(src_path))?;
+        let mipmaps = &options.mipmaps;
+        let level_count = if mipmaps.enabled() {
+            mipmaps
+                .levels(image.width(), image.height())
+                .unwrap_or_else(|| full_mip_levels(image.width(), image.height()))
+        } else {
+            1
+        };
+        let levels = (0..level_count)
+            .map(|level| {
+                let (width, height) = (
+                    (image.width() >> level).max(1),
+                    (image.height() >> level).max(1),
+                );
+                if level == 0 {
+                    image.to_rgba32f()
+                } else {
+                    resize_image(&image, width, height, texture_type).to_rgba32f()
+                }
+            })
+            .collect::<Vec<_>>();
+        let levels_dir = dst_path.with_extension("levels");
+        let mut commands = Vec::new();
+        let result = write_layers(working_dir, &levels_dir, &levels, texture_type)
+            .map_err(CompressError::from)
+            .and_then(|level_paths| {
+                let mut level_data = Vec::new();
+                for level_path in level_paths {
+                    let astc_path = level_path.with_extension("astc");
+                    let mut command = Command::new(&self.cli_path);
+                    command.current_dir(working_dir);
+                    command.args([
+                        match texture_type {
+                            _ if hdr => "-ch",
+                            TextureType::Srgb => "-cs",
+                            _ => "-cl",
+                        },
+                        level_path.to_str().unwrap(),
+                        astc_path.to_str().unwrap(),
+                        format!("{}x{}", block.0, block.1).as_str(),
+                        format!("-{}", options.quality.astc_preset()).as_str(),
+                        "-silent",
+                    ]);
+                    if matches!(texture_type, TextureType::NormalMap) {
+                        command.arg("-normal");
+                    }
+                    commands.push(run_command(&mut command)?);
+                    level_data.push(read_astc(&working_dir.join(&astc_path))?);
+                }
+                write_ktx2(
+                    &working_dir.join(dst_path),
+                    vk_format(block, texture_type, hdr),
+                    (image.width(), image.height()),
+                    &astc_dfd(
+                        block,
+                        !hdr && matches!(texture_type, TextureType::Srgb),
+                        hdr,
+                    ),
+                    &level_data,
+                )?;
+                Ok(())
+            });
+        let _ = std::fs::remove_dir_all(working_dir.join(&levels_dir));
+        result.map_err(|e| e.after(&commands)).map(|_| commands)
+    }
+
+    /// `-normal` stores X in the color channels and Y in alpha
+    fn normal_map_swizzle(&self, compression_format: CompressionFormat) -> Option<&'static str> {
+        compression_format
+            .astc_block()
+            .filter(|_| !compression_format.is_hdr())
+            .map(|_| "ra01")
+    }
+
+    fn decompress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        let block = compression_format.astc_block().ok_or_else(|| {
+            format!(
+                "Decompressing {} is not supported by astcenc",
+                compression_format
+            )
+        })?;
+        let level = read_ktx2_level0(&working_dir.join(src_path))?;
+        let astc_path = src_path.with_extension("decoded.astc");
+        let decoded_path = src_path.with_extension("decoded.png");
+        write_astc(
+            &working_dir.join(&astc_path),
+            block,
+            level.dimensions,
+            &level.data,
+        )?;
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        command.args([
+            if compression_format.is_hdr() {
+                "-dh"
+            } else if level.vk_format % 2 == 0 {
+                // sRGB formats follow their UNORM counterparts
+                "-ds"
+            } else {
+                "-dl"
+            },
+            astc_path.to_str().unwrap(),
+            decoded_path.to_str().unwrap(),
+            "-silent",
+        ]);
+        let result = run_command(&mut command)
+            .map_err(String::from)
+            .and_then(|_| {
+                image::open(working_dir.join(&decoded_path))
+                    .map(|image| image.to_rgba8())
+                    .map_err(|e| format!("Failed to read {} - {}", decoded_path.display(), e))
+            });
+        let _ = std::fs::remove_file(working_dir.join(&astc_path));
+        let _ = std::fs::remove_file(working_dir.join(&decoded_path));
+        result
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use image::RgbaImage;
+use which::which;
+
+use crate::{
+    run_command, CompressError, CompressionFormat, CompressionOptions, Compressor, ContainerFormat,
+    TextureType,
+};
+
+/// The avifenc command line tool from libavif, which decodes with avifdec
+pub struct Avifenc {
+    cli_path: PathBuf,
+}
+
+impl Avifenc {
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            cli_path: which("avifenc").map_err(|e| {
+                format!(
+                    "Failed to find the avifenc CLI tool. Make sure it is in your PATH. {:?}",
+                    e
+                )
+            })?,
+        })
+    }
+}
+
+impl Compressor for Avifenc {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        if !matches!(
+            compression_format,
+            CompressionFormat::Avif | CompressionFormat::Aviflossless
+        ) || !matches!(container_format, ContainerFormat::Avif)
+        {
+            return Err(format!(
+                "Unsupported format {:?} {:?} - must be {} or {}, and {}",
+                compression_format,
+                container_format,
+                CompressionFormat::Avif,
+                CompressionFormat::Aviflossless,
+                ContainerFormat::Avif,
+            )
+            .into());
+        }
+        if matches!(texture_type, TextureType::Hdr) {
+            return Err(String::from("HDR textures are not supported by avifenc").into());
+        }
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        let quality = &options.quality;
+        command.args(["--speed", quality.avif_speed().to_string().as_str()]);
+        if matches!(compression_format, CompressionFormat::Aviflossless) {
+            command.arg("--lossless");
+        } else {
+            // Chroma subsampling would blur channels that hold unrelated data, such as normals
+            // or occlusion, roughness and metalness
+            command.args([
+                "-q",
+                quality.web_quality().to_string().as_str(),
+                "--yuv",
+                "444",
+            ]);
+        }
+        command.args([src_path, dst_path]);
+        run_command(&mut command).map(|command_line| vec![command_line])
+    }
+
+    fn decompress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        _compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        let avifdec = which("avifdec").map_err(|e| {
+            format!(
+                "Failed to find the avifdec CLI tool. Make sure it is in your PATH. {:?}",
+                e
+            )
+        })?;
+        let decoded_path = src_path.with_extension("decoded.png");
+        let mut command = Command::new(avifdec);
+        command.current_dir(working_dir);
+        command.args([src_path, &decoded_path]);
+        run_command(&mut command)?;
+        let image = image::open(working_dir.join(&decoded_path))
+            .map(|image| image.to_rgba8())
+            .map_err(|e| format!("Failed to read {} - {}", decoded_path.display(), e));
+        let _ = std::fs::remove_file(working_dir.join(&decoded_path));
+        image
+    }
+}
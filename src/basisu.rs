@@ -3,14 +3,21 @@ use std::{
     process::Command,
 };
 
+use image::{Rgba32FImage, RgbaImage};
 use which::which;
 
-use crate::{CompressionFormat, Compressor, ContainerFormat, TextureType};
+use crate::{
+    run_command, write_layers, CompressError, CompressionFormat, CompressionOptions, Compressor,
+    ContainerFormat, MipWrap, TextureShape, TextureType,
+};
+
+/// basisu's transcoder_texture_format value for uncompressed RGBA32
+const TRANSCODER_FORMAT_RGBA32: &str = "13";
 
-pub const SUPPORTED_COMPRESSION_FORMATS: [CompressionFormat; 2] =
+pub(crate) const SUPPORTED_COMPRESSION_FORMATS: [CompressionFormat; 2] =
     [CompressionFormat::Etc1s, CompressionFormat::Uastc];
 
-pub const SUPPORTED_CONTAINER_FORMATS: [ContainerFormat; 2] =
+pub(crate) const SUPPORTED_CONTAINER_FORMATS: [ContainerFormat; 2] =
     [ContainerFormat::Basis, ContainerFormat::Ktx2];
 
 pub struct Basisu {
@@ -30,16 +37,17 @@ impl Basisu {
     }
 }
 
-impl Compressor for Basisu {
-    fn compress(
+impl Basisu {
+    /// Builds the command for everything but the input files
+    fn command(
         &self,
         working_dir: &Path,
-        src_path: &Path,
         dst_path: &Path,
         texture_type: TextureType,
         compression_format: CompressionFormat,
         container_format: ContainerFormat,
-    ) -> Result<(), String> {
+        options: &CompressionOptions,
+    ) -> Result<Command, String> {
         if !SUPPORTED_COMPRESSION_FORMATS.contains(&compression_format)
             || !SUPPORTED_CONTAINER_FORMATS.contains(&container_format)
         {
@@ -53,36 +61,178 @@ impl Compressor for Basisu {
         }
         let mut command = Command::new(&self.cli_path);
         command.current_dir(working_dir);
-        command.args([
-            src_path.to_str().unwrap(),
-            "-output_file",
-            dst_path.to_str().unwrap(),
-            "-mipmap",
-            "-mip_fast",
-        ]);
+        command.args(["-output_file", dst_path.to_str().unwrap()]);
+        let mipmaps = &options.mipmaps;
+        if mipmaps.enabled() {
+            command.arg("-mipmap");
+            // -mip_fast filters each level from the previous one, so it is left out when a
+            // filter is chosen
+            match mipmaps.mip_filter {
+                Some(mip_filter) => {
+                    command.args(["-mip_filter", mip_filter.to_string().as_str()]);
+                }
+                None => {
+                    command.arg("-mip_fast");
+                }
+            }
+            if let Some(mip_min_size) = mipmaps.mip_min_size {
+                command.args(["-mip_smallest", mip_min_size.to_string().as_str()]);
+            }
+            // NOTE: basisu wraps by default and has no reflect mode
+            if matches!(mipmaps.mip_wrap, Some(MipWrap::Clamp)) {
+                command.arg("-mip_clamp");
+            }
+        }
+        match compression_format {
+            CompressionFormat::Etc1s => command.args([
+                "-q",
+                options.quality.etc1s_quality().to_string().as_str(),
+                "-comp_level",
+                options.quality.etc1s_effort().to_string().as_str(),
+            ]),
+            _ => command.args([
+                "-uastc",
+                "-uastc_level",
+                options.quality.uastc_level().to_string().as_str(),
+            ]),
+        };
         if matches!(compression_format, CompressionFormat::Uastc) {
-            command.arg("-uastc");
+            let supercompression = &options.supercompression;
+            if let Some(rdo_lambda) = supercompression.rdo_lambda {
+                command.args(["-uastc_rdo_l", rdo_lambda.to_string().as_str()]);
+            }
+            if let Some(rdo_dict_size) = supercompression.rdo_dict_size {
+                command.args(["-uastc_rdo_d", rdo_dict_size.to_string().as_str()]);
+            }
         }
         if matches!(container_format, ContainerFormat::Ktx2) {
             command.arg("-ktx2");
+            if matches!(compression_format, CompressionFormat::Uastc) {
+                match options.supercompression.zstd_level {
+                    Some(0) => {
+                        command.arg("-ktx2_no_zstandard");
+                    }
+                    Some(zstd_level) => {
+                        command.args(["-ktx2_zstandard_level", zstd_level.to_string().as_str()]);
+                    }
+                    None => {}
+                }
+            }
         }
         match texture_type {
             TextureType::Srgb => command.arg("-mip_srgb"),
             TextureType::Linear => command.args(["-linear", "-mip_linear"]),
             TextureType::NormalMap => command.args(["-normal_map", "-linear", "-mip_linear"]),
+            TextureType::Hdr => {
+                return Err(String::from("HDR textures are not supported by basisu"));
+            }
         };
-        match command.output() {
-            Ok(output) => {
-                if output.status.success() {
-                    Ok(())
-                } else {
-                    Err(format!(
-                        "Failed to execute command:\n{}",
-                        std::str::from_utf8(&output.stderr).unwrap()
-                    ))
-                }
+        Ok(command)
+    }
+}
+
+impl Compressor for Basisu {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let mut command = self.command(
+            working_dir,
+            dst_path,
+            texture_type,
+            compression_format,
+            container_format,
+            options,
+        )?;
+        command.arg(src_path);
+        run_command(&mut command).map(|command_line| vec![command_line])
+    }
+
+    fn compress_layers(
+        &self,
+        working_dir: &Path,
+        layers: &[Rgba32FImage],
+        shape: TextureShape,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let tex_type = match shape {
+            TextureShape::Cubemap => "cubemap",
+            TextureShape::Array => "2darray",
+            // basisu writes the slices of -tex_type 3d as the layers of a 2D array in KTX2
+            TextureShape::Volume => {
+                return Err(format!("{} textures are not supported by basisu", shape).into())
             }
-            Err(e) => Err(format!("Failed to execute command: {:?}", e)),
-        }
+        };
+        let mut command = self.command(
+            working_dir,
+            dst_path,
+            texture_type,
+            compression_format,
+            container_format,
+            options,
+        )?;
+        let layers_dir = dst_path.with_extension("layers");
+        let layer_paths = write_layers(working_dir, &layers_dir, layers, texture_type)?;
+        command.args(["-tex_type", tex_type]);
+        command.args(&layer_paths);
+        let result = run_command(&mut command).map(|command_line| vec![command_line]);
+        let _ = std::fs::remove_dir_all(working_dir.join(&layers_dir));
+        result
+    }
+
+    fn decompress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        _compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        let unpack_dir = src_path.with_extension("unpacked");
+        std::fs::create_dir_all(working_dir.join(&unpack_dir)).map_err(|e| {
+            format!(
+                "Failed to recursively create directory: {} - {}",
+                unpack_dir.display(),
+                e
+            )
+        })?;
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        command.args([
+            "-unpack",
+            "-no_ktx",
+            "-format_only",
+            TRANSCODER_FORMAT_RGBA32,
+            "-output_path",
+            unpack_dir.to_str().unwrap(),
+            src_path.to_str().unwrap(),
+        ]);
+        let image = run_command(&mut command)
+            .map_err(String::from)
+            .and_then(|_| {
+                // basisu names unpacked files <name>_unpacked_rgba_<format>_<level>_<image>.png
+                let level_0 = std::fs::read_dir(working_dir.join(&unpack_dir))
+                    .map_err(|e| format!("Failed to read {} - {}", unpack_dir.display(), e))?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .find(|path| {
+                        path.file_name()
+                            .and_then(|name| name.to_str())
+                            .is_some_and(|name| name.ends_with("_unpacked_rgba_RGBA32_0_0000.png"))
+                    })
+                    .ok_or_else(|| format!("basisu did not unpack {}", src_path.display()))?;
+                image::open(&level_0)
+                    .map(|image| image.to_rgba8())
+                    .map_err(|e| format!("Failed to read {} - {}", level_0.display(), e))
+            });
+        let _ = std::fs::remove_dir_all(working_dir.join(&unpack_dir));
+        image
     }
 }
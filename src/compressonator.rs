@@ -0,0 +1,139 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use image::RgbaImage;
+use which::which;
+
+use crate::{
+    coverage::full_mip_levels, dds::set_dds_srgb, ktx::set_ktx2_srgb, run_command, CompressError,
+    CompressionFormat, CompressionOptions, Compressor, ContainerFormat, TextureType,
+};
+
+pub(crate) const SUPPORTED_COMPRESSION_FORMATS: [CompressionFormat; 7] = [
+    CompressionFormat::Bc1,
+    CompressionFormat::Bc3,
+    CompressionFormat::Bc4,
+    CompressionFormat::Bc5,
+    CompressionFormat::Bc6h,
+    CompressionFormat::Bc6hs,
+    CompressionFormat::Bc7,
+];
+const SUPPORTED_CONTAINER_FORMATS: [ContainerFormat; 2] =
+    [ContainerFormat::Dds, ContainerFormat::Ktx2];
+
+/// AMD's Compressonator command line tool
+pub struct Compressonator {
+    cli_path: PathBuf,
+}
+
+impl Compressonator {
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            cli_path: which("compressonatorcli").map_err(|e| {
+                format!(
+                    "Failed to find the compressonatorcli CLI tool. Make sure it is in your PATH. \
+                     {:?}",
+                    e
+                )
+            })?,
+        })
+    }
+}
+
+impl Compressor for Compressonator {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        if !SUPPORTED_COMPRESSION_FORMATS.contains(&compression_format)
+            || !SUPPORTED_CONTAINER_FORMATS.contains(&container_format)
+        {
+            return Err(format!(
+                "Unsupported format {:?} {:?} - must be one of {:?} and one of {:?}",
+                compression_format,
+                container_format,
+                SUPPORTED_COMPRESSION_FORMATS,
+                SUPPORTED_CONTAINER_FORMATS,
+            )
+            .into());
+        }
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        command.args([
+            "-fd",
+            match compression_format {
+                CompressionFormat::Bc1 => "BC1",
+                CompressionFormat::Bc3 => "BC3",
+                CompressionFormat::Bc4 => "BC4",
+                CompressionFormat::Bc5 => "BC5",
+                CompressionFormat::Bc6h => "BC6H",
+                CompressionFormat::Bc6hs => "BC6H_SF",
+                _ => "BC7",
+            },
+            "-Quality",
+            (options.quality.percentage() as f32 / 100.0)
+                .to_string()
+                .as_str(),
+        ]);
+        let mipmaps = &options.mipmaps;
+        if mipmaps.enabled() {
+            let (width, height) = image::image_dimensions(working_dir.join(src_path))
+                .map_err(|e| format!("Failed to read {} - {}", src_path.display(), e))?;
+            let levels = mipmaps
+                .levels(width, height)
+                .unwrap_or_else(|| full_mip_levels(width, height));
+            command.args(["-miplevels", levels.to_string().as_str()]);
+        } else {
+            command.arg("-nomipmap");
+        }
+        command.args([src_path, dst_path]);
+        let command_line = run_command(&mut command)?;
+        // compressonatorcli always writes UNORM formats
+        let result = match container_format {
+            _ if !matches!(texture_type, TextureType::Srgb) => Ok(()),
+            ContainerFormat::Dds => set_dds_srgb(&working_dir.join(dst_path)),
+            _ => set_ktx2_srgb(&working_dir.join(dst_path)),
+        };
+        match result {
+            Ok(()) => Ok(vec![command_line]),
+            Err(e) => Err(CompressError::from(e).after(&[command_line])),
+        }
+    }
+
+    fn writes_dds(&self) -> bool {
+        true
+    }
+
+    fn normal_map_swizzle(&self, compression_format: CompressionFormat) -> Option<&'static str> {
+        match compression_format {
+            CompressionFormat::Bc5 => Some("rg01"),
+            _ => None,
+        }
+    }
+
+    fn decompress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        _compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        let decoded_path = src_path.with_extension("decoded.png");
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        command.args([src_path, &decoded_path]);
+        run_command(&mut command)?;
+        let image = image::open(working_dir.join(&decoded_path))
+            .map(|image| image.to_rgba8())
+            .map_err(|e| format!("Failed to read {} - {}", decoded_path.display(), e));
+        let _ = std::fs::remove_file(working_dir.join(&decoded_path));
+        image
+    }
+}
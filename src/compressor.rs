@@ -1,30 +1,90 @@
-use std::path::Path;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 use clap::ArgEnum;
+use image::{DynamicImage, Rgba32FImage, RgbaImage};
+use serde::{Deserialize, Serialize};
 
-use crate::Backend;
+use crate::{dds::convert_ktx2_to_dds, ktx::write_ktx1_rgba32f, Backend, CompressionOptions};
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, ArgEnum, Deserialize, Serialize, strum::Display, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
 pub enum TextureType {
     Srgb,
     Linear,
     NormalMap,
+    /// Floating point color data, kept at full range
+    Hdr,
+}
+
+/// Kinds of textures made up of several images of the same size
+#[derive(Clone, Copy, Debug, ArgEnum, Serialize, strum::Display, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum TextureShape {
+    /// Six faces in +X, -X, +Y, -Y, +Z, -Z order
+    Cubemap,
+    /// A 2D texture array with one layer per image
+    Array,
+    /// A 3D texture with one depth slice per image
+    Volume,
 }
 
 #[derive(
-    Clone, Copy, Debug, ArgEnum, strum::Display, strum::EnumIter, strum::EnumString, PartialEq, Eq,
+    Clone,
+    Copy,
+    Debug,
+    ArgEnum,
+    Deserialize,
+    Serialize,
+    strum::Display,
+    strum::EnumIter,
+    strum::EnumString,
+    PartialEq,
+    Eq,
 )]
+#[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 pub enum CompressionFormat {
     Astc,
     Astc4x4,
+    Astc5x4,
     Astc5x5,
+    Astc6x5,
     Astc6x6,
+    Astc8x5,
+    Astc8x6,
     Astc8x8,
+    Astc10x5,
+    Astc10x6,
+    Astc10x8,
+    Astc10x10,
+    Astc12x10,
+    Astc12x12,
+    Astc4x4hdr,
+    Astc5x4hdr,
+    Astc5x5hdr,
+    Astc6x5hdr,
+    Astc6x6hdr,
+    Astc8x5hdr,
+    Astc8x6hdr,
+    Astc8x8hdr,
+    Astc10x5hdr,
+    Astc10x6hdr,
+    Astc10x8hdr,
+    Astc10x10hdr,
+    Astc12x10hdr,
+    Astc12x12hdr,
     Bc1,
     Bc3,
     Bc4,
     Bc5,
+    Bc6h,
+    Bc6hs,
     Bc7,
     Etc1s,
     Etc2r,
@@ -32,16 +92,33 @@ pub enum CompressionFormat {
     Etc2rgb,
     Etc2rgba,
     Uastc,
+    /// Lossy WebP image, for `EXT_texture_webp`
+    Webp,
+    /// Lossless WebP image, for `EXT_texture_webp`
+    Webplossless,
+    /// Lossy AVIF image, for `EXT_texture_avif`
+    Avif,
+    /// Lossless AVIF image, for `EXT_texture_avif`
+    Aviflossless,
 }
 
 impl CompressionFormat {
     pub fn backend(&self) -> Option<Backend> {
         Some(match *self {
             CompressionFormat::Astc4x4 => Backend::Kram,
+            CompressionFormat::Astc5x5 => Backend::Kram,
+            CompressionFormat::Astc6x6 => Backend::Kram,
+            CompressionFormat::Astc8x8 => Backend::Kram,
+            CompressionFormat::Astc4x4hdr => Backend::Kram,
+            CompressionFormat::Astc5x5hdr => Backend::Kram,
+            CompressionFormat::Astc6x6hdr => Backend::Kram,
+            CompressionFormat::Astc8x8hdr => Backend::Kram,
             CompressionFormat::Bc1 => Backend::Kram,
             CompressionFormat::Bc3 => Backend::Kram,
             CompressionFormat::Bc4 => Backend::Kram,
             CompressionFormat::Bc5 => Backend::Kram,
+            CompressionFormat::Bc6h => Backend::Kram,
+            CompressionFormat::Bc6hs => Backend::Kram,
             CompressionFormat::Bc7 => Backend::Kram,
             CompressionFormat::Etc1s => Backend::Basisu,
             CompressionFormat::Etc2r => Backend::Kram,
@@ -49,27 +126,186 @@ impl CompressionFormat {
             CompressionFormat::Etc2rgb => Backend::Kram,
             CompressionFormat::Etc2rgba => Backend::Kram,
             CompressionFormat::Uastc => Backend::Basisu,
+            CompressionFormat::Webp => Backend::Cwebp,
+            CompressionFormat::Webplossless => Backend::Cwebp,
+            CompressionFormat::Avif => Backend::Avifenc,
+            CompressionFormat::Aviflossless => Backend::Avifenc,
+            format if format.astc_block().is_some() => Backend::Astcenc,
+            _ => return None,
+        })
+    }
+
+    /// Whether this codec stores floating point data for HDR textures
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            *self,
+            CompressionFormat::Astc4x4hdr
+                | CompressionFormat::Astc5x4hdr
+                | CompressionFormat::Astc5x5hdr
+                | CompressionFormat::Astc6x5hdr
+                | CompressionFormat::Astc6x6hdr
+                | CompressionFormat::Astc8x5hdr
+                | CompressionFormat::Astc8x6hdr
+                | CompressionFormat::Astc8x8hdr
+                | CompressionFormat::Astc10x5hdr
+                | CompressionFormat::Astc10x6hdr
+                | CompressionFormat::Astc10x8hdr
+                | CompressionFormat::Astc10x10hdr
+                | CompressionFormat::Astc12x10hdr
+                | CompressionFormat::Astc12x12hdr
+                | CompressionFormat::Bc6h
+                | CompressionFormat::Bc6hs
+        )
+    }
+
+    /// Whether this is one of the BC codecs of desktop GPUs
+    pub fn is_bc(&self) -> bool {
+        matches!(
+            *self,
+            CompressionFormat::Bc1
+                | CompressionFormat::Bc3
+                | CompressionFormat::Bc4
+                | CompressionFormat::Bc5
+                | CompressionFormat::Bc6h
+                | CompressionFormat::Bc6hs
+                | CompressionFormat::Bc7
+        )
+    }
+
+    /// Whether this codec writes a WebP or AVIF image rather than GPU texture blocks
+    pub fn is_web_image(&self) -> bool {
+        matches!(
+            *self,
+            CompressionFormat::Webp
+                | CompressionFormat::Webplossless
+                | CompressionFormat::Avif
+                | CompressionFormat::Aviflossless
+        )
+    }
+
+    /// Width and height in texels of the blocks of an ASTC codec with an explicit block size
+    pub fn astc_block(&self) -> Option<(u32, u32)> {
+        Some(match *self {
+            CompressionFormat::Astc4x4 | CompressionFormat::Astc4x4hdr => (4, 4),
+            CompressionFormat::Astc5x4 | CompressionFormat::Astc5x4hdr => (5, 4),
+            CompressionFormat::Astc5x5 | CompressionFormat::Astc5x5hdr => (5, 5),
+            CompressionFormat::Astc6x5 | CompressionFormat::Astc6x5hdr => (6, 5),
+            CompressionFormat::Astc6x6 | CompressionFormat::Astc6x6hdr => (6, 6),
+            CompressionFormat::Astc8x5 | CompressionFormat::Astc8x5hdr => (8, 5),
+            CompressionFormat::Astc8x6 | CompressionFormat::Astc8x6hdr => (8, 6),
+            CompressionFormat::Astc8x8 | CompressionFormat::Astc8x8hdr => (8, 8),
+            CompressionFormat::Astc10x5 | CompressionFormat::Astc10x5hdr => (10, 5),
+            CompressionFormat::Astc10x6 | CompressionFormat::Astc10x6hdr => (10, 6),
+            CompressionFormat::Astc10x8 | CompressionFormat::Astc10x8hdr => (10, 8),
+            CompressionFormat::Astc10x10 | CompressionFormat::Astc10x10hdr => (10, 10),
+            CompressionFormat::Astc12x10 | CompressionFormat::Astc12x10hdr => (12, 10),
+            CompressionFormat::Astc12x12 | CompressionFormat::Astc12x12hdr => (12, 12),
             _ => return None,
         })
     }
 
+    /// Width and height in texels of the blocks this codec compresses
+    pub fn block_dimensions(&self) -> (u32, u32) {
+        if self.is_web_image() {
+            return (1, 1);
+        }
+        self.astc_block().unwrap_or((4, 4))
+    }
+
+    /// The variant of this codec without an alpha channel, for opaque images
+    pub fn opaque_variant(&self) -> CompressionFormat {
+        match *self {
+            CompressionFormat::Bc3 | CompressionFormat::Bc7 => CompressionFormat::Bc1,
+            CompressionFormat::Etc2rgba => CompressionFormat::Etc2rgb,
+            format => format,
+        }
+    }
+
+    /// The variant of this codec with an alpha channel, for images with transparency
+    pub fn alpha_variant(&self) -> CompressionFormat {
+        match *self {
+            CompressionFormat::Bc1 => CompressionFormat::Bc7,
+            CompressionFormat::Etc2rgb => CompressionFormat::Etc2rgba,
+            format => format,
+        }
+    }
+
     pub fn container(&self) -> ContainerFormat {
-        if matches!(*self, CompressionFormat::Etc1s) {
-            ContainerFormat::Basis
-        } else {
-            ContainerFormat::Ktx2
+        match *self {
+            CompressionFormat::Etc1s => ContainerFormat::Basis,
+            CompressionFormat::Webp | CompressionFormat::Webplossless => ContainerFormat::Webp,
+            CompressionFormat::Avif | CompressionFormat::Aviflossless => ContainerFormat::Avif,
+            _ => ContainerFormat::Ktx2,
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, ArgEnum, strum::Display, strum::EnumString, PartialEq, Eq)]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    ArgEnum,
+    Deserialize,
+    Serialize,
+    strum::Display,
+    strum::EnumString,
+    PartialEq,
+    Eq,
+)]
+#[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 pub enum ContainerFormat {
+    /// AVIF image, for the AVIF codecs
+    Avif,
     Basis,
+    /// DirectDraw Surface, for the BC codecs, as used by Direct3D and `MSFT_texture_dds`
+    Dds,
     Ktx2,
+    /// WebP image, for the WebP codecs
+    Webp,
+}
+
+/// Why compressing failed, along with the command lines that were run up to and including the one
+/// that failed
+#[derive(Clone, Debug)]
+pub struct CompressError {
+    pub message: String,
+    pub commands: Vec<String>,
+}
+
+impl CompressError {
+    /// Adds the command lines that succeeded before the failure
+    pub(crate) fn after(mut self, commands: &[String]) -> Self {
+        self.commands.splice(0..0, commands.iter().cloned());
+        self
+    }
+}
+
+impl From<String> for CompressError {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            commands: Vec::new(),
+        }
+    }
+}
+
+impl From<CompressError> for String {
+    fn from(error: CompressError) -> Self {
+        error.message
+    }
+}
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
 }
 
 pub trait Compressor {
+    /// Compresses `src_path` to `dst_path`, both relative to `working_dir`, and returns the
+    /// command lines that were executed, or the ones attempted on failure
+    #[allow(clippy::too_many_arguments)]
     fn compress(
         &self,
         working_dir: &Path,
@@ -78,5 +314,182 @@ pub trait Compressor {
         texture_type: TextureType,
         compression_format: CompressionFormat,
         container_format: ContainerFormat,
-    ) -> Result<(), String>;
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError>;
+
+    /// Compresses several images of the same size into one cubemap, array or 3D texture at
+    /// `dst_path`, relative to `working_dir`, and returns the command lines that were executed
+    #[allow(clippy::too_many_arguments)]
+    fn compress_layers(
+        &self,
+        _working_dir: &Path,
+        _layers: &[Rgba32FImage],
+        shape: TextureShape,
+        _dst_path: &Path,
+        _texture_type: TextureType,
+        _compression_format: CompressionFormat,
+        _container_format: ContainerFormat,
+        _options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        Err(format!("{} textures are not supported by this backend", shape).into())
+    }
+
+    /// The `KTXswizzle` that reads X and Y from a normal map compressed to a format, if this
+    /// backend stores normal maps in two channels for it
+    fn normal_map_swizzle(&self, _compression_format: CompressionFormat) -> Option<&'static str> {
+        None
+    }
+
+    /// Whether this backend writes DDS files itself. For other backends, DDS files are rewrapped
+    /// from KTX2.
+    fn writes_dds(&self) -> bool {
+        false
+    }
+
+    /// Whether this backend only accepts images whose dimensions are a multiple of the codec's
+    /// blocks, which rules out compressing the smallest mip levels one at a time
+    fn needs_whole_blocks(&self) -> bool {
+        false
+    }
+
+    /// Decodes mip level 0 of a file previously produced by this compressor, so that it can be
+    /// compared against the source image
+    fn decompress(
+        &self,
+        _working_dir: &Path,
+        _src_path: &Path,
+        compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        Err(format!(
+            "Decompressing {} is not supported by this backend",
+            compression_format
+        ))
+    }
+}
+
+/// Runs `compress` with the path relative to `working_dir`, container and options to compress
+/// with. DDS files that the backend does not write itself (`native_dds`) are rewrapped from a KTX2
+/// file compressed next to them without supercompression.
+pub(crate) fn compress_to_container(
+    working_dir: &Path,
+    dst_path: &Path,
+    compression_format: CompressionFormat,
+    container_format: ContainerFormat,
+    options: &CompressionOptions,
+    native_dds: bool,
+    compress: impl FnOnce(
+        &Path,
+        ContainerFormat,
+        &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError>,
+) -> Result<Vec<String>, CompressError> {
+    if !matches!(container_format, ContainerFormat::Dds) {
+        return compress(dst_path, container_format, options);
+    }
+    if !compression_format.is_bc() {
+        return Err(format!(
+            "DDS files need a BC codec rather than {}",
+            compression_format
+        )
+        .into());
+    }
+    if native_dds {
+        return compress(dst_path, container_format, options);
+    }
+    let ktx2_path = dst_path.with_extension("ktx2");
+    let mut ktx2_options = options.clone();
+    ktx2_options.supercompression.zstd_level = Some(0);
+    let result = compress(&ktx2_path, ContainerFormat::Ktx2, &ktx2_options).and_then(|commands| {
+        match convert_ktx2_to_dds(&working_dir.join(&ktx2_path), &working_dir.join(dst_path)) {
+            Ok(()) => Ok(commands),
+            Err(e) => Err(CompressError::from(e).after(&commands)),
+        }
+    });
+    let _ = std::fs::remove_file(working_dir.join(&ktx2_path));
+    result
+}
+
+/// Writes each layer into `dir` as a PNG file, or as a floating point KTX file for HDR textures,
+/// and returns their paths. `dir` and the returned paths are relative to `working_dir`.
+pub(crate) fn write_layers(
+    working_dir: &Path,
+    dir: &Path,
+    layers: &[Rgba32FImage],
+    texture_type: TextureType,
+) -> Result<Vec<PathBuf>, String> {
+    std::fs::create_dir_all(working_dir.join(dir)).map_err(|e| {
+        format!(
+            "Failed to recursively create directory: {} - {}",
+            dir.display(),
+            e
+        )
+    })?;
+    layers
+        .iter()
+        .enumerate()
+        .map(|(index, layer)| {
+            if matches!(texture_type, TextureType::Hdr) {
+                let path = dir.join(format!("layer_{}.ktx", index));
+                write_ktx1_rgba32f(&working_dir.join(&path), layer)?;
+                Ok(path)
+            } else {
+                let path = dir.join(format!("layer_{}.png", index));
+                DynamicImage::ImageRgba32F(layer.clone())
+                    .to_rgba8()
+                    .save(working_dir.join(&path))
+                    .map_err(|e| format!("Failed to write {} - {}", path.display(), e))?;
+                Ok(path)
+            }
+        })
+        .collect()
+}
+
+/// Formats a command as it would be typed into a shell
+pub(crate) fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| {
+            let arg = arg.to_string_lossy();
+            if arg.contains(char::is_whitespace) {
+                format!("\"{}\"", arg)
+            } else {
+                arg.into_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Runs a command to completion and returns its command line
+pub(crate) fn run_command(command: &mut Command) -> Result<String, CompressError> {
+    let command_line = command_line(command);
+    let message = match command.output() {
+        Ok(output) if output.status.success() => return Ok(command_line),
+        Ok(output) => format!(
+            "Failed to execute command: {}\n{}",
+            command_line,
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(e) => format!("Failed to execute command: {} - {:?}", command_line, e),
+    };
+    Err(CompressError {
+        message,
+        commands: vec![command_line],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kram_formats_default_to_kram() {
+        for format in crate::kram::SUPPORTED_COMPRESSION_FORMATS {
+            assert_eq!(format.backend(), Some(Backend::Kram), "{}", format);
+        }
+        assert_eq!(
+            CompressionFormat::Astc10x10.backend(),
+            Some(Backend::Astcenc)
+        );
+    }
 }
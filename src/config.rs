@@ -0,0 +1,78 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{CompressionFormat, ContainerFormat, TextureType};
+
+/// Name of the project config file, which is looked up in the current directory and its parents
+pub(crate) const CONFIG_FILE_NAME: &str = "klafsa.json";
+
+/// Project-wide settings read from `klafsa.json`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ProjectConfig {
+    /// The tool used by the `custom` backend
+    pub custom_backend: Option<CustomBackendConfig>,
+}
+
+/// An external encoder described by command templates rather than Rust code
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CustomBackendConfig {
+    /// Program and arguments to compress an image. Arguments that expand to an empty string are
+    /// left out.
+    pub command: Vec<String>,
+    /// Program and arguments to decode a compressed file to PNG, for quality metrics
+    pub decode_command: Option<Vec<String>>,
+    /// Expansion of `{srgb_flag}` for sRGB textures
+    #[serde(default)]
+    pub srgb_flag: String,
+    /// Expansion of `{srgb_flag}` for other textures
+    #[serde(default)]
+    pub linear_flag: String,
+    /// Expansion of `{mipmap_flag}` when mipmaps are enabled
+    #[serde(default)]
+    pub mipmap_flag: String,
+    /// Combinations the tool can produce
+    pub supports: Vec<CustomSupport>,
+}
+
+/// Every combination of the listed codecs, containers and texture types is supported
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CustomSupport {
+    pub codecs: Vec<CompressionFormat>,
+    pub containers: Vec<ContainerFormat>,
+    pub texture_types: Vec<TextureType>,
+}
+
+impl ProjectConfig {
+    /// Reads the nearest `klafsa.json`, starting at the current directory, along with the path it
+    /// was read from
+    pub(crate) fn find() -> Result<Option<(PathBuf, ProjectConfig)>, String> {
+        let current_dir = std::env::current_dir()
+            .map_err(|e| format!("Failed to get the current directory - {}", e))?;
+        match current_dir
+            .ancestors()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file())
+        {
+            Some(path) => {
+                let config = Self::read(&path)?;
+                Ok(Some((path, config)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn read(path: &Path) -> Result<ProjectConfig, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open config file {} - {}", path.display(), e))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("Invalid config file {} - {}", path.display(), e))
+    }
+}
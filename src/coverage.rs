@@ -0,0 +1,95 @@
+use image::{DynamicImage, Rgba32FImage};
+
+use crate::{
+    resize::{resize_image, with_depth_of},
+    TextureType,
+};
+
+/// Steps of the search for the alpha scale that restores a mip level's coverage
+const SEARCH_STEPS: usize = 16;
+/// The largest factor alpha is scaled by, which thin features that fade out entirely can reach
+const MAX_ALPHA_SCALE: f32 = 8.0;
+
+/// The fraction of texels whose alpha, scaled by `scale`, passes an alpha test at `cutoff`
+fn coverage(image: &Rgba32FImage, cutoff: f32, scale: f32) -> f32 {
+    let passed = image
+        .pixels()
+        .filter(|pixel| (pixel.0[3] * scale).min(1.0) >= cutoff)
+        .count();
+    passed as f32 / (image.width() * image.height()) as f32
+}
+
+/// Builds a mip chain of `levels` levels for an alpha-tested texture. Each level is filtered down
+/// from the first, and its alpha is then scaled so that the fraction of texels passing the alpha
+/// test at `cutoff` matches the first level, instead of shrinking as alpha is averaged.
+pub(crate) fn coverage_mip_chain(
+    image: &DynamicImage,
+    texture_type: TextureType,
+    cutoff: f32,
+    levels: u32,
+) -> Vec<DynamicImage> {
+    let target = coverage(&image.to_rgba32f(), cutoff, 1.0);
+    let mut chain = vec![image.clone()];
+    for level in 1..levels {
+        let width = (image.width() >> level).max(1);
+        let height = (image.height() >> level).max(1);
+        let level_image = resize_image(image, width, height, texture_type);
+        let mut rgba = level_image.to_rgba32f();
+        // Coverage only grows with the scale, so the closest scale can be found by bisection
+        let (mut low, mut high) = (0.0, MAX_ALPHA_SCALE);
+        for _ in 0..SEARCH_STEPS {
+            let mid = (low + high) / 2.0;
+            if coverage(&rgba, cutoff, mid) < target {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        for pixel in rgba.pixels_mut() {
+            pixel.0[3] = (pixel.0[3] * high).min(1.0);
+        }
+        chain.push(with_depth_of(&level_image, rgba));
+    }
+    chain
+}
+
+/// Number of levels in a full mip chain down to 1x1
+pub(crate) fn full_mip_levels(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn preserves_alpha_coverage() {
+        // One opaque texel in each 2x2 block, which filters down to a quarter alpha everywhere
+        // and would fail the alpha test entirely without scaling
+        let image = Rgba32FImage::from_fn(16, 16, |x, y| {
+            Rgba([
+                1.0,
+                1.0,
+                1.0,
+                if x % 2 == 0 && y % 2 == 0 { 1.0 } else { 0.0 },
+            ])
+        });
+        let image = DynamicImage::ImageRgba32F(image);
+        let chain = coverage_mip_chain(&image, TextureType::Linear, 0.5, 4);
+        assert_eq!(chain.len(), 4);
+        for (level, level_image) in chain.iter().enumerate() {
+            assert_eq!(level_image.width(), 16 >> level);
+            let passed = coverage(&level_image.to_rgba32f(), 0.5, 1.0);
+            assert!(passed >= 0.25, "level {} has coverage {}", level, passed);
+        }
+    }
+
+    #[test]
+    fn counts_full_mip_chain() {
+        assert_eq!(full_mip_levels(1, 1), 1);
+        assert_eq!(full_mip_levels(256, 64), 9);
+        assert_eq!(full_mip_levels(300, 17), 9);
+    }
+}
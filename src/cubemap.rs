@@ -0,0 +1,105 @@
+use std::f32::consts::PI;
+
+use image::{imageops, Rgba, Rgba32FImage};
+
+/// Number of cubemap faces, which KTX2 and the backends expect in +X, -X, +Y, -Y, +Z, -Z order
+pub(crate) const FACE_COUNT: usize = 6;
+
+/// Direction from the cube center through a point on a face, for face index 0-5 in +X, -X, +Y,
+/// -Y, +Z, -Z order and face coordinates `u`, `v` from -1 to 1, with `v` pointing down
+pub(crate) fn face_direction(face: usize, u: f32, v: f32) -> [f32; 3] {
+    let direction = match face {
+        0 => [1.0, -v, -u],
+        1 => [-1.0, -v, u],
+        2 => [u, 1.0, v],
+        3 => [u, -1.0, -v],
+        4 => [u, -v, 1.0],
+        _ => [-u, -v, -1.0],
+    };
+    normalize(direction)
+}
+
+pub(crate) fn normalize([x, y, z]: [f32; 3]) -> [f32; 3] {
+    let length = (x * x + y * y + z * z).sqrt();
+    [x / length, y / length, z / length]
+}
+
+/// Face coordinates from -1 to 1 of the center of texel `x`, `y` on a face of `size` texels
+pub(crate) fn texel_coordinates(x: u32, y: u32, size: u32) -> (f32, f32) {
+    (
+        2.0 * (x as f32 + 0.5) / size as f32 - 1.0,
+        2.0 * (y as f32 + 0.5) / size as f32 - 1.0,
+    )
+}
+
+/// Bilinearly samples an equirectangular image in the given direction
+pub(crate) fn sample_equirect(image: &Rgba32FImage, [x, y, z]: [f32; 3]) -> Rgba<f32> {
+    let (width, height) = image.dimensions();
+    let u = 0.5 + x.atan2(-z) / (2.0 * PI);
+    let v = y.clamp(-1.0, 1.0).acos() / PI;
+    let fx = u * width as f32 - 0.5;
+    let fy = (v * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+    let x0 = fx.floor();
+    let y0 = fy.floor();
+    let tx = fx - x0;
+    let ty = fy - y0;
+    let column = |x: f32| (x as i64).rem_euclid(width as i64) as u32;
+    let row = |y: f32| (y as u32).min(height - 1);
+    let texel = |x: f32, y: f32| image.get_pixel(column(x), row(y)).0;
+    let (a, b) = (texel(x0, y0), texel(x0 + 1.0, y0));
+    let (c, d) = (texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+    let mut sample = [0.0; 4];
+    for (i, value) in sample.iter_mut().enumerate() {
+        let top = a[i] + (b[i] - a[i]) * tx;
+        let bottom = c[i] + (d[i] - c[i]) * tx;
+        *value = top + (bottom - top) * ty;
+    }
+    Rgba(sample)
+}
+
+/// Resamples an equirectangular image into six cubemap faces of `size` texels
+pub(crate) fn faces_from_equirect(image: &Rgba32FImage, size: u32) -> Vec<Rgba32FImage> {
+    (0..FACE_COUNT)
+        .map(|face| {
+            Rgba32FImage::from_fn(size, size, |x, y| {
+                let (u, v) = texel_coordinates(x, y, size);
+                sample_equirect(image, face_direction(face, u, v))
+            })
+        })
+        .collect()
+}
+
+/// Cuts the six faces out of a horizontal (4x3) or vertical (3x4) cross layout
+pub(crate) fn faces_from_cross(image: &Rgba32FImage) -> Result<Vec<Rgba32FImage>, String> {
+    let (width, height) = image.dimensions();
+    let (size, vertical) = if width % 4 == 0 && width / 4 * 3 == height {
+        (width / 4, false)
+    } else if height % 4 == 0 && height / 4 * 3 == width {
+        (width / 3, true)
+    } else {
+        return Err(format!(
+            "A {}x{} image is not a 4x3 or 3x4 cubemap cross",
+            width, height
+        ));
+    };
+    // Cells of +X, -X, +Y, -Y, +Z, -Z in units of faces
+    let cells = if vertical {
+        [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (1, 3)]
+    } else {
+        [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (3, 1)]
+    };
+    Ok(cells
+        .iter()
+        .enumerate()
+        .map(|(face, &(column, row))| {
+            let face_image =
+                imageops::crop_imm(image, column * size, row * size, size, size).to_image();
+            if face == 5 && vertical {
+                // The -Z face is upside down at the bottom of a vertical cross
+                imageops::rotate180(&face_image)
+            } else {
+                face_image
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,280 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use image::RgbaImage;
+use which::which;
+
+use crate::{
+    config::{CustomBackendConfig, ProjectConfig, CONFIG_FILE_NAME},
+    coverage::full_mip_levels,
+    run_command, CompressError, CompressionFormat, CompressionOptions, Compressor, ContainerFormat,
+    TextureType,
+};
+
+/// A tool plugged in through the `custom_backend` command templates of the project config file
+pub struct Custom {
+    config: CustomBackendConfig,
+    /// Directory of the config file, which relative program paths start from
+    config_dir: PathBuf,
+}
+
+impl Custom {
+    pub fn new() -> Result<Self, String> {
+        let (config_path, config) = ProjectConfig::find()?.ok_or_else(|| {
+            format!(
+                "The custom backend needs a {} file in the current directory or one of its parents",
+                CONFIG_FILE_NAME
+            )
+        })?;
+        let config = config.custom_backend.ok_or_else(|| {
+            format!(
+                "No custom_backend is configured in {}",
+                config_path.display()
+            )
+        })?;
+        let custom = Self {
+            config,
+            config_dir: config_path.parent().unwrap().to_path_buf(),
+        };
+        // Check that the encoder can be found before compressing anything
+        custom.program(&custom.config.command)?;
+        Ok(custom)
+    }
+
+    /// The program of a command template. Programs given as paths are relative to the config
+    /// file, while others are looked up in the `PATH`.
+    fn program(&self, template: &[String]) -> Result<PathBuf, String> {
+        let program = template.first().ok_or_else(|| {
+            String::from("Command templates of the custom backend cannot be empty")
+        })?;
+        if Path::new(program).components().count() > 1 {
+            Ok(self.config_dir.join(program))
+        } else {
+            which(program).map_err(|e| {
+                format!(
+                    "Failed to find the {} CLI tool of the custom backend. Make sure it is in your \
+                     PATH. {:?}",
+                    program, e
+                )
+            })
+        }
+    }
+
+    fn supports(
+        &self,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+    ) -> bool {
+        self.config.supports.iter().any(|support| {
+            support.codecs.contains(&compression_format)
+                && support.containers.contains(&container_format)
+                && support.texture_types.contains(&texture_type)
+        })
+    }
+
+    /// Runs a command template, expanding each `{name}` in its arguments with the value from
+    /// `variables` and leaving out arguments that expand to nothing
+    fn run(
+        &self,
+        working_dir: &Path,
+        template: &[String],
+        variables: &[(&str, String)],
+    ) -> Result<String, CompressError> {
+        let mut command = Command::new(self.program(template)?);
+        command.current_dir(working_dir);
+        for arg in &template[1..] {
+            let arg = variables.iter().fold(arg.clone(), |arg, (name, value)| {
+                arg.replace(&format!("{{{}}}", name), value)
+            });
+            if !arg.is_empty() {
+                command.arg(arg);
+            }
+        }
+        run_command(&mut command)
+    }
+}
+
+impl Compressor for Custom {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        if !self.supports(texture_type, compression_format, container_format) {
+            return Err(format!(
+                "Unsupported format {:?} {:?} for {} textures - not in the supports list of the \
+                 custom backend",
+                compression_format, container_format, texture_type
+            )
+            .into());
+        }
+        let mipmaps = &options.mipmaps;
+        let mip_levels = if mipmaps.enabled() {
+            let (width, height) = image::image_dimensions(working_dir.join(src_path))
+                .map_err(|e| format!("Failed to read {} - {}", src_path.display(), e))?;
+            mipmaps
+                .levels(width, height)
+                .unwrap_or_else(|| full_mip_levels(width, height))
+        } else {
+            1
+        };
+        let variables = [
+            ("input", src_path.display().to_string()),
+            ("output", dst_path.display().to_string()),
+            ("codec", compression_format.to_string()),
+            ("container", container_format.to_string()),
+            ("texture_type", texture_type.to_string()),
+            (
+                "srgb_flag",
+                if matches!(texture_type, TextureType::Srgb) {
+                    self.config.srgb_flag.clone()
+                } else {
+                    self.config.linear_flag.clone()
+                },
+            ),
+            (
+                "mipmap_flag",
+                if mipmaps.enabled() {
+                    self.config.mipmap_flag.clone()
+                } else {
+                    String::new()
+                },
+            ),
+            ("mip_levels", mip_levels.to_string()),
+            ("quality", options.quality.level.to_string()),
+            (
+                "quality_percentage",
+                options.quality.percentage().to_string(),
+            ),
+        ];
+        self.run(working_dir, &self.config.command, &variables)
+            .map(|command_line| vec![command_line])
+    }
+
+    fn decompress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        let template = self.config.decode_command.as_ref().ok_or_else(|| {
+            format!(
+                "Decompressing {} needs a decode_command for the custom backend",
+                compression_format
+            )
+        })?;
+        let decoded_path = src_path.with_extension("decoded.png");
+        let variables = [
+            ("input", src_path.display().to_string()),
+            ("output", decoded_path.display().to_string()),
+            ("codec", compression_format.to_string()),
+        ];
+        self.run(working_dir, template, &variables)?;
+        let image = image::open(working_dir.join(&decoded_path))
+            .map(|image| image.to_rgba8())
+            .map_err(|e| format!("Failed to read {} - {}", decoded_path.display(), e));
+        let _ = std::fs::remove_file(working_dir.join(&decoded_path));
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::test_dir;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        options: CompressionOptions,
+    }
+
+    fn custom(dir: &Path) -> Custom {
+        // `true` ignores its arguments, whose expansion shows in the returned command line
+        let config = serde_json::from_value(serde_json::json!({
+            "command": [
+                "true", "{input}", "{output}", "--codec={codec}", "{container}",
+                "{texture_type}", "{srgb_flag}", "{mipmap_flag}", "{mip_levels}", "{quality}",
+                "{quality_percentage}"
+            ],
+            "srgb_flag": "--srgb",
+            "mipmap_flag": "--mipmaps",
+            "supports": [
+                { "codecs": ["bc7"], "containers": ["ktx2"], "texture_types": ["srgb", "linear"] }
+            ]
+        }))
+        .unwrap();
+        Custom {
+            config,
+            config_dir: dir.to_path_buf(),
+        }
+    }
+
+    /// The arguments of the command line a compression ran
+    fn compress(
+        dir: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        args: &[&str],
+    ) -> Result<String, CompressError> {
+        let options =
+            Cli::parse_from(std::iter::once("klafsa").chain(args.iter().copied())).options;
+        custom(dir)
+            .compress(
+                dir,
+                Path::new("image.png"),
+                Path::new("image.ktx2"),
+                texture_type,
+                compression_format,
+                ContainerFormat::Ktx2,
+                &options,
+            )
+            .map(|commands| commands[0].split_once(' ').unwrap().1.to_string())
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        let dir = test_dir("expands_placeholders");
+        RgbaImage::new(8, 8).save(dir.join("image.png")).unwrap();
+        assert_eq!(
+            compress(
+                &dir,
+                TextureType::Srgb,
+                CompressionFormat::Bc7,
+                &["--mipmaps", "on", "--quality", "high"]
+            )
+            .unwrap(),
+            "image.png image.ktx2 --codec=bc7 ktx2 srgb --srgb --mipmaps 4 high 75"
+        );
+        // Flags that expand to nothing are left out
+        assert_eq!(
+            compress(
+                &dir,
+                TextureType::Linear,
+                CompressionFormat::Bc7,
+                &["--mipmaps", "off"]
+            )
+            .unwrap(),
+            "image.png image.ktx2 --codec=bc7 ktx2 linear 1 normal 50"
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_codecs() {
+        let dir = test_dir("rejects_unsupported_codecs");
+        let result = compress(&dir, TextureType::Srgb, CompressionFormat::Bc1, &[]);
+        assert!(result.unwrap_err().commands.is_empty());
+        let result = compress(&dir, TextureType::NormalMap, CompressionFormat::Bc7, &[]);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use image::RgbaImage;
+use which::which;
+
+use crate::{
+    run_command, CompressError, CompressionFormat, CompressionOptions, Compressor, ContainerFormat,
+    TextureType,
+};
+
+/// The cwebp command line tool from libwebp
+pub struct Cwebp {
+    cli_path: PathBuf,
+}
+
+impl Cwebp {
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            cli_path: which("cwebp").map_err(|e| {
+                format!(
+                    "Failed to find the cwebp CLI tool. Make sure it is in your PATH. {:?}",
+                    e
+                )
+            })?,
+        })
+    }
+}
+
+impl Compressor for Cwebp {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        if !matches!(
+            compression_format,
+            CompressionFormat::Webp | CompressionFormat::Webplossless
+        ) || !matches!(container_format, ContainerFormat::Webp)
+        {
+            return Err(format!(
+                "Unsupported format {:?} {:?} - must be {} or {}, and {}",
+                compression_format,
+                container_format,
+                CompressionFormat::Webp,
+                CompressionFormat::Webplossless,
+                ContainerFormat::Webp,
+            )
+            .into());
+        }
+        if matches!(texture_type, TextureType::Hdr) {
+            return Err(String::from("HDR textures are not supported by WebP").into());
+        }
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        let quality = &options.quality;
+        match (compression_format, texture_type) {
+            (CompressionFormat::Webplossless, _) => {
+                command.args(["-z", quality.webp_lossless_level().to_string().as_str()]);
+            }
+            // Lossy WebP always subsamples chroma to 4:2:0, which sharp YUV conversion makes less
+            // visible in color images
+            (_, TextureType::Srgb) => {
+                command.args([
+                    "-q",
+                    quality.web_quality().to_string().as_str(),
+                    "-m",
+                    quality.webp_method().to_string().as_str(),
+                    "-sharp_yuv",
+                ]);
+            }
+            // Data textures would have their channels mixed by chroma subsampling, so they are
+            // encoded near-lossless instead, which quantizes each channel on its own
+            _ => {
+                command.args([
+                    "-near_lossless",
+                    quality.web_quality().to_string().as_str(),
+                    "-m",
+                    quality.webp_method().to_string().as_str(),
+                ]);
+            }
+        }
+        // Keep the color of fully transparent texels, which filtering still blends in
+        command.args(["-exact", "-quiet"]);
+        command.arg(src_path).arg("-o").arg(dst_path);
+        run_command(&mut command).map(|command_line| vec![command_line])
+    }
+
+    fn decompress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        _compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        image::open(working_dir.join(src_path))
+            .map(|image| image.to_rgba8())
+            .map_err(|e| format!("Failed to read {} - {}", src_path.display(), e))
+    }
+}
@@ -0,0 +1,290 @@
+use std::path::Path;
+
+use crate::ktx::read_ktx2;
+
+const DDS_MAGIC: [u8; 4] = *b"DDS ";
+const DDS_HEADER_SIZE: usize = 124;
+/// Offset of the pixel format's FourCC from the start of the file
+const DDS_FOUR_CC_OFFSET: usize = 84;
+/// Offset of the DX10 header's DXGI format from the start of the file
+const DDS_DXGI_FORMAT_OFFSET: usize = 4 + DDS_HEADER_SIZE;
+
+const DDSD_CAPS: u32 = 0x1;
+const DDSD_HEIGHT: u32 = 0x2;
+const DDSD_WIDTH: u32 = 0x4;
+const DDSD_PIXELFORMAT: u32 = 0x1000;
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+const DDSD_LINEARSIZE: u32 = 0x80000;
+const DDSD_DEPTH: u32 = 0x800000;
+const DDPF_FOURCC: u32 = 0x4;
+const DDSCAPS_COMPLEX: u32 = 0x8;
+const DDSCAPS_TEXTURE: u32 = 0x1000;
+const DDSCAPS_MIPMAP: u32 = 0x400000;
+/// DDSCAPS2_CUBEMAP along with all six of the DDSCAPS2_CUBEMAP_* face flags
+const DDSCAPS2_CUBEMAP_ALL_FACES: u32 = 0xFE00;
+const DDSCAPS2_VOLUME: u32 = 0x200000;
+const D3D10_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+const D3D10_RESOURCE_DIMENSION_TEXTURE3D: u32 = 4;
+const DDS_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+
+/// The DXGI format of each BC vkFormat
+const DXGI_FORMATS: [(u32, u32); 16] = [
+    (131, 71), // BC1 RGB UNORM as DXGI_FORMAT_BC1_UNORM
+    (132, 72), // BC1 RGB SRGB as DXGI_FORMAT_BC1_UNORM_SRGB
+    (133, 71), // BC1 RGBA UNORM
+    (134, 72), // BC1 RGBA SRGB
+    (135, 74), // BC2 UNORM
+    (136, 75), // BC2 SRGB
+    (137, 77), // BC3 UNORM
+    (138, 78), // BC3 SRGB
+    (139, 80), // BC4 UNORM
+    (140, 81), // BC4 SNORM
+    (141, 83), // BC5 UNORM
+    (142, 84), // BC5 SNORM
+    (143, 95), // BC6H UFLOAT as DXGI_FORMAT_BC6H_UF16
+    (144, 96), // BC6H SFLOAT as DXGI_FORMAT_BC6H_SF16
+    (145, 98), // BC7 UNORM
+    (146, 99), // BC7 SRGB
+];
+
+/// The legacy FourCC of DXGI formats that readers without DX10 header support understand
+fn legacy_four_cc(dxgi_format: u32) -> Option<[u8; 4]> {
+    match dxgi_format {
+        71 => Some(*b"DXT1"),
+        74 => Some(*b"DXT3"),
+        77 => Some(*b"DXT5"),
+        _ => None,
+    }
+}
+
+/// Rewraps the compressed payload of a KTX2 file without supercompression into a DDS file. BC1
+/// to BC3 2D textures in linear space get a legacy header, and everything else a DX10 header.
+pub(crate) fn convert_ktx2_to_dds(src_path: &Path, dst_path: &Path) -> Result<(), String> {
+    let texture = read_ktx2(src_path)?;
+    let dxgi_format = DXGI_FORMATS
+        .iter()
+        .find(|(vk_format, _)| *vk_format == texture.vk_format)
+        .map(|(_, dxgi_format)| *dxgi_format)
+        .ok_or_else(|| {
+            format!(
+                "vkFormat {} of {} cannot be stored in DDS - only BC1 to BC7 are supported",
+                texture.vk_format,
+                src_path.display()
+            )
+        })?;
+    let (width, height, depth) = texture.dimensions;
+    let cubemap = texture.face_count == 6;
+    let volume = depth > 0;
+    let elements = (texture.layer_count.max(1) * texture.face_count.max(1)) as usize;
+    let four_cc =
+        legacy_four_cc(dxgi_format).filter(|_| texture.layer_count == 0 && !cubemap && !volume);
+
+    let level_count = texture.levels.len() as u32;
+    let mut flags = DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PIXELFORMAT | DDSD_LINEARSIZE;
+    let mut caps = DDSCAPS_TEXTURE;
+    let mut caps2 = 0;
+    if level_count > 1 {
+        flags |= DDSD_MIPMAPCOUNT;
+        caps |= DDSCAPS_COMPLEX | DDSCAPS_MIPMAP;
+    }
+    if cubemap {
+        caps |= DDSCAPS_COMPLEX;
+        caps2 |= DDSCAPS2_CUBEMAP_ALL_FACES;
+    }
+    if volume {
+        flags |= DDSD_DEPTH;
+        caps |= DDSCAPS_COMPLEX;
+        caps2 |= DDSCAPS2_VOLUME;
+    }
+    let linear_size = texture.levels[0].len() / elements / depth.max(1) as usize;
+
+    let mut bytes = DDS_MAGIC.to_vec();
+    for value in [
+        DDS_HEADER_SIZE as u32,
+        flags,
+        height.max(1),
+        width,
+        linear_size as u32,
+        depth,
+        level_count,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    // dwReserved1, then the pixel format's dwSize and dwFlags
+    bytes.resize(bytes.len() + 11 * 4, 0);
+    bytes.extend_from_slice(&32u32.to_le_bytes());
+    bytes.extend_from_slice(&DDPF_FOURCC.to_le_bytes());
+    bytes.extend_from_slice(&four_cc.unwrap_or(*b"DX10"));
+    // The pixel format's bit count and masks, which are unused with a FourCC
+    bytes.resize(bytes.len() + 5 * 4, 0);
+    for value in [caps, caps2, 0, 0, 0] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    if four_cc.is_none() {
+        for value in [
+            dxgi_format,
+            if volume {
+                D3D10_RESOURCE_DIMENSION_TEXTURE3D
+            } else {
+                D3D10_RESOURCE_DIMENSION_TEXTURE2D
+            },
+            if cubemap {
+                DDS_RESOURCE_MISC_TEXTURECUBE
+            } else {
+                0
+            },
+            // Cubemap arrays count cubes rather than faces
+            texture.layer_count.max(1),
+            0,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    // KTX2 stores every layer and face of a mip level together, while DDS stores the whole mip
+    // chain of each layer and face in turn
+    for element in 0..elements {
+        for level in &texture.levels {
+            let size = level.len() / elements;
+            bytes.extend_from_slice(&level[element * size..(element + 1) * size]);
+        }
+    }
+    std::fs::write(dst_path, bytes)
+        .map_err(|e| format!("Failed to write DDS file {} - {}", dst_path.display(), e))
+}
+
+/// Marks a DDS file written with a UNORM format as sRGB, for tools without an sRGB option.
+/// Legacy headers are replaced with a DX10 header, which can express sRGB, and formats without
+/// an sRGB counterpart are left as they are.
+pub(crate) fn set_dds_srgb(path: &Path) -> Result<(), String> {
+    let mut bytes = std::fs::read(path)
+        .map_err(|e| format!("Failed to read DDS file {} - {}", path.display(), e))?;
+    if !bytes.starts_with(&DDS_MAGIC) || bytes.len() < DDS_DXGI_FORMAT_OFFSET {
+        return Err(format!("{} is not a DDS file", path.display()));
+    }
+    let four_cc = &bytes[DDS_FOUR_CC_OFFSET..DDS_FOUR_CC_OFFSET + 4];
+    if four_cc == b"DX10" {
+        let dxgi_format = bytes
+            .get(DDS_DXGI_FORMAT_OFFSET..DDS_DXGI_FORMAT_OFFSET + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| String::from("Unexpected end of DDS file"))?;
+        if legacy_four_cc(dxgi_format).is_none() && dxgi_format != 98 {
+            return Ok(());
+        }
+        bytes[DDS_DXGI_FORMAT_OFFSET..DDS_DXGI_FORMAT_OFFSET + 4]
+            .copy_from_slice(&(dxgi_format + 1).to_le_bytes());
+    } else {
+        let dxgi_format = match four_cc {
+            b"DXT1" => 71,
+            b"DXT3" => 74,
+            b"DXT5" => 77,
+            _ => return Ok(()),
+        };
+        let caps2 = u32::from_le_bytes(bytes[112..116].try_into().unwrap());
+        let mut dx10_header = Vec::new();
+        for value in [
+            dxgi_format + 1,
+            if caps2 & DDSCAPS2_VOLUME != 0 {
+                D3D10_RESOURCE_DIMENSION_TEXTURE3D
+            } else {
+                D3D10_RESOURCE_DIMENSION_TEXTURE2D
+            },
+            if caps2 & DDSCAPS2_CUBEMAP_ALL_FACES != 0 {
+                DDS_RESOURCE_MISC_TEXTURECUBE
+            } else {
+                0
+            },
+            1,
+            0,
+        ] {
+            dx10_header.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes[DDS_FOUR_CC_OFFSET..DDS_FOUR_CC_OFFSET + 4].copy_from_slice(b"DX10");
+        bytes.splice(DDS_DXGI_FORMAT_OFFSET..DDS_DXGI_FORMAT_OFFSET, dx10_header);
+    }
+    std::fs::write(path, bytes)
+        .map_err(|e| format!("Failed to write DDS file {} - {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ktx::write_ktx2, test_dir};
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn convert(name: &str, vk_format: u32, levels: &[Vec<u8>]) -> Vec<u8> {
+        let dir = test_dir(name);
+        let ktx2_path = dir.join("texture.ktx2");
+        let dds_path = dir.join("texture.dds");
+        write_ktx2(&ktx2_path, vk_format, (8, 8), &[], levels).unwrap();
+        convert_ktx2_to_dds(&ktx2_path, &dds_path).unwrap();
+        std::fs::read(&dds_path).unwrap()
+    }
+
+    #[test]
+    fn linear_bc1_gets_legacy_header() {
+        let levels = vec![vec![1; 32], vec![2; 8]];
+        let bytes = convert("linear_bc1_gets_legacy_header", 131, &levels);
+        assert_eq!(&bytes[..4], b"DDS ");
+        assert_eq!((read_u32(&bytes, 12), read_u32(&bytes, 16)), (8, 8));
+        assert_eq!(read_u32(&bytes, 20), 32);
+        assert_eq!(read_u32(&bytes, 28), 2);
+        assert_eq!(&bytes[DDS_FOUR_CC_OFFSET..DDS_FOUR_CC_OFFSET + 4], b"DXT1");
+        assert_eq!(&bytes[4 + DDS_HEADER_SIZE..], levels.concat());
+    }
+
+    #[test]
+    fn srgb_bc7_gets_dx10_header() {
+        let levels = vec![vec![1; 64]];
+        let bytes = convert("srgb_bc7_gets_dx10_header", 146, &levels);
+        assert_eq!(&bytes[DDS_FOUR_CC_OFFSET..DDS_FOUR_CC_OFFSET + 4], b"DX10");
+        assert_eq!(read_u32(&bytes, DDS_DXGI_FORMAT_OFFSET), 99);
+        assert_eq!(
+            read_u32(&bytes, DDS_DXGI_FORMAT_OFFSET + 4),
+            D3D10_RESOURCE_DIMENSION_TEXTURE2D
+        );
+        assert_eq!(&bytes[DDS_DXGI_FORMAT_OFFSET + 20..], levels.concat());
+    }
+
+    #[test]
+    fn linear_bc1_cubemap_gets_dx10_header() {
+        let dir = test_dir("linear_bc1_cubemap_gets_dx10_header");
+        let ktx2_path = dir.join("texture.ktx2");
+        let dds_path = dir.join("texture.dds");
+        let levels = vec![(0..6).flat_map(|face| [face; 32]).collect::<Vec<u8>>()];
+        write_ktx2(&ktx2_path, 131, (8, 8), &[], &levels).unwrap();
+        // faceCount
+        let mut ktx2 = std::fs::read(&ktx2_path).unwrap();
+        ktx2[36..40].copy_from_slice(&6u32.to_le_bytes());
+        std::fs::write(&ktx2_path, ktx2).unwrap();
+        convert_ktx2_to_dds(&ktx2_path, &dds_path).unwrap();
+
+        let bytes = std::fs::read(&dds_path).unwrap();
+        assert_eq!(read_u32(&bytes, 20), 32);
+        assert_eq!(&bytes[DDS_FOUR_CC_OFFSET..DDS_FOUR_CC_OFFSET + 4], b"DX10");
+        assert_eq!(read_u32(&bytes, DDS_DXGI_FORMAT_OFFSET), 71);
+        assert_eq!(
+            read_u32(&bytes, DDS_DXGI_FORMAT_OFFSET + 8),
+            DDS_RESOURCE_MISC_TEXTURECUBE
+        );
+        assert_eq!(&bytes[DDS_DXGI_FORMAT_OFFSET + 20..], levels.concat());
+    }
+
+    #[test]
+    fn set_srgb_replaces_legacy_header() {
+        let dir = test_dir("set_srgb_replaces_legacy_header");
+        let ktx2_path = dir.join("texture.ktx2");
+        let dds_path = dir.join("texture.dds");
+        let levels = vec![vec![1; 32]];
+        write_ktx2(&ktx2_path, 131, (8, 8), &[], &levels).unwrap();
+        convert_ktx2_to_dds(&ktx2_path, &dds_path).unwrap();
+        set_dds_srgb(&dds_path).unwrap();
+
+        let bytes = std::fs::read(&dds_path).unwrap();
+        assert_eq!(&bytes[DDS_FOUR_CC_OFFSET..DDS_FOUR_CC_OFFSET + 4], b"DX10");
+        assert_eq!(read_u32(&bytes, DDS_DXGI_FORMAT_OFFSET), 72);
+        assert_eq!(&bytes[DDS_DXGI_FORMAT_OFFSET + 20..], levels.concat());
+    }
+}
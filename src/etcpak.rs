@@ -0,0 +1,144 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use image::RgbaImage;
+use tracing::warn;
+use which::which;
+
+use crate::{
+    ktx::convert_ktx1_to_ktx2, run_command, CompressError, CompressionFormat, CompressionOptions,
+    Compressor, ContainerFormat, QualityLevel, TextureType,
+};
+
+pub(crate) const SUPPORTED_COMPRESSION_FORMATS: [CompressionFormat; 5] = [
+    CompressionFormat::Bc1,
+    CompressionFormat::Bc3,
+    CompressionFormat::Bc7,
+    CompressionFormat::Etc2rgb,
+    CompressionFormat::Etc2rgba,
+];
+
+/// The etcpak command line tool, a very fast ETC and BC encoder for previews rather than final
+/// assets
+pub struct Etcpak {
+    cli_path: PathBuf,
+}
+
+impl Etcpak {
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            cli_path: which("etcpak").map_err(|e| {
+                format!(
+                    "Failed to find the etcpak CLI tool. Make sure it is in your PATH. {:?}",
+                    e
+                )
+            })?,
+        })
+    }
+}
+
+impl Compressor for Etcpak {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        if !SUPPORTED_COMPRESSION_FORMATS.contains(&compression_format)
+            || !matches!(container_format, ContainerFormat::Ktx2)
+        {
+            return Err(format!(
+                "Unsupported format {:?} {:?} - must be one of {:?} and {}",
+                compression_format,
+                container_format,
+                SUPPORTED_COMPRESSION_FORMATS,
+                ContainerFormat::Ktx2,
+            )
+            .into());
+        }
+        if matches!(texture_type, TextureType::Hdr) {
+            return Err(String::from("HDR textures are not supported by etcpak").into());
+        }
+        if options
+            .supercompression
+            .zstd_level
+            .is_some_and(|level| level > 0)
+        {
+            warn!("Zstandard supercompression is not supported by etcpak and is ignored");
+        }
+
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        match compression_format {
+            CompressionFormat::Bc1 => {
+                command.arg("--dxtc");
+            }
+            CompressionFormat::Bc3 => {
+                command.args(["--dxtc", "--rgba"]);
+            }
+            CompressionFormat::Bc7 => {
+                command.arg("--bc7");
+            }
+            CompressionFormat::Etc2rgba => {
+                command.arg("--rgba");
+            }
+            // ETC1 blocks are valid ETC2 RGB blocks and are quicker to encode
+            _ if matches!(options.quality.level, QualityLevel::Fast) => {}
+            _ => {
+                command.arg("--etc2");
+            }
+        }
+        if !matches!(texture_type, TextureType::Srgb) {
+            command.arg("--linear");
+        }
+        // etcpak always generates the full mip chain, which is cut short when converting to KTX2
+        let mipmaps = &options.mipmaps;
+        let max_levels = if mipmaps.enabled() {
+            command.arg("-m");
+            let (width, height) = image::image_dimensions(working_dir.join(src_path))
+                .map_err(|e| format!("Failed to read {} - {}", src_path.display(), e))?;
+            mipmaps.levels(width, height)
+        } else {
+            Some(1)
+        };
+        // etcpak writes KTX1, which is converted to KTX2
+        let ktx1_path = dst_path.with_extension("etcpak.ktx");
+        command.args([src_path, &ktx1_path]);
+        let result =
+            run_command(&mut command).and_then(|command_line| {
+                match convert_ktx1_to_ktx2(
+                    &working_dir.join(&ktx1_path),
+                    &working_dir.join(dst_path),
+                    matches!(texture_type, TextureType::Srgb),
+                    max_levels,
+                ) {
+                    Ok(()) => Ok(vec![command_line]),
+                    Err(e) => Err(CompressError::from(e).after(&[command_line])),
+                }
+            });
+        let _ = std::fs::remove_file(working_dir.join(&ktx1_path));
+        result
+    }
+
+    fn needs_whole_blocks(&self) -> bool {
+        true
+    }
+
+    fn decompress(
+        &self,
+        _working_dir: &Path,
+        _src_path: &Path,
+        compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        Err(format!(
+            "Decompressing {} is not supported by etcpak",
+            compression_format
+        ))
+    }
+}
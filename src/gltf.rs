@@ -1,21 +1,48 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, HashMap, HashSet},
     fs::File,
     io::{BufReader, BufWriter},
-    path::Path,
+    path::{Path, PathBuf},
+    time::Instant,
 };
 
 use clap::Args;
-use gltf::json::{image::MimeType, Root};
+use gltf::{
+    json::{image::MimeType, Root},
+    material::AlphaMode,
+    texture::{MinFilter, WrappingMode},
+};
+use image::imageops;
 use indicatif::{ProgressBar, ProgressStyle};
 use strum::IntoEnumIterator;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    image::ImageFormat, Backend, Basisu, CompressionFormat, Compressor, ContainerFormat, Kram,
-    TextureType, Toktx,
+    compressor::compress_to_container,
+    coverage::{coverage_mip_chain, full_mip_levels},
+    etcpak,
+    image::{
+        has_alpha, image_dimensions, open_image, write_intermediate_ktx, write_intermediate_png,
+        ImageFormat,
+    },
+    ktx::{merge_ktx2_levels, set_ktx2_value},
+    metrics,
+    pack::pack_orm,
+    resize::{resize_image, Conform},
+    transcode::{BasisSource, Reuse},
+    Backend, CompressError, CompressionFormat, CompressionOptions, Compressor, ContainerFormat,
+    DimensionPolicy, KtxCreate, MipWrap, MipmapMode, NormalMaps, Quality, QualityLevel,
+    QualityMetrics, Report, ReportEntry, Resize, TextureRole, TextureType,
 };
 
+/// Directory next to source images that holds intermediate conversions while compressing
+const INTERMEDIATE_DIR: &str = "klafsa_intermediate";
+
+const KHR_TEXTURE_TRANSFORM: &str = "KHR_texture_transform";
+
+/// Image added for a source image, along with the texture extension that refers to it
+type ExtensionImage = (usize, &'static str);
+
 #[derive(Args, Debug)]
 pub struct Gltf {
     /// Path to the JSON-format .gltf file
@@ -23,6 +50,49 @@ pub struct Gltf {
     /// Compress to all formats
     #[clap(long)]
     compress_to_all: bool,
+    /// Codecs to try for each texture, smallest first, keeping the first that meets the quality
+    /// targets
+    #[clap(
+        long,
+        arg_enum,
+        use_value_delimiter = true,
+        conflicts_with = "compress-to-all"
+    )]
+    candidates: Vec<CompressionFormat>,
+    /// Minimum PSNR in dB that a candidate codec must reach
+    #[clap(long)]
+    min_psnr: Option<f64>,
+    /// Minimum SSIM that a candidate codec must reach
+    #[clap(long)]
+    min_ssim: Option<f64>,
+    /// Always use the requested codec, instead of switching between its opaque and alpha variants
+    /// (BC1 and BC7, ETC2 RGB and RGBA) depending on how base color textures use alpha. Implied
+    /// by --compress-to-all
+    #[clap(long)]
+    fixed_codec: bool,
+    /// Backend for the BC codecs when each codec picks its own backend, with --compress-to-all or
+    /// --candidates
+    #[clap(long, arg_enum, default_value_t = Backend::Kram)]
+    bc_backend: Backend,
+    /// Write a JSON report of every compressed image to this path
+    #[clap(long)]
+    report: Option<String>,
+    #[clap(flatten)]
+    resize: Resize,
+    #[clap(flatten)]
+    normal_maps: NormalMaps,
+    /// Leave the mipmaps of alpha-tested base color textures to the backend, instead of
+    /// generating them with alpha scaled to keep the coverage at the material's alphaCutoff
+    #[clap(long)]
+    backend_alpha_mips: bool,
+    /// Pack occlusion into the red channel of metallic-roughness textures where materials sample
+    /// both with the same UVs and sampler, so they are compressed as one ORM texture
+    #[clap(long)]
+    pack_orm: bool,
+    /// Preview preset for quick turnaround: encode with etcpak where it supports the codec, and
+    /// at --quality fast otherwise. Outputs are written to the same paths as without it
+    #[clap(long, conflicts_with = "candidates")]
+    fast: bool,
 }
 
 impl Gltf {
@@ -31,44 +101,155 @@ impl Gltf {
         backend: Backend,
         compression_format: CompressionFormat,
         container_format: ContainerFormat,
+        options: &CompressionOptions,
     ) -> Result<(), String> {
         if !self.file_path.to_lowercase().ends_with(".gltf") {
             error!("File must be a JSON-format glTF file with a .gtlf file extension");
             std::process::exit(1);
         }
+        if !self.candidates.is_empty() && self.min_psnr.is_none() && self.min_ssim.is_none() {
+            return Err(String::from(
+                "Candidate codecs require a quality target from --min-psnr and/or --min-ssim",
+            ));
+        }
+        if !self.candidates.is_empty() && matches!(container_format, ContainerFormat::Dds) {
+            // Most backends can only decode KTX2, so DDS candidates could not be compared
+            return Err(String::from(
+                "Candidate codecs cannot be written to the DDS container, as they are decoded \
+                 from KTX2 to measure their quality",
+            ));
+        }
+
+        let fast_options;
+        let options = if self.fast {
+            fast_options = CompressionOptions {
+                quality: Quality {
+                    level: QualityLevel::Fast,
+                    ..options.quality.clone()
+                },
+                ..options.clone()
+            };
+            &fast_options
+        } else {
+            options
+        };
 
         info!("Processing {}", self.file_path);
 
-        let gltf = gltf::Gltf::open(&self.file_path).expect("Failed to open file");
-        let working_dir = Path::new(&self.file_path).parent().map_or_else(
-            || std::env::current_dir().expect("Failed to get parent directory of the glTF file"),
-            |p| p.into(),
-        );
+        let working_dir = Path::new(&self.file_path)
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map_or_else(
+                || {
+                    std::env::current_dir()
+                        .expect("Failed to get parent directory of the glTF file")
+                },
+                |p| p.into(),
+            );
+        let mut gltf_root = read_gltf_to_json(&self.file_path);
+        if self.pack_orm {
+            // gltf-json drops the extensions of occlusion textures, which packing needs to see
+            let json = std::fs::read(&self.file_path)
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string()))
+                .map_err(|e| format!("Failed to read {} - {}", self.file_path, e))?;
+            pack_orm(&working_dir, &mut gltf_root, &json)?;
+        }
+        let gltf = gltf::Document::from_json(gltf_root.clone())
+            .map_err(|e| format!("Invalid glTF file {} - {}", self.file_path, e))?;
 
         let mut normal_map_textures = HashSet::new();
         let mut linear_textures = HashSet::new();
+        // Base color textures, and whether any material using them blends or masks by alpha
+        let mut base_color_textures = HashMap::new();
+        // The alpha cutoff of base color textures used by MASK materials, or None for textures
+        // also used by other materials or with different cutoffs
+        let mut alpha_cutoffs: HashMap<usize, Option<f32>> = HashMap::new();
+        let mut texture_roles: HashMap<usize, Vec<TextureRole>> = HashMap::new();
+        // Textures whose UVs cannot be scaled with KHR_texture_transform, which gltf-json does not
+        // support on normal and occlusion textures
+        let mut fixed_uv_textures = HashSet::new();
+        let mut add_role = |index: usize, role: TextureRole| {
+            let roles = texture_roles.entry(index).or_default();
+            if !roles.contains(&role) {
+                roles.push(role);
+            }
+        };
         for material in gltf.materials() {
+            if let Some(texture) = material.pbr_metallic_roughness().base_color_texture() {
+                *base_color_textures
+                    .entry(texture.texture().index())
+                    .or_insert(false) |= !matches!(material.alpha_mode(), AlphaMode::Opaque);
+                let cutoff = Some(material.alpha_cutoff().unwrap_or(0.5))
+                    .filter(|_| matches!(material.alpha_mode(), AlphaMode::Mask));
+                alpha_cutoffs
+                    .entry(texture.texture().index())
+                    .and_modify(|other| {
+                        if *other != cutoff {
+                            *other = None;
+                        }
+                    })
+                    .or_insert(cutoff);
+                add_role(texture.texture().index(), TextureRole::BaseColor);
+                if has_rotation(&texture) {
+                    fixed_uv_textures.insert(texture.texture().index());
+                }
+            }
             if let Some(texture) = material.normal_texture() {
                 normal_map_textures.insert(texture.texture().index());
+                fixed_uv_textures.insert(texture.texture().index());
+                add_role(texture.texture().index(), TextureRole::Normal);
             }
             if let Some(texture) = material.occlusion_texture() {
                 linear_textures.insert(texture.texture().index());
+                fixed_uv_textures.insert(texture.texture().index());
+                add_role(texture.texture().index(), TextureRole::Occlusion);
             }
             if let Some(texture) = material
                 .pbr_metallic_roughness()
                 .metallic_roughness_texture()
             {
                 linear_textures.insert(texture.texture().index());
+                add_role(texture.texture().index(), TextureRole::MetallicRoughness);
+                if has_rotation(&texture) {
+                    fixed_uv_textures.insert(texture.texture().index());
+                }
+            }
+            if let Some(texture) = material.emissive_texture() {
+                add_role(texture.texture().index(), TextureRole::Emissive);
+                if has_rotation(&texture) {
+                    fixed_uv_textures.insert(texture.texture().index());
+                }
             }
         }
 
         let formats = self.get_formats(compression_format);
-        let compressors = self.get_compressors(backend);
+        let compressors = self.get_compressors(&formats, backend)?;
+        let output_names = if self.candidates.is_empty() {
+            formats
+                .iter()
+                .map(|format| {
+                    format!(
+                        "{}_{}",
+                        format,
+                        self.get_container(*format, container_format)
+                    )
+                })
+                .collect::<Vec<_>>()
+        } else {
+            vec![format!("auto_{}", container_format)]
+        };
 
-        let gltf_root = read_gltf_to_json(&self.file_path);
-        let mut gltf_roots = vec![gltf_root; formats.len()];
+        let mut report = Report {
+            file_path: self.file_path.clone(),
+            options: Some(options.clone()),
+            ..Default::default()
+        };
+        let mut gltf_roots = vec![gltf_root; output_names.len()];
+        // For each output, the extension image added for each source image
+        let mut extension_images = vec![HashMap::new(); output_names.len()];
 
-        let progress_bar = ProgressBar::new((formats.len() * gltf.textures().len()) as u64)
+        let progress_bar = ProgressBar::new((output_names.len() * gltf.textures().len()) as u64)
             .with_style(
                 ProgressStyle::default_bar().template(
                     "{pos}/{len} [{elapsed_precise}]/[{duration_precise}] {wide_bar} {msg}",
@@ -78,18 +259,71 @@ impl Gltf {
 
         for texture in gltf.textures() {
             match texture.source().source() {
-                gltf::image::Source::View { mime_type, .. } => {
-                    warn!("Cannot process texture views. (Mime-type: {})", mime_type);
-                    progress_bar.inc(formats.len() as u64);
+                gltf::image::Source::View { view, mime_type } => {
+                    let reason =
+                        format!("Cannot process texture views. (Mime-type: {})", mime_type);
+                    warn!("{}", reason);
+                    report.skip(
+                        texture.index(),
+                        &format!("bufferView {}", view.index()),
+                        reason,
+                    );
+                    progress_bar.inc(output_names.len() as u64);
                     continue;
                 }
                 gltf::image::Source::Uri { uri, mime_type } => {
-                    if ImageFormat::from_mime_or_extension(mime_type, Some(uri)).is_none() {
-                        warn!("Unsupported image format");
-                        progress_bar.inc(formats.len() as u64);
-                        continue;
-                    }
-                    let texture_type = if linear_textures.contains(&texture.index()) {
+                    let image_format = match ImageFormat::detect(&working_dir.join(uri), mime_type)
+                    {
+                        Some(image_format) if image_format.is_supported() => image_format,
+                        Some(image_format) => {
+                            let reason =
+                                format!("Unsupported image format {} for {}", image_format, uri);
+                            warn!("{}", reason);
+                            report.skip(texture.index(), uri, reason);
+                            progress_bar.inc(output_names.len() as u64);
+                            continue;
+                        }
+                        None => {
+                            let reason = format!("Unsupported image format for {}", uri);
+                            warn!("{}", reason);
+                            report.skip(texture.index(), uri, reason);
+                            progress_bar.inc(output_names.len() as u64);
+                            continue;
+                        }
+                    };
+                    let src_path = Path::new(uri);
+                    let basis_source =
+                        match BasisSource::detect(&working_dir.join(uri), image_format) {
+                            Ok(basis_source) => basis_source,
+                            Err(e) => {
+                                let reason = format!(
+                                    "Unsupported image format {} for {} - {}",
+                                    image_format, uri, e
+                                );
+                                warn!("{}", reason);
+                                report.skip(texture.index(), uri, reason);
+                                progress_bar.inc(output_names.len() as u64);
+                                continue;
+                            }
+                        };
+                    // Path to a decodable version of the source image
+                    let image_path = match basis_source {
+                        Some(basis_source) => {
+                            match decode_basis_source(&working_dir, src_path, basis_source) {
+                                Ok(image_path) => image_path,
+                                Err(e) => {
+                                    error!("{}", e);
+                                    report.skip(texture.index(), uri, e);
+                                    progress_bar.inc(output_names.len() as u64);
+                                    continue;
+                                }
+                            }
+                        }
+                        None => src_path.to_path_buf(),
+                    };
+                    let texture_type = if image_format.is_hdr() {
+                        TextureType::Hdr
+                    } else if linear_textures.contains(&texture.index()) {
                         TextureType::Linear
                     } else if normal_map_textures.contains(&texture.index()) {
                         TextureType::NormalMap
@@ -104,128 +338,601 @@ impl Gltf {
                             .into_string()
                             .unwrap(),
                     );
-                    for (format, gltf_root) in formats.iter().zip(gltf_roots.iter_mut()) {
-                        let src_path = Path::new(uri);
-                        let container = self.get_container(*format, container_format);
-                        let dst_path = src_path
-                            .parent()
-                            .unwrap()
-                            .join(format!("{}_{}", format, container))
-                            .join(format!(
-                                "{}_{}.{}",
-                                src_path.file_stem().unwrap().to_str().unwrap(),
-                                format,
-                                container
-                            ));
-                        if let Err(e) =
-                            std::fs::create_dir_all(working_dir.join(dst_path.parent().unwrap()))
-                        {
-                            error!(
-                                "Failed to recursively create directory: {} - {}",
-                                dst_path.parent().unwrap().display(),
-                                e
-                            );
-                            progress_bar.inc(1);
+                    let alpha = match base_color_textures.get(&texture.index()) {
+                        // Every variant has its own output with --compress-to-all
+                        Some(_) if self.fixed_codec || self.compress_to_all => None,
+                        Some(false) => {
+                            debug!("{} is used as opaque base color", uri);
+                            Some(AlphaUsage::Opaque)
+                        }
+                        Some(true) => match has_alpha(&working_dir.join(&image_path)) {
+                            Ok(true) => Some(AlphaUsage::Alpha),
+                            Ok(false) => {
+                                debug!("{} has a fully opaque alpha channel", uri);
+                                Some(AlphaUsage::Opaque)
+                            }
+                            Err(e) => {
+                                warn!("Failed to analyze alpha channel - {}", e);
+                                None
+                            }
+                        },
+                        None => None,
+                    };
+                    let (width, height) = match image_dimensions(&working_dir.join(&image_path)) {
+                        Ok(dimensions) => dimensions,
+                        Err(e) => {
+                            error!("{}", e);
+                            report.skip(texture.index(), uri, e);
+                            progress_bar.inc(output_names.len() as u64);
                             continue;
                         }
-                        if let Err(e) = compressors[&format.backend().unwrap()].compress(
+                    };
+                    let size = if self.resize.enabled() {
+                        let roles = texture_roles
+                            .get(&texture.index())
+                            .map_or(&[][..], |roles| roles.as_slice());
+                        let size = self.resize.target_size(width, height, roles);
+                        if let Some((target_width, target_height)) = size {
+                            info!(
+                                "Downscaling {} from {}x{} to {}x{}",
+                                uri, width, height, target_width, target_height
+                            );
+                        }
+                        size
+                    } else {
+                        None
+                    };
+                    let sampler = texture.sampler();
+                    let can_pad = matches!(sampler.wrap_s(), WrappingMode::ClampToEdge)
+                        && matches!(sampler.wrap_t(), WrappingMode::ClampToEdge)
+                        && !fixed_uv_textures.contains(&texture.index());
+                    let input_path = if size.is_none()
+                        && self.normal_maps(texture_type).is_none()
+                        && (basis_source.is_some()
+                            || image_format.is_native()
+                                && src_path
+                                    .extension()
+                                    .and_then(|extension| extension.to_str())
+                                    .and_then(ImageFormat::from_extension)
+                                    == Some(image_format))
+                    {
+                        image_path.clone()
+                    } else {
+                        match write_intermediate(
                             &working_dir,
                             src_path,
-                            &dst_path,
+                            &image_path,
                             texture_type,
-                            *format,
-                            container,
+                            size,
+                            None,
+                            self.normal_maps(texture_type),
                         ) {
-                            error!("{} -> {} - {}", uri, dst_path.display(), e);
+                            Ok(input_path) => input_path,
+                            Err(e) => {
+                                error!("{}", e);
+                                report.skip(texture.index(), uri, e);
+                                progress_bar.inc(output_names.len() as u64);
+                                continue;
+                            }
+                        }
+                    };
+                    let job = TextureJob {
+                        working_dir: &working_dir,
+                        src_path,
+                        image_path: &image_path,
+                        basis_source,
+                        input_path: &input_path,
+                        texture_type,
+                        size,
+                        dimensions: size.unwrap_or((width, height)),
+                        can_pad,
+                        alpha,
+                        alpha_cutoff: alpha_cutoffs
+                            .get(&texture.index())
+                            .copied()
+                            .flatten()
+                            .filter(|_| !self.backend_alpha_mips)
+                            .filter(|_| !matches!(alpha, Some(AlphaUsage::Opaque))),
+                        options: &texture_options(options, &sampler, uri),
+                    };
+                    let image_index = texture.source().index();
+                    if !self.candidates.is_empty() {
+                        match self.compress_to_candidate(
+                            &compressors,
+                            backend,
+                            &job,
+                            container_format,
+                            &mut report,
+                        ) {
+                            Ok((
+                                Compressed {
+                                    dst_path,
+                                    compression_format: format,
+                                    uv_scale,
+                                },
+                                metrics,
+                            )) => {
+                                info!("{} -> {} ({})", uri, format, metrics);
+                                set_image_uri(
+                                    &mut gltf_roots[0],
+                                    &mut extension_images[0],
+                                    image_index,
+                                    container_format,
+                                    &dst_path,
+                                );
+                                if texture_extension(container_format).is_some()
+                                    && !image_format.is_native()
+                                {
+                                    if let Err(e) = set_fallback_png(
+                                        &mut gltf_roots[0],
+                                        image_index,
+                                        &working_dir,
+                                        src_path,
+                                        &image_path,
+                                        &dst_path,
+                                    ) {
+                                        error!("{}", e);
+                                    }
+                                }
+                                if let Some(uv_scale) = uv_scale {
+                                    scale_texture_uvs(
+                                        &mut gltf_roots[0],
+                                        texture.index(),
+                                        uv_scale,
+                                    );
+                                }
+                            }
+                            Err(e) => error!("{} - {}", uri, e),
+                        }
+                        progress_bar.inc(1);
+                    } else {
+                        for ((format, gltf_root), extension_images) in formats
+                            .iter()
+                            .zip(gltf_roots.iter_mut())
+                            .zip(extension_images.iter_mut())
+                        {
+                            // HDR and LDR images keep their source in the other kind's outputs
+                            if self.compress_to_all
+                                && format.is_hdr() != matches!(texture_type, TextureType::Hdr)
+                            {
+                                debug!(
+                                    "{}: skipping {} for a {} texture",
+                                    uri, format, texture_type
+                                );
+                                progress_bar.inc(1);
+                                continue;
+                            }
+                            let container = self.get_container(*format, container_format);
+                            match self.compress_texture(
+                                &compressors,
+                                backend,
+                                &job,
+                                *format,
+                                container,
+                                &mut report,
+                            ) {
+                                Ok(Compressed {
+                                    dst_path, uv_scale, ..
+                                }) => {
+                                    set_image_uri(
+                                        gltf_root,
+                                        extension_images,
+                                        image_index,
+                                        container,
+                                        &dst_path,
+                                    );
+                                    if texture_extension(container).is_some()
+                                        && !image_format.is_native()
+                                    {
+                                        if let Err(e) = set_fallback_png(
+                                            gltf_root,
+                                            image_index,
+                                            &working_dir,
+                                            src_path,
+                                            &image_path,
+                                            &dst_path,
+                                        ) {
+                                            error!("{}", e);
+                                        }
+                                    }
+                                    if let Some(uv_scale) = uv_scale {
+                                        scale_texture_uvs(gltf_root, texture.index(), uv_scale);
+                                    }
+                                }
+                                Err(e) => error!("{}", e),
+                            }
                             progress_bar.inc(1);
-                            continue;
                         }
-                        gltf_root.images[texture.source().index()].mime_type = match container {
-                            // NOTE: There is no valid official mime type for .basis files
-                            ContainerFormat::Basis => None,
-                            ContainerFormat::Ktx2 => Some(MimeType(String::from("image/ktx2"))),
-                        };
-                        gltf_root.images[texture.source().index()].uri =
-                            Some(dst_path.display().to_string());
+                    }
+                    for intermediate_path in [&input_path, &image_path] {
+                        if intermediate_path != src_path {
+                            let _ = std::fs::remove_file(working_dir.join(intermediate_path));
+                            // NOTE: This only succeeds once the directory is empty
+                            let _ = std::fs::remove_dir(
+                                working_dir.join(intermediate_path.parent().unwrap()),
+                            );
+                        }
                     }
                 }
             }
-            progress_bar.inc(1);
         }
         progress_bar.finish();
 
-        for (format, gltf_root) in formats.iter().zip(gltf_roots.into_iter()) {
+        for ((output_name, gltf_root), extension_images) in
+            output_names.iter().zip(gltf_roots).zip(extension_images)
+        {
             let dst_path = self
                 .file_path
                 .rsplit_once('.')
-                .map(|(path, extension)| {
-                    format!(
-                        "{}_{}_{}.{}",
-                        path,
-                        format,
-                        if self.compress_to_all {
-                            format.container()
-                        } else {
-                            container_format
-                        },
-                        extension
-                    )
-                })
+                .map(|(path, extension)| format!("{}_{}.{}", path, output_name, extension))
                 .expect("Failed to create compressed glTF filename");
-            write_json_to_gltf(gltf_root, &dst_path);
+            write_json_to_gltf(gltf_root, &extension_images, &dst_path);
+            report.outputs.push(dst_path);
+        }
+
+        if let Some(report_path) = &self.report {
+            report.write(report_path)?;
         }
 
         Ok(())
     }
 
+    fn compress_texture(
+        &self,
+        compressors: &HashMap<Backend, Box<dyn Compressor>>,
+        backend: Backend,
+        job: &TextureJob,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        report: &mut Report,
+    ) -> Result<Compressed, String> {
+        let requested_format = compression_format;
+        let compression_format = match job.alpha {
+            Some(AlphaUsage::Opaque) => requested_format.opaque_variant(),
+            Some(AlphaUsage::Alpha) => requested_format.alpha_variant(),
+            None => requested_format,
+        };
+        if compression_format != requested_format {
+            info!(
+                "{}: using {} instead of {} for {} base color",
+                job.src_path.display(),
+                compression_format,
+                requested_format,
+                match job.alpha {
+                    Some(AlphaUsage::Alpha) => "transparent",
+                    _ => "opaque",
+                }
+            );
+        }
+        let mut backend = self.get_backend(compression_format, backend);
+        let dst_path = job
+            .src_path
+            .parent()
+            .unwrap()
+            .join(format!("{}_{}", compression_format, container_format))
+            .join(format!(
+                "{}_{}.{}",
+                job.src_path.file_stem().unwrap().to_str().unwrap(),
+                compression_format,
+                container_format
+            ));
+        let conform = self.conform(job, compression_format);
+        match conform {
+            Some(conform) => info!(
+                "{}: {} for {}",
+                job.src_path.display(),
+                conform,
+                compression_format
+            ),
+            None if !job
+                .dimensions
+                .0
+                .is_multiple_of(compression_format.block_dimensions().0)
+                || !job
+                    .dimensions
+                    .1
+                    .is_multiple_of(compression_format.block_dimensions().1) =>
+            {
+                warn!(
+                    "{} is {}x{}, which is not a multiple of the {}x{} blocks of {}. Use \
+                     --dimensions pad or pot if the backend or GPU rejects it",
+                    job.src_path.display(),
+                    job.dimensions.0,
+                    job.dimensions.1,
+                    compression_format.block_dimensions().0,
+                    compression_format.block_dimensions().1,
+                    compression_format
+                )
+            }
+            None => {}
+        }
+        // KTX2 and Basis sources that klafsa would not change are used as they are
+        let reuse = job
+            .basis_source
+            .filter(|_| {
+                job.size.is_none()
+                    && conform.is_none()
+                    && self.normal_maps(job.texture_type).is_none()
+            })
+            .and_then(|basis_source| basis_source.reuse(compression_format, container_format));
+        match reuse {
+            Some(Reuse::PassThrough) => info!(
+                "{}: passing through {} source unchanged",
+                job.src_path.display(),
+                compression_format
+            ),
+            Some(Reuse::Transcode(_)) => {
+                info!(
+                    "{}: transcoding {} source to {}",
+                    job.src_path.display(),
+                    job.basis_source.unwrap().codec,
+                    compression_format
+                );
+                backend = Backend::Ktx;
+            }
+            None => {}
+        }
+        let start = Instant::now();
+        let input_path = match conform {
+            Some(conform) => write_intermediate(
+                job.working_dir,
+                job.src_path,
+                job.image_path,
+                job.texture_type,
+                job.size,
+                Some(conform),
+                self.normal_maps(job.texture_type),
+            ),
+            None => Ok(job.input_path.to_path_buf()),
+        };
+        let result = input_path
+            .clone()
+            .map_err(CompressError::from)
+            .and_then(|input_path| match reuse {
+                Some(reuse) => {
+                    reuse_basis_source(job, reuse, &dst_path, compression_format, container_format)
+                }
+                None => compress_job(
+                    compressors[&backend].as_ref(),
+                    job,
+                    &input_path,
+                    &dst_path,
+                    compression_format,
+                    container_format,
+                ),
+            })
+            .and_then(|commands| {
+                // Reused sources are not written by a compressor, which may not have been created
+                let swizzle = reuse
+                    .is_none()
+                    .then(|| compressors.get(&backend))
+                    .flatten()
+                    .and_then(|compressor| compressor.normal_map_swizzle(compression_format));
+                if let (TextureType::NormalMap, ContainerFormat::Ktx2, Some(swizzle)) =
+                    (job.texture_type, container_format, swizzle)
+                {
+                    // Tells loaders which channels hold X and Y, and that Z must be reconstructed
+                    if let Err(e) =
+                        set_ktx2_value(&job.working_dir.join(&dst_path), "KTXswizzle", swizzle)
+                    {
+                        return Err(CompressError::from(e).after(&commands));
+                    }
+                }
+                Ok(commands)
+            })
+            .map_err(|e| CompressError {
+                message: format!(
+                    "{} -> {} - {}",
+                    job.src_path.display(),
+                    dst_path.display(),
+                    e
+                ),
+                commands: e.commands,
+            });
+        if result.is_err() {
+            // Keeps a file left by an earlier run from being taken for the output
+            let _ = std::fs::remove_file(job.working_dir.join(&dst_path));
+        }
+        report.entries.push(ReportEntry {
+            src_path: job.src_path.to_path_buf(),
+            dst_path: dst_path.clone(),
+            backend,
+            compression_format,
+            container_format,
+            texture_type: job.texture_type,
+            commands: match &result {
+                Ok(commands) => commands.clone(),
+                Err(e) => e.commands.clone(),
+            },
+            duration_secs: start.elapsed().as_secs_f64(),
+            src_size: file_size(&job.working_dir.join(job.src_path)),
+            dst_size: file_size(&job.working_dir.join(&dst_path)),
+            quality: None,
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.message.clone()),
+        });
+        if let (Some(_), Ok(input_path)) = (conform, input_path) {
+            let _ = std::fs::remove_file(job.working_dir.join(&input_path));
+            // NOTE: This only succeeds once the directory is empty
+            let _ = std::fs::remove_dir(job.working_dir.join(input_path.parent().unwrap()));
+        }
+        result
+            .map(|_| Compressed {
+                dst_path,
+                compression_format,
+                uv_scale: conform.and_then(|conform| conform.uv_scale(job.dimensions)),
+            })
+            .map_err(String::from)
+    }
+
+    /// The normal map preprocessing to apply to images of a texture type, if any
+    fn normal_maps(&self, texture_type: TextureType) -> Option<&NormalMaps> {
+        Some(&self.normal_maps)
+            .filter(|normal_maps| normal_maps.enabled())
+            .filter(|_| matches!(texture_type, TextureType::NormalMap))
+    }
+
+    /// How the image of a job must be resized or padded for a codec under the dimension policy
+    fn conform(&self, job: &TextureJob, compression_format: CompressionFormat) -> Option<Conform> {
+        match self.resize.dimensions {
+            // etcpak rejects images that are not a multiple of its blocks. They are resized
+            // rather than padded so that the glTF stays the same as without --fast
+            DimensionPolicy::Keep if self.uses_etcpak(compression_format) => DimensionPolicy::Pad
+                .conform(job.dimensions, compression_format.block_dimensions(), false),
+            policy => policy.conform(
+                job.dimensions,
+                compression_format.block_dimensions(),
+                job.can_pad,
+            ),
+        }
+    }
+
+    /// Compresses to each candidate codec in turn and keeps the first whose decoded result meets
+    /// the quality targets, falling back to the last candidate that could be compressed
+    fn compress_to_candidate(
+        &self,
+        compressors: &HashMap<Backend, Box<dyn Compressor>>,
+        backend: Backend,
+        job: &TextureJob,
+        container_format: ContainerFormat,
+        report: &mut Report,
+    ) -> Result<(Compressed, QualityMetrics), String> {
+        let reference = open_image(&job.working_dir.join(job.image_path))?;
+        let reference = match job.size {
+            Some((width, height)) => resize_image(&reference, width, height, job.texture_type),
+            None => reference,
+        };
+        let reference = match self.normal_maps(job.texture_type) {
+            Some(normal_maps) => {
+                normal_maps.preprocess(&reference, &job.src_path.display().to_string())
+            }
+            None => reference,
+        };
+        let mut best: Option<(Compressed, QualityMetrics)> = None;
+        for candidate in &self.candidates {
+            let compressed = match self.compress_texture(
+                compressors,
+                backend,
+                job,
+                *candidate,
+                container_format,
+                report,
+            ) {
+                Ok(compressed) => compressed,
+                Err(e) => {
+                    warn!("Skipping candidate {} - {}", candidate, e);
+                    continue;
+                }
+            };
+            // Base colors may have been compressed to another variant of the candidate
+            let format = compressed.compression_format;
+            let conform = self.conform(job, format);
+            let reference = match conform {
+                Some(conform @ Conform::Resize(..)) => conform.apply(&reference, job.texture_type),
+                _ => reference.clone(),
+            }
+            .to_rgba8();
+            let metrics = match compressors[&self.get_backend(format, backend)]
+                .decompress(job.working_dir, &compressed.dst_path, format)
+                .map(|decoded| match conform {
+                    // Padding is not part of the image and is left out of the comparison
+                    Some(Conform::Pad(..)) => {
+                        imageops::crop_imm(&decoded, 0, 0, reference.width(), reference.height())
+                            .to_image()
+                    }
+                    _ => decoded,
+                })
+                .and_then(|decoded| metrics::compare(&reference, &decoded))
+            {
+                Ok(metrics) => metrics,
+                Err(e) => {
+                    warn!(
+                        "Skipping candidate {} for {} - {}",
+                        format,
+                        job.src_path.display(),
+                        e
+                    );
+                    let _ = std::fs::remove_file(job.working_dir.join(&compressed.dst_path));
+                    continue;
+                }
+            };
+            debug!("{} as {}: {}", job.src_path.display(), format, metrics);
+            if let Some(entry) = report.entries.last_mut() {
+                entry.quality = Some(metrics);
+            }
+            // Two candidates with the same variant write the same file
+            if let Some((rejected, _)) = best
+                .take()
+                .filter(|(rejected, _)| rejected.dst_path != compressed.dst_path)
+            {
+                let _ = std::fs::remove_file(job.working_dir.join(rejected.dst_path));
+            }
+            let meets_targets = metrics.meets(self.min_psnr, self.min_ssim);
+            best = Some((compressed, metrics));
+            if meets_targets {
+                return Ok(best.unwrap());
+            }
+        }
+        let best = best.ok_or_else(|| String::from("No candidate codec could be used"))?;
+        warn!(
+            "No candidate codec meets the quality targets for {}, using {} ({})",
+            job.src_path.display(),
+            best.0.compression_format,
+            best.1
+        );
+        Ok(best)
+    }
+
     fn get_formats(&self, compression_format: CompressionFormat) -> Vec<CompressionFormat> {
         if self.compress_to_all {
+            // The block sizes only astcenc supports are left out, as they would multiply the
+            // outputs and make every run depend on astcenc, and so are the web codecs unless
+            // they are the requested codec, as they are not GPU formats
             CompressionFormat::iter()
-                .filter(|f| f.backend().is_some())
+                .filter(|f| f.backend().is_some_and(|b| b != Backend::Astcenc))
+                .filter(|f| !f.is_web_image() || *f == compression_format)
                 .collect::<Vec<_>>()
+        } else if !self.candidates.is_empty() {
+            self.candidates.clone()
         } else {
             vec![compression_format]
         }
     }
 
-    fn get_compressors(&self, backend: Backend) -> HashMap<Backend, Box<dyn Compressor>> {
-        let mut compressors: HashMap<Backend, Box<dyn Compressor>> = HashMap::new();
-        if self.compress_to_all {
-            compressors.insert(
-                Backend::Basisu,
-                Box::new(Basisu::new().expect("Failed to create basisu compressor")),
-            );
-            compressors.insert(
-                Backend::Kram,
-                Box::new(Kram::new().expect("Failed to create kram compressor")),
-            );
-            compressors.insert(
-                Backend::Toktx,
-                Box::new(Toktx::new().expect("Failed to create toktx compressor")),
-            );
+    fn uses_etcpak(&self, compression_format: CompressionFormat) -> bool {
+        self.fast && etcpak::SUPPORTED_COMPRESSION_FORMATS.contains(&compression_format)
+    }
+
+    fn get_backend(&self, compression_format: CompressionFormat, backend: Backend) -> Backend {
+        if self.uses_etcpak(compression_format) {
+            Backend::Etcpak
+        } else if compression_format.is_web_image() {
+            // Only cwebp and avifenc write WebP and AVIF images
+            compression_format.backend().unwrap()
+        } else if self.compress_to_all || !self.candidates.is_empty() {
+            if compression_format.is_bc() {
+                self.bc_backend
+            } else {
+                compression_format.backend().unwrap_or(backend)
+            }
         } else {
-            match backend {
-                Backend::Basisu => {
-                    compressors.insert(
-                        Backend::Basisu,
-                        Box::new(Basisu::new().expect("Failed to create basisu compressor")),
-                    );
-                }
-                Backend::Kram => {
-                    compressors.insert(
-                        Backend::Kram,
-                        Box::new(Kram::new().expect("Failed to create kram compressor")),
-                    );
-                }
-                Backend::Toktx => {
-                    compressors.insert(
-                        Backend::Toktx,
-                        Box::new(Toktx::new().expect("Failed to create toktx compressor")),
-                    );
-                }
+            backend
+        }
+    }
+
+    fn get_compressors(
+        &self,
+        formats: &[CompressionFormat],
+        backend: Backend,
+    ) -> Result<HashMap<Backend, Box<dyn Compressor>>, String> {
+        let mut compressors = HashMap::new();
+        for format in formats {
+            let backend = self.get_backend(*format, backend);
+            if let Entry::Vacant(entry) = compressors.entry(backend) {
+                entry.insert(backend.compressor()?);
             }
         }
-        compressors
+        Ok(compressors)
     }
 
     fn get_container(
@@ -233,7 +940,7 @@ impl Gltf {
         compression_format: CompressionFormat,
         container_format: ContainerFormat,
     ) -> ContainerFormat {
-        if self.compress_to_all {
+        if self.compress_to_all || compression_format.is_web_image() {
             compression_format.container()
         } else {
             container_format
@@ -241,17 +948,657 @@ impl Gltf {
     }
 }
 
+fn compress_job(
+    compressor: &dyn Compressor,
+    job: &TextureJob,
+    input_path: &Path,
+    dst_path: &Path,
+    compression_format: CompressionFormat,
+    container_format: ContainerFormat,
+) -> Result<Vec<String>, CompressError> {
+    if matches!(job.texture_type, TextureType::Hdr) && !compression_format.is_hdr() {
+        return Err(format!(
+            "HDR images need an HDR codec rather than {}",
+            compression_format
+        )
+        .into());
+    }
+    std::fs::create_dir_all(job.working_dir.join(dst_path.parent().unwrap())).map_err(|e| {
+        format!(
+            "Failed to recursively create directory: {} - {}",
+            dst_path.parent().unwrap().display(),
+            e
+        )
+    })?;
+    // WebP and AVIF images have no mip levels
+    let mut coverage_cutoff = job
+        .alpha_cutoff
+        .filter(|_| job.options.mipmaps.enabled() && !compression_format.is_web_image());
+    if coverage_cutoff.is_some()
+        && (matches!(container_format, ContainerFormat::Basis)
+            || matches!(compression_format, CompressionFormat::Etc1s))
+    {
+        warn!(
+            "{}: leaving mipmaps to the backend, since alpha coverage cannot be preserved for {} \
+             {}",
+            job.src_path.display(),
+            compression_format,
+            container_format
+        );
+        coverage_cutoff = None;
+    }
+    if coverage_cutoff.is_some() && compressor.needs_whole_blocks() {
+        warn!(
+            "{}: leaving mipmaps to the backend, since it cannot compress mip levels smaller than \
+             a block to preserve alpha coverage",
+            job.src_path.display()
+        );
+        coverage_cutoff = None;
+    }
+    compress_to_container(
+        job.working_dir,
+        dst_path,
+        compression_format,
+        container_format,
+        job.options,
+        // Mip levels with preserved coverage are merged as KTX2, even by backends that write DDS
+        compressor.writes_dds() && coverage_cutoff.is_none(),
+        |dst_path, container_format, options| match coverage_cutoff {
+            Some(cutoff) => compress_coverage_mips(
+                compressor,
+                job,
+                input_path,
+                dst_path,
+                compression_format,
+                cutoff,
+                options,
+            ),
+            None => compressor.compress(
+                job.working_dir,
+                input_path,
+                dst_path,
+                job.texture_type,
+                compression_format,
+                container_format,
+                options,
+            ),
+        },
+    )
+}
+
+/// Copies a KTX2 or Basis source that is already in the codec and container, or transcodes it
+fn reuse_basis_source(
+    job: &TextureJob,
+    reuse: Reuse,
+    dst_path: &Path,
+    compression_format: CompressionFormat,
+    container_format: ContainerFormat,
+) -> Result<Vec<String>, CompressError> {
+    std::fs::create_dir_all(job.working_dir.join(dst_path.parent().unwrap())).map_err(|e| {
+        format!(
+            "Failed to recursively create directory: {} - {}",
+            dst_path.parent().unwrap().display(),
+            e
+        )
+    })?;
+    compress_to_container(
+        job.working_dir,
+        dst_path,
+        compression_format,
+        container_format,
+        job.options,
+        false,
+        |dst_path, _, options| match reuse {
+            Reuse::PassThrough => std::fs::copy(
+                job.working_dir.join(job.src_path),
+                job.working_dir.join(dst_path),
+            )
+            .map(|_| Vec::new())
+            .map_err(|e| format!("Failed to copy to {} - {}", dst_path.display(), e).into()),
+            Reuse::Transcode(target) => {
+                KtxCreate::new()
+                    .map_err(CompressError::from)
+                    .and_then(|ktx| {
+                        ktx.transcode(job.working_dir, job.src_path, dst_path, target, options)
+                    })
+            }
+        },
+    )
+}
+
+/// Generates the mip chain of an alpha-tested texture with its alpha coverage preserved,
+/// compresses each level on its own and merges them into one KTX2 file
+fn compress_coverage_mips(
+    compressor: &dyn Compressor,
+    job: &TextureJob,
+    input_path: &Path,
+    dst_path: &Path,
+    compression_format: CompressionFormat,
+    cutoff: f32,
+    options: &CompressionOptions,
+) -> Result<Vec<String>, CompressError> {
+    let image = open_image(&job.working_dir.join(input_path))?;
+    let levels = options
+        .mipmaps
+        .levels(image.width(), image.height())
+        .unwrap_or_else(|| full_mip_levels(image.width(), image.height()));
+    debug!(
+        "{}: generating {} mip levels with alpha coverage preserved at {}",
+        job.src_path.display(),
+        levels,
+        cutoff
+    );
+    let mut options = options.clone();
+    options.mipmaps.mode = MipmapMode::Off;
+    let level_dir = job.src_path.parent().unwrap().join(INTERMEDIATE_DIR);
+    std::fs::create_dir_all(job.working_dir.join(&level_dir)).map_err(|e| {
+        format!(
+            "Failed to recursively create directory: {} - {}",
+            level_dir.display(),
+            e
+        )
+    })?;
+    let mut commands = Vec::new();
+    let mut level_paths = Vec::new();
+    let mut result = Ok(());
+    for (level, level_image) in coverage_mip_chain(&image, job.texture_type, cutoff, levels)
+        .into_iter()
+        .enumerate()
+    {
+        let level_input_path = level_dir.join(format!(
+            "{}.{}.level_{}.png",
+            job.src_path.file_name().unwrap().to_str().unwrap(),
+            compression_format,
+            level
+        ));
+        let level_dst_path = dst_path.with_extension(format!("level_{}.ktx2", level));
+        result = write_intermediate_png(
+            level_image,
+            job.src_path,
+            &job.working_dir.join(&level_input_path),
+        )
+        .map_err(CompressError::from)
+        .and_then(|_| {
+            compressor.compress(
+                job.working_dir,
+                &level_input_path,
+                &level_dst_path,
+                job.texture_type,
+                compression_format,
+                ContainerFormat::Ktx2,
+                &options,
+            )
+        })
+        .map(|level_commands| commands.extend(level_commands));
+        let _ = std::fs::remove_file(job.working_dir.join(&level_input_path));
+        level_paths.push(job.working_dir.join(level_dst_path));
+        if result.is_err() {
+            break;
+        }
+    }
+    // NOTE: This only succeeds once the directory is empty
+    let _ = std::fs::remove_dir(job.working_dir.join(&level_dir));
+    let result = result.and_then(|_| {
+        merge_ktx2_levels(&level_paths, &job.working_dir.join(dst_path))
+            .map_err(CompressError::from)
+    });
+    for level_path in level_paths {
+        let _ = std::fs::remove_file(level_path);
+    }
+    result.map_err(|e| e.after(&commands)).map(|_| commands)
+}
+
+/// Resolves the mipmap settings left to the glTF sampler for one texture
+fn texture_options(
+    options: &CompressionOptions,
+    sampler: &gltf::texture::Sampler,
+    uri: &str,
+) -> CompressionOptions {
+    let mut options = options.clone();
+    let mipmaps = &mut options.mipmaps;
+    if matches!(mipmaps.mode, MipmapMode::Auto) {
+        mipmaps.mode = match sampler.min_filter() {
+            Some(MinFilter::Nearest | MinFilter::Linear) => MipmapMode::Off,
+            _ => MipmapMode::On,
+        };
+    }
+    if mipmaps.mip_wrap.is_none() {
+        if mipmaps.enabled() && sampler.wrap_s() != sampler.wrap_t() {
+            warn!(
+                "{} wraps S and T differently, so its mip levels are generated with the S mode \
+                 {:?}. Use --mip-wrap to choose another",
+                uri,
+                sampler.wrap_s()
+            );
+        }
+        mipmaps.mip_wrap = Some(match sampler.wrap_s() {
+            WrappingMode::ClampToEdge => MipWrap::Clamp,
+            WrappingMode::MirroredRepeat => MipWrap::Reflect,
+            WrappingMode::Repeat => MipWrap::Wrap,
+        });
+    }
+    options
+}
+
+/// Whether a base color texture needs a codec with an alpha channel
+#[derive(Clone, Copy, Debug)]
+enum AlphaUsage {
+    /// The material is opaque or the image has no transparent pixels
+    Opaque,
+    Alpha,
+}
+
+/// A compressed texture, and the UV scale that maps it back onto the source image if it was
+/// padded
+struct Compressed {
+    dst_path: PathBuf,
+    /// The codec that was used, which for base colors may be the alpha or opaque variant of the
+    /// requested one
+    compression_format: CompressionFormat,
+    uv_scale: Option<[f32; 2]>,
+}
+
+/// The source image of a texture and how it should be compressed
+struct TextureJob<'a> {
+    working_dir: &'a Path,
+    /// Path to the source image as referenced by the glTF
+    src_path: &'a Path,
+    /// Path to a decodable version of the source image, which is a PNG file decoded from KTX2 and
+    /// Basis sources
+    image_path: &'a Path,
+    /// The codec of KTX2 and Basis sources, which can be passed through or transcoded
+    basis_source: Option<BasisSource>,
+    /// Path to the image passed to the backend, which may be an intermediate conversion
+    input_path: &'a Path,
+    texture_type: TextureType,
+    /// Dimensions the source image was downscaled to in the input image
+    size: Option<(u32, u32)>,
+    /// Dimensions of the input image
+    dimensions: (u32, u32),
+    /// Whether the image can be padded, with UVs scaled to match
+    can_pad: bool,
+    alpha: Option<AlphaUsage>,
+    /// Alpha test cutoff whose coverage the mip levels must keep, for MASK materials
+    alpha_cutoff: Option<f32>,
+    options: &'a CompressionOptions,
+}
+
+/// Converts a source image that backends cannot read, or that klafsa changes first, to a PNG
+/// file, or a floating point KTX file for HDR textures, in a subdirectory next to it. Returns its
+/// path relative to the working directory.
+fn write_intermediate(
+    working_dir: &Path,
+    src_path: &Path,
+    image_path: &Path,
+    texture_type: TextureType,
+    size: Option<(u32, u32)>,
+    conform: Option<Conform>,
+    normal_maps: Option<&NormalMaps>,
+) -> Result<PathBuf, String> {
+    let input_path = src_path
+        .parent()
+        .unwrap()
+        .join(INTERMEDIATE_DIR)
+        .join(format!(
+            "{}{}.{}",
+            src_path.file_name().unwrap().to_str().unwrap(),
+            conform.map_or_else(String::new, |conform| format!(".{}", conform)),
+            match texture_type {
+                TextureType::Hdr => "ktx",
+                _ => "png",
+            }
+        ));
+    std::fs::create_dir_all(working_dir.join(input_path.parent().unwrap())).map_err(|e| {
+        format!(
+            "Failed to recursively create directory: {} - {}",
+            input_path.parent().unwrap().display(),
+            e
+        )
+    })?;
+    let image = open_image(&working_dir.join(image_path))?;
+    let image = match size {
+        Some((width, height)) => resize_image(&image, width, height, texture_type),
+        None => image,
+    };
+    let image = match conform {
+        Some(conform) => conform.apply(&image, texture_type),
+        None => image,
+    };
+    let image = match normal_maps {
+        Some(normal_maps) => normal_maps.preprocess(&image, &src_path.display().to_string()),
+        None => image,
+    };
+    match texture_type {
+        TextureType::Hdr => write_intermediate_ktx(&image, &working_dir.join(&input_path))?,
+        _ => write_intermediate_png(image, src_path, &working_dir.join(&input_path))?,
+    }
+    Ok(input_path)
+}
+
+/// Decodes a KTX2 or Basis source image to a PNG file in a subdirectory next to it. Returns its
+/// path relative to the working directory.
+fn decode_basis_source(
+    working_dir: &Path,
+    src_path: &Path,
+    basis_source: BasisSource,
+) -> Result<PathBuf, String> {
+    let image_path = src_path
+        .parent()
+        .unwrap()
+        .join(INTERMEDIATE_DIR)
+        .join(format!(
+            "{}.decoded.png",
+            src_path.file_name().unwrap().to_str().unwrap()
+        ));
+    std::fs::create_dir_all(working_dir.join(image_path.parent().unwrap())).map_err(|e| {
+        format!(
+            "Failed to recursively create directory: {} - {}",
+            image_path.parent().unwrap().display(),
+            e
+        )
+    })?;
+    debug!(
+        "Decoding {} source {} to {}",
+        basis_source.codec,
+        src_path.display(),
+        image_path.display()
+    );
+    basis_source.decode(working_dir, src_path, &image_path)?;
+    Ok(image_path)
+}
+
+/// Whether a texture reference rotates its UVs with KHR_texture_transform
+fn has_rotation(info: &gltf::texture::Info) -> bool {
+    info.texture_transform()
+        .is_some_and(|transform| transform.rotation() != 0.0)
+}
+
+/// Scales the UVs of every reference to a texture, after any KHR_texture_transform it already
+/// has, so that they cover only the top left part of a padded image
+fn scale_texture_uvs(gltf_root: &mut Root, texture_index: usize, [scale_u, scale_v]: [f32; 2]) {
+    for material in gltf_root.materials.iter_mut() {
+        let pbr = &mut material.pbr_metallic_roughness;
+        for info in [
+            pbr.base_color_texture.as_mut(),
+            pbr.metallic_roughness_texture.as_mut(),
+            material.emissive_texture.as_mut(),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|info| info.index.value() == texture_index)
+        {
+            let transform = info
+                .extensions
+                .get_or_insert_with(Default::default)
+                .texture_transform
+                .get_or_insert_with(Default::default);
+            transform.offset.0[0] *= scale_u;
+            transform.offset.0[1] *= scale_v;
+            transform.scale.0[0] *= scale_u;
+            transform.scale.0[1] *= scale_v;
+        }
+    }
+    if !gltf_root
+        .extensions_used
+        .iter()
+        .any(|extension| extension == KHR_TEXTURE_TRANSFORM)
+    {
+        gltf_root
+            .extensions_used
+            .push(String::from(KHR_TEXTURE_TRANSFORM));
+    }
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    std::fs::metadata(path).ok().map(|metadata| metadata.len())
+}
+
+/// The texture extension that refers to images in a container, for containers that core glTF
+/// does not allow
+fn texture_extension(container_format: ContainerFormat) -> Option<&'static str> {
+    match container_format {
+        ContainerFormat::Avif => Some("EXT_texture_avif"),
+        ContainerFormat::Dds => Some("MSFT_texture_dds"),
+        ContainerFormat::Webp => Some("EXT_texture_webp"),
+        ContainerFormat::Basis | ContainerFormat::Ktx2 => None,
+    }
+}
+
+/// Points an image at its compressed file. Containers with a texture extension are added as
+/// separate images that textures refer to through the extension, keeping the original image as
+/// a fallback, and `extension_images` records which image each was added for.
+fn set_image_uri(
+    gltf_root: &mut Root,
+    extension_images: &mut HashMap<usize, ExtensionImage>,
+    image_index: usize,
+    container_format: ContainerFormat,
+    dst_path: &Path,
+) {
+    let image_index = match texture_extension(container_format) {
+        Some(extension) => {
+            extension_images
+                .entry(image_index)
+                .or_insert_with(|| {
+                    let name = gltf_root.images[image_index].name.clone();
+                    gltf_root.images.push(gltf::json::Image {
+                        buffer_view: None,
+                        mime_type: None,
+                        name,
+                        uri: None,
+                        extensions: None,
+                        extras: Default::default(),
+                    });
+                    (gltf_root.images.len() - 1, extension)
+                })
+                .0
+        }
+        None => image_index,
+    };
+    gltf_root.images[image_index].mime_type = match container_format {
+        ContainerFormat::Avif => Some(MimeType(String::from("image/avif"))),
+        // NOTE: There is no valid official mime type for .basis files
+        ContainerFormat::Basis => None,
+        ContainerFormat::Dds => Some(MimeType(String::from("image/vnd-ms.dds"))),
+        ContainerFormat::Ktx2 => Some(MimeType(String::from("image/ktx2"))),
+        ContainerFormat::Webp => Some(MimeType(String::from("image/webp"))),
+    };
+    gltf_root.images[image_index].uri = Some(dst_path.display().to_string());
+}
+
+/// Writes a PNG copy of a source image next to its compressed file and makes it the image's
+/// fallback, for sources such as TGA or EXR that viewers without the texture extension cannot
+/// load either
+fn set_fallback_png(
+    gltf_root: &mut Root,
+    image_index: usize,
+    working_dir: &Path,
+    src_path: &Path,
+    image_path: &Path,
+    dst_path: &Path,
+) -> Result<(), String> {
+    let fallback_path = dst_path
+        .parent()
+        .unwrap()
+        .join(src_path.with_extension("png").file_name().unwrap());
+    write_intermediate_png(
+        open_image(&working_dir.join(image_path))?,
+        src_path,
+        &working_dir.join(&fallback_path),
+    )?;
+    gltf_root.images[image_index].mime_type = Some(MimeType(String::from("image/png")));
+    gltf_root.images[image_index].uri = Some(fallback_path.display().to_string());
+    Ok(())
+}
+
 fn read_gltf_to_json<P: AsRef<Path>>(src_path: P) -> Root {
     let file = File::open(src_path).expect("Failed to open glTF JSON file");
     let reader = BufReader::new(file);
     Root::from_reader(reader).expect("Failed to parse glTF JSON file")
 }
 
-fn write_json_to_gltf<P: AsRef<Path> + Copy>(root: Root, dst_path: P) {
+fn write_json_to_gltf<P: AsRef<Path> + Copy>(
+    mut root: Root,
+    extension_images: &HashMap<usize, ExtensionImage>,
+    dst_path: P,
+) {
+    for (_, extension) in extension_images.values() {
+        if !root.extensions_used.iter().any(|used| used == extension) {
+            root.extensions_used.push(String::from(*extension));
+        }
+    }
     let file = File::create(dst_path).expect("Failed to open glTF JSON file");
     let writer = BufWriter::new(file);
-    root.to_writer_pretty(writer)
-        .map_err(|e| error!("{:?}", e))
-        .expect("Failed to write glTF JSON file");
+    let has_texture_transforms = root
+        .extensions_used
+        .iter()
+        .any(|used| used == "KHR_texture_transform");
+    if extension_images.is_empty() && !has_texture_transforms {
+        root.to_writer_pretty(writer)
+            .map_err(|e| error!("{:?}", e))
+            .expect("Failed to write glTF JSON file");
+    } else {
+        // What gltf-json cannot write as glTF is patched in the JSON
+        let mut json = serde_json::to_value(root)
+            .map_err(|e| error!("{:?}", e))
+            .expect("Failed to serialize glTF JSON");
+        remove_texture_transform_nulls(&mut json);
+        add_extension_sources(&mut json, extension_images);
+        serde_json::to_writer_pretty(writer, &json)
+            .map_err(|e| error!("{:?}", e))
+            .expect("Failed to write glTF JSON file");
+    }
     info!("Wrote new glTF file at: {:?}", dst_path.as_ref());
 }
+
+/// Adds the texture extension of each texture whose image has an extension image, as gltf-json
+/// has no fields for `MSFT_texture_dds`, `EXT_texture_webp` or `EXT_texture_avif`
+fn add_extension_sources(
+    json: &mut serde_json::Value,
+    extension_images: &HashMap<usize, ExtensionImage>,
+) {
+    let textures = match json.get_mut("textures").and_then(|t| t.as_array_mut()) {
+        Some(textures) => textures,
+        None => return,
+    };
+    for texture in textures {
+        let extension_image = texture
+            .get("source")
+            .and_then(|source| source.as_u64())
+            .and_then(|source| extension_images.get(&(source as usize)));
+        if let (Some((image, extension)), Some(texture)) =
+            (extension_image, texture.as_object_mut())
+        {
+            let extensions = texture
+                .entry("extensions")
+                .or_insert_with(|| serde_json::json!({}));
+            extensions[*extension] = serde_json::json!({ "source": image });
+        }
+    }
+}
+
+/// Removes properties set to null from `KHR_texture_transform` objects, which glTF does not allow
+/// but gltf-json writes for an unset texCoord
+fn remove_texture_transform_nulls(json: &mut serde_json::Value) {
+    match json {
+        serde_json::Value::Object(object) => {
+            if let Some(serde_json::Value::Object(transform)) =
+                object.get_mut("KHR_texture_transform")
+            {
+                transform.retain(|_, value| !value.is_null());
+            }
+            object.values_mut().for_each(remove_texture_transform_nulls);
+        }
+        serde_json::Value::Array(array) => {
+            array.iter_mut().for_each(remove_texture_transform_nulls)
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::test_dir;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        options: CompressionOptions,
+    }
+
+    /// Stands in for etcpak, which rejects images that are not a multiple of its blocks, and
+    /// records the dimensions it was given and whether it was asked for mipmaps
+    #[derive(Default)]
+    struct WholeBlocks {
+        calls: RefCell<Vec<(u32, u32, bool)>>,
+    }
+
+    impl Compressor for WholeBlocks {
+        fn compress(
+            &self,
+            working_dir: &Path,
+            src_path: &Path,
+            dst_path: &Path,
+            _texture_type: TextureType,
+            _compression_format: CompressionFormat,
+            _container_format: ContainerFormat,
+            options: &CompressionOptions,
+        ) -> Result<Vec<String>, CompressError> {
+            let (width, height) = ::image::image_dimensions(working_dir.join(src_path)).unwrap();
+            self.calls
+                .borrow_mut()
+                .push((width, height, options.mipmaps.enabled()));
+            if width % 4 != 0 || height % 4 != 0 {
+                return Err(format!("{}x{} is not a multiple of 4", width, height).into());
+            }
+            std::fs::write(working_dir.join(dst_path), []).unwrap();
+            Ok(vec![String::from("etcpak")])
+        }
+
+        fn needs_whole_blocks(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn leaves_coverage_mips_to_whole_block_backends() {
+        let dir = test_dir("leaves_coverage_mips_to_whole_block_backends");
+        let src_path = Path::new("cutout.png");
+        // A checkerboard of opaque and transparent texels, as on an alpha-masked base color
+        ::image::RgbaImage::from_fn(12, 12, |x, y| {
+            ::image::Rgba([255, 255, 255, if (x + y) % 2 == 0 { 255 } else { 0 }])
+        })
+        .save(dir.join(src_path))
+        .unwrap();
+        let options = Cli::parse_from(["klafsa", "--mipmaps", "on"]).options;
+        let job = TextureJob {
+            working_dir: &dir,
+            src_path,
+            image_path: src_path,
+            basis_source: None,
+            input_path: src_path,
+            texture_type: TextureType::Srgb,
+            size: None,
+            dimensions: (12, 12),
+            can_pad: false,
+            alpha: Some(AlphaUsage::Alpha),
+            alpha_cutoff: Some(0.5),
+            options: &options,
+        };
+        let compressor = WholeBlocks::default();
+        compress_job(
+            &compressor,
+            &job,
+            src_path,
+            Path::new("bc7_ktx2/cutout_bc7.ktx2"),
+            CompressionFormat::Bc7,
+            ContainerFormat::Ktx2,
+        )
+        .unwrap();
+        assert_eq!(*compressor.calls.borrow(), [(12, 12, true)]);
+    }
+}
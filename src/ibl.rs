@@ -0,0 +1,419 @@
+use std::{
+    f32::consts::PI,
+    path::{Path, PathBuf},
+};
+
+use clap::Args;
+use image::{imageops, Rgba, Rgba32FImage};
+use tracing::info;
+
+use crate::{
+    cubemap::{
+        face_direction, faces_from_equirect, normalize, sample_equirect, texel_coordinates,
+        FACE_COUNT,
+    },
+    image::open_image,
+    ktx::merge_ktx2_levels,
+    write_layers, Backend, CompressionFormat, CompressionOptions, Compressor, ContainerFormat,
+    MipWrap, MipmapMode, TextureShape, TextureType,
+};
+
+/// Smallest face size of the prefiltered specular mip chain
+const MIN_SPECULAR_SIZE: u32 = 4;
+/// Width of the panorama that irradiance is integrated over
+const IRRADIANCE_SOURCE_WIDTH: u32 = 256;
+const BRDF_LUT_SIZE: u32 = 256;
+const BRDF_LUT_SAMPLES: u32 = 512;
+
+#[derive(Args, Debug)]
+pub struct Ibl {
+    /// Path to the equirectangular HDR environment map
+    pub file_path: String,
+    /// Prefix of the output paths. Writes <prefix>_specular.ktx2, <prefix>_irradiance.ktx2 and
+    /// <prefix>_brdf_lut.ktx2
+    #[clap(short, long)]
+    output: String,
+    /// Face size of the first mip level of the specular cubemap
+    #[clap(long, default_value_t = 256)]
+    specular_size: u32,
+    /// Face size of the irradiance cubemap
+    #[clap(long, default_value_t = 32)]
+    irradiance_size: u32,
+    /// Number of GGX samples per texel of the specular cubemap
+    #[clap(long, default_value_t = 256)]
+    samples: u32,
+    /// Also write the split-sum BRDF lookup table, with NdotV along X and roughness along Y
+    #[clap(long)]
+    brdf_lut: bool,
+}
+
+impl Ibl {
+    pub fn process(
+        &self,
+        backend: Backend,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<(), String> {
+        if !compression_format.is_hdr() {
+            return Err(format!(
+                "Environment maps need an HDR codec rather than {}",
+                compression_format
+            ));
+        }
+        if !matches!(container_format, ContainerFormat::Ktx2) {
+            return Err(format!(
+                "Environment maps can only be written to {}",
+                ContainerFormat::Ktx2
+            ));
+        }
+        if !matches!(backend, Backend::Kram) {
+            return Err(format!(
+                "Environment maps are HDR cubemaps, which {} cannot compress. Use --backend {}",
+                backend,
+                Backend::Kram
+            ));
+        }
+        if !self.specular_size.is_power_of_two() || !self.irradiance_size.is_power_of_two() {
+            return Err(String::from("Cubemap face sizes must be powers of two"));
+        }
+
+        info!("Processing {}", self.file_path);
+        let panorama = open_image(Path::new(&self.file_path))?.to_rgba32f();
+        let pyramid = pyramid(panorama);
+        let working_dir = std::env::current_dir()
+            .map_err(|e| format!("Failed to get the current directory - {}", e))?;
+        let compressor = backend.compressor()?;
+        // Every level is prefiltered here, so the backends must not generate mipmaps
+        let mut options = options.clone();
+        options.mipmaps.mode = MipmapMode::Off;
+        options.mipmaps.mip_wrap = Some(MipWrap::Clamp);
+
+        let specular_path = PathBuf::from(format!("{}_specular.ktx2", self.output));
+        let levels = self.specular_levels(&pyramid);
+        info!(
+            "Compressing {} specular mip levels to {}",
+            levels.len(),
+            specular_path.display()
+        );
+        let level_paths = (0..levels.len())
+            .map(|level| specular_path.with_extension(format!("level_{}.ktx2", level)))
+            .collect::<Vec<_>>();
+        let result = levels
+            .iter()
+            .zip(&level_paths)
+            .try_for_each(|(faces, level_path)| {
+                compressor
+                    .compress_layers(
+                        &working_dir,
+                        faces,
+                        TextureShape::Cubemap,
+                        level_path,
+                        TextureType::Hdr,
+                        compression_format,
+                        container_format,
+                        &options,
+                    )
+                    .map(|_| ())
+                    .map_err(String::from)
+            })
+            .and_then(|_| merge_ktx2_levels(&level_paths, &specular_path));
+        for level_path in &level_paths {
+            let _ = std::fs::remove_file(working_dir.join(level_path));
+        }
+        result?;
+
+        let irradiance_path = PathBuf::from(format!("{}_irradiance.ktx2", self.output));
+        info!("Compressing irradiance to {}", irradiance_path.display());
+        compressor.compress_layers(
+            &working_dir,
+            &irradiance_faces(&pyramid, self.irradiance_size),
+            TextureShape::Cubemap,
+            &irradiance_path,
+            TextureType::Hdr,
+            compression_format,
+            container_format,
+            &options,
+        )?;
+
+        if self.brdf_lut {
+            let lut_path = PathBuf::from(format!("{}_brdf_lut.ktx2", self.output));
+            info!("Compressing BRDF lookup table to {}", lut_path.display());
+            compress_image(
+                compressor.as_ref(),
+                &working_dir,
+                &brdf_lut(BRDF_LUT_SIZE, BRDF_LUT_SAMPLES),
+                &lut_path,
+                compression_format,
+                container_format,
+                &options,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Prefilters the environment for GGX with roughness going from 0 at the first mip level to 1
+    /// at the last
+    fn specular_levels(&self, pyramid: &[Rgba32FImage]) -> Vec<Vec<Rgba32FImage>> {
+        let level_count =
+            (self.specular_size.max(MIN_SPECULAR_SIZE) / MIN_SPECULAR_SIZE).ilog2() + 1;
+        (0..level_count)
+            .map(|level| {
+                let size = (self.specular_size >> level).max(1);
+                if level == 0 {
+                    return faces_from_equirect(&pyramid[0], size);
+                }
+                let roughness = level as f32 / (level_count - 1) as f32;
+                (0..FACE_COUNT)
+                    .map(|face| {
+                        Rgba32FImage::from_fn(size, size, |x, y| {
+                            let (u, v) = texel_coordinates(x, y, size);
+                            prefilter_ggx(
+                                pyramid,
+                                face_direction(face, u, v),
+                                roughness,
+                                self.samples,
+                            )
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Writes a single image for the backend and compresses it as a 2D texture
+fn compress_image(
+    compressor: &dyn Compressor,
+    working_dir: &Path,
+    image: &Rgba32FImage,
+    dst_path: &Path,
+    compression_format: CompressionFormat,
+    container_format: ContainerFormat,
+    options: &CompressionOptions,
+) -> Result<Vec<String>, String> {
+    let layers_dir = dst_path.with_extension("layers");
+    let result = write_layers(
+        working_dir,
+        &layers_dir,
+        std::slice::from_ref(image),
+        TextureType::Hdr,
+    )
+    .and_then(|src_paths| {
+        compressor
+            .compress(
+                working_dir,
+                &src_paths[0],
+                dst_path,
+                TextureType::Hdr,
+                compression_format,
+                container_format,
+                options,
+            )
+            .map_err(String::from)
+    });
+    let _ = std::fs::remove_dir_all(working_dir.join(&layers_dir));
+    result
+}
+
+/// Halves the panorama until it is a single texel high, for filtered importance sampling
+fn pyramid(panorama: Rgba32FImage) -> Vec<Rgba32FImage> {
+    let mut pyramid = vec![panorama];
+    while let Some(last) = pyramid.last().filter(|last| last.height() > 1) {
+        let (width, height) = ((last.width() / 2).max(1), last.height() / 2);
+        let next = imageops::resize(last, width, height, imageops::FilterType::Triangle);
+        pyramid.push(next);
+    }
+    pyramid
+}
+
+/// Trilinearly samples the panorama pyramid, with `lod` 0 being full resolution
+fn sample_pyramid(pyramid: &[Rgba32FImage], direction: [f32; 3], lod: f32) -> [f32; 4] {
+    let lod = lod.clamp(0.0, (pyramid.len() - 1) as f32);
+    let lower = lod.floor() as usize;
+    let upper = (lower + 1).min(pyramid.len() - 1);
+    let t = lod - lower as f32;
+    let a = sample_equirect(&pyramid[lower], direction).0;
+    let b = sample_equirect(&pyramid[upper], direction).0;
+    [0, 1, 2, 3].map(|i| a[i] + (b[i] - a[i]) * t)
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+/// Point `i` of an `n` point Hammersley set
+fn hammersley(i: u32, n: u32) -> (f32, f32) {
+    (
+        i as f32 / n as f32,
+        i.reverse_bits() as f32 / (u32::MAX as f32 + 1.0),
+    )
+}
+
+/// GGX-distributed half vector around `normal`, and its cosine with the normal
+fn importance_sample_ggx((x1, x2): (f32, f32), normal: [f32; 3], alpha: f32) -> ([f32; 3], f32) {
+    let phi = 2.0 * PI * x1;
+    let cos_theta = ((1.0 - x2) / (1.0 + (alpha * alpha - 1.0) * x2)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let up = if normal[2].abs() < 0.999 {
+        [0.0, 0.0, 1.0]
+    } else {
+        [1.0, 0.0, 0.0]
+    };
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    let h = [0, 1, 2].map(|i| {
+        tangent[i] * sin_theta * phi.cos()
+            + bitangent[i] * sin_theta * phi.sin()
+            + normal[i] * cos_theta
+    });
+    (normalize(h), cos_theta)
+}
+
+fn ggx_distribution(n_dot_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+/// Radiance convolved with the GGX lobe around `normal`, assuming the view direction equals the
+/// normal. Samples come from lower resolution levels the less likely they are, which removes
+/// most of the noise of a low sample count.
+fn prefilter_ggx(
+    pyramid: &[Rgba32FImage],
+    normal: [f32; 3],
+    roughness: f32,
+    samples: u32,
+) -> Rgba<f32> {
+    let alpha = roughness * roughness;
+    let (width, height) = pyramid[0].dimensions();
+    let texel_solid_angle = 4.0 * PI / (width * height) as f32;
+    let mut color = [0.0; 3];
+    let mut weight = 0.0;
+    for i in 0..samples {
+        let (h, n_dot_h) = importance_sample_ggx(hammersley(i, samples), normal, alpha);
+        let v_dot_h = dot(normal, h);
+        let l = [0, 1, 2].map(|c| 2.0 * v_dot_h * h[c] - normal[c]);
+        let n_dot_l = dot(normal, l);
+        if n_dot_l <= 0.0 {
+            continue;
+        }
+        let pdf = ggx_distribution(n_dot_h, alpha) / 4.0;
+        let sample_solid_angle = 1.0 / (samples as f32 * pdf + f32::EPSILON);
+        let lod = 0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.0;
+        let sample = sample_pyramid(pyramid, l, lod);
+        for c in 0..3 {
+            color[c] += sample[c] * n_dot_l;
+        }
+        weight += n_dot_l;
+    }
+    let weight = weight.max(f32::EPSILON);
+    Rgba([color[0] / weight, color[1] / weight, color[2] / weight, 1.0])
+}
+
+/// The first nine real spherical harmonics basis functions in the given direction
+fn sh_basis([x, y, z]: [f32; 3]) -> [f32; 9] {
+    [
+        0.282095,
+        0.488603 * y,
+        0.488603 * z,
+        0.488603 * x,
+        1.092548 * x * y,
+        1.092548 * y * z,
+        0.315392 * (3.0 * z * z - 1.0),
+        1.092548 * x * z,
+        0.546274 * (x * x - y * y),
+    ]
+}
+
+/// Cosine-weighted average radiance over the hemisphere around each texel's direction, that is
+/// irradiance divided by pi, computed from a third-order spherical harmonics projection
+fn irradiance_faces(pyramid: &[Rgba32FImage], size: u32) -> Vec<Rgba32FImage> {
+    let source = pyramid
+        .iter()
+        .find(|level| level.width() <= IRRADIANCE_SOURCE_WIDTH)
+        .unwrap_or(&pyramid[pyramid.len() - 1]);
+    let (width, height) = source.dimensions();
+    let mut coefficients = [[0.0f32; 3]; 9];
+    for (x, y, pixel) in source.enumerate_pixels() {
+        let theta = (y as f32 + 0.5) / height as f32 * PI;
+        let phi = ((x as f32 + 0.5) / width as f32 - 0.5) * 2.0 * PI;
+        let direction = [
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        ];
+        let solid_angle = (2.0 * PI / width as f32) * (PI / height as f32) * theta.sin();
+        for (coefficient, basis) in coefficients.iter_mut().zip(sh_basis(direction)) {
+            for (value, radiance) in coefficient.iter_mut().zip(pixel.0) {
+                *value += radiance * basis * solid_angle;
+            }
+        }
+    }
+    // Convolution with the clamped cosine lobe, divided by pi
+    let bands = [
+        1.0,
+        2.0 / 3.0,
+        2.0 / 3.0,
+        2.0 / 3.0,
+        0.25,
+        0.25,
+        0.25,
+        0.25,
+        0.25,
+    ];
+    (0..FACE_COUNT)
+        .map(|face| {
+            Rgba32FImage::from_fn(size, size, |x, y| {
+                let (u, v) = texel_coordinates(x, y, size);
+                let basis = sh_basis(face_direction(face, u, v));
+                let mut color = [0.0, 0.0, 0.0, 1.0];
+                for i in 0..9 {
+                    for c in 0..3 {
+                        color[c] += bands[i] * coefficients[i][c] * basis[i];
+                    }
+                }
+                for value in color.iter_mut().take(3) {
+                    *value = value.max(0.0);
+                }
+                Rgba(color)
+            })
+        })
+        .collect()
+}
+
+/// Split-sum scale and bias to F0 of the GGX specular BRDF in red and green, with NdotV going
+/// from 0 to 1 along X and roughness from 0 to 1 along Y
+fn brdf_lut(size: u32, samples: u32) -> Rgba32FImage {
+    Rgba32FImage::from_fn(size, size, |x, y| {
+        let n_dot_v = (x as f32 + 0.5) / size as f32;
+        let roughness = (y as f32 + 0.5) / size as f32;
+        let alpha = roughness * roughness;
+        let k = alpha / 2.0;
+        let geometry = |n_dot: f32| n_dot / (n_dot * (1.0 - k) + k);
+        let view = [(1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v];
+        let (mut scale, mut bias) = (0.0, 0.0);
+        for i in 0..samples {
+            let (h, n_dot_h) =
+                importance_sample_ggx(hammersley(i, samples), [0.0, 0.0, 1.0], alpha);
+            let v_dot_h = dot(view, h);
+            let n_dot_l = 2.0 * v_dot_h * h[2] - view[2];
+            if n_dot_l <= 0.0 {
+                continue;
+            }
+            let visibility = geometry(n_dot_v) * geometry(n_dot_l) * v_dot_h / (n_dot_h * n_dot_v);
+            let fresnel = (1.0 - v_dot_h).powi(5);
+            scale += (1.0 - fresnel) * visibility;
+            bias += fresnel * visibility;
+        }
+        Rgba([scale / samples as f32, bias / samples as f32, 0.0, 1.0])
+    })
+}
@@ -1,31 +1,146 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use ::image::{codecs::hdr::HdrDecoder, io::Reader, DynamicImage, Rgb32FImage};
 use tracing::warn;
 
-#[derive(Debug)]
+use crate::ktx::write_ktx1_rgba32f;
+
+/// Signature at the end of TGA 2.0 files, which have no magic bytes at the start
+const TGA_FOOTER_SIGNATURE: &[u8; 18] = b"TRUEVISION-XFILE.\0";
+
+#[derive(Clone, Copy, Debug, strum::Display, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
 pub(crate) enum ImageFormat {
+    Avif,
+    Basis,
+    Bmp,
+    Dds,
+    Exr,
+    Gif,
+    Hdr,
     Jpeg,
+    Ktx,
+    Ktx2,
     Png,
+    Tga,
+    Tiff,
+    WebP,
 }
 
 impl ImageFormat {
+    /// Whether klafsa can compress images of this format. KTX2 and Basis images must hold ETC1S or
+    /// UASTC.
+    pub(crate) fn is_supported(&self) -> bool {
+        matches!(
+            *self,
+            ImageFormat::Basis
+                | ImageFormat::Bmp
+                | ImageFormat::Exr
+                | ImageFormat::Hdr
+                | ImageFormat::Jpeg
+                | ImageFormat::Ktx2
+                | ImageFormat::Png
+                | ImageFormat::Tga
+                | ImageFormat::Tiff
+                | ImageFormat::WebP
+        )
+    }
+
+    /// Whether images of this format hold floating point HDR data
+    pub(crate) fn is_hdr(&self) -> bool {
+        matches!(*self, ImageFormat::Exr | ImageFormat::Hdr)
+    }
+
+    /// Whether all backends can read this format directly, rather than through an intermediate
+    /// PNG file
+    pub(crate) fn is_native(&self) -> bool {
+        matches!(*self, ImageFormat::Jpeg | ImageFormat::Png)
+    }
+
+    pub(crate) fn from_magic_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(match bytes {
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => ImageFormat::Png,
+            [0xFF, 0xD8, 0xFF, ..] => ImageFormat::Jpeg,
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => ImageFormat::WebP,
+            [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, 0x0D, 0x0A, 0x1A, 0x0A, ..] => {
+                ImageFormat::Ktx2
+            }
+            [0xAB, b'K', b'T', b'X', b' ', b'1', b'1', 0xBB, 0x0D, 0x0A, 0x1A, 0x0A, ..] => {
+                ImageFormat::Ktx
+            }
+            [b's', b'B', 0x13, 0x00, ..] => ImageFormat::Basis,
+            [b'D', b'D', b'S', b' ', ..] => ImageFormat::Dds,
+            [b'G', b'I', b'F', b'8', ..] => ImageFormat::Gif,
+            [b'B', b'M', ..] => ImageFormat::Bmp,
+            [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => ImageFormat::Tiff,
+            [0x76, 0x2F, 0x31, 0x01, ..] => ImageFormat::Exr,
+            [b'#', b'?', b'R', b'A', b'D', b'I', b'A', b'N', b'C', b'E', ..]
+            | [b'#', b'?', b'R', b'G', b'B', b'E', ..] => ImageFormat::Hdr,
+            [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f', ..] => ImageFormat::Avif,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn from_mime_type(mime_type: &str) -> Option<Self> {
+        Some(match mime_type.to_lowercase().as_str() {
+            "image/avif" => ImageFormat::Avif,
+            "image/bmp" => ImageFormat::Bmp,
+            "image/vnd-ms.dds" | "image/vnd.ms-dds" => ImageFormat::Dds,
+            "image/x-exr" | "image/aces" => ImageFormat::Exr,
+            "image/gif" => ImageFormat::Gif,
+            "image/vnd.radiance" => ImageFormat::Hdr,
+            "image/jpeg" => ImageFormat::Jpeg,
+            "image/ktx" => ImageFormat::Ktx,
+            "image/ktx2" => ImageFormat::Ktx2,
+            "image/png" => ImageFormat::Png,
+            "image/x-tga" | "image/x-targa" => ImageFormat::Tga,
+            "image/tiff" => ImageFormat::Tiff,
+            "image/webp" => ImageFormat::WebP,
+            _ => return None,
+        })
+    }
+
+    pub(crate) fn from_extension(extension: &str) -> Option<Self> {
+        Some(match extension.to_lowercase().as_str() {
+            "avif" => ImageFormat::Avif,
+            "basis" => ImageFormat::Basis,
+            "bmp" => ImageFormat::Bmp,
+            "dds" => ImageFormat::Dds,
+            "exr" => ImageFormat::Exr,
+            "gif" => ImageFormat::Gif,
+            "hdr" => ImageFormat::Hdr,
+            "jpeg" | "jpg" => ImageFormat::Jpeg,
+            "ktx" => ImageFormat::Ktx,
+            "ktx2" => ImageFormat::Ktx2,
+            "png" => ImageFormat::Png,
+            "tga" => ImageFormat::Tga,
+            "tif" | "tiff" => ImageFormat::Tiff,
+            "webp" => ImageFormat::WebP,
+            _ => return None,
+        })
+    }
+
     pub(crate) fn from_mime_or_extension(
         mime_type: Option<&str>,
         extension: Option<&str>,
     ) -> Option<Self> {
         if let Some(mime_type) = mime_type {
-            match mime_type.to_lowercase().as_str() {
-                "image/jpeg" => return Some(ImageFormat::Jpeg),
-                "image/png" => return Some(ImageFormat::Png),
-                _ => {
+            match ImageFormat::from_mime_type(mime_type) {
+                Some(format) => return Some(format),
+                None => {
                     warn!("Unsupported mime-type: {}", mime_type);
                 }
             }
         }
         if let Some(extension) = extension {
             if let Some((_, extension)) = extension.rsplit_once('.') {
-                match extension.to_lowercase().as_str() {
-                    "jpeg" => return Some(ImageFormat::Jpeg),
-                    "png" => return Some(ImageFormat::Png),
-                    _ => {
+                match ImageFormat::from_extension(extension) {
+                    Some(format) => return Some(format),
+                    None => {
                         warn!("Unsupported extension: {}", extension);
                     }
                 }
@@ -33,4 +148,218 @@ impl ImageFormat {
         }
         None
     }
+
+    /// Identifies the format of an image file from its content, falling back to the declared mime
+    /// type and the file extension. Warns when the declarations disagree with the content.
+    pub(crate) fn detect(path: &Path, mime_type: Option<&str>) -> Option<Self> {
+        let sniffed = match sniff(path) {
+            Ok(sniffed) => sniffed,
+            Err(e) => {
+                warn!("Failed to read {} - {}", path.display(), e);
+                None
+            }
+        };
+        let sniffed = match sniffed {
+            Some(sniffed) => sniffed,
+            None => return ImageFormat::from_mime_or_extension(mime_type, path.to_str()),
+        };
+        for mismatch in sniffed.mismatches(path, mime_type) {
+            warn!("{}", mismatch);
+        }
+        Some(sniffed)
+    }
+
+    /// Describes how the declared mime type and the extension of a file disagree with its content
+    fn mismatches(&self, path: &Path, mime_type: Option<&str>) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        if let Some(mime_type) = mime_type {
+            if ImageFormat::from_mime_type(mime_type) != Some(*self) {
+                mismatches.push(format!(
+                    "{} is declared as {} but contains {}",
+                    path.display(),
+                    mime_type,
+                    self
+                ));
+            }
+        }
+        if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+            if ImageFormat::from_extension(extension) != Some(*self) {
+                mismatches.push(format!(
+                    "{} has a .{} extension but contains {}",
+                    path.display(),
+                    extension,
+                    self
+                ));
+            }
+        }
+        mismatches
+    }
+}
+
+fn sniff(path: &Path) -> std::io::Result<Option<ImageFormat>> {
+    let mut file = File::open(path)?;
+    let mut header = Vec::with_capacity(32);
+    file.by_ref().take(32).read_to_end(&mut header)?;
+    if let Some(format) = ImageFormat::from_magic_bytes(&header) {
+        return Ok(Some(format));
+    }
+    let mut footer = [0; TGA_FOOTER_SIGNATURE.len()];
+    if file.seek(SeekFrom::End(-(footer.len() as i64))).is_ok()
+        && file.read_exact(&mut footer).is_ok()
+        && &footer == TGA_FOOTER_SIGNATURE
+    {
+        return Ok(Some(ImageFormat::Tga));
+    }
+    Ok(None)
+}
+
+/// Decodes an image, identifying its format by content rather than by file extension
+pub(crate) fn open_image(path: &Path) -> Result<DynamicImage, String> {
+    if matches!(sniff(path), Ok(Some(ImageFormat::Hdr))) {
+        return open_radiance(path);
+    }
+    Reader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| format!("Failed to read {} - {}", path.display(), e))?
+        .decode()
+        .map_err(|e| format!("Failed to decode {} - {}", path.display(), e))
+}
+
+/// Decodes a Radiance HDR image at full range, which the generic decoder converts to 8 bits
+fn open_radiance(path: &Path) -> Result<DynamicImage, String> {
+    let file =
+        File::open(path).map_err(|e| format!("Failed to read {} - {}", path.display(), e))?;
+    let decode_error = |e| format!("Failed to decode {} - {}", path.display(), e);
+    let decoder = HdrDecoder::new(BufReader::new(file)).map_err(decode_error)?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_hdr().map_err(decode_error)?;
+    Rgb32FImage::from_raw(
+        metadata.width,
+        metadata.height,
+        pixels.iter().flat_map(|pixel| pixel.0).collect(),
+    )
+    .map(DynamicImage::ImageRgb32F)
+    .ok_or_else(|| format!("Failed to decode {} - truncated pixel data", path.display()))
+}
+
+/// Reads the dimensions of an image without decoding it
+pub(crate) fn image_dimensions(path: &Path) -> Result<(u32, u32), String> {
+    Reader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| format!("Failed to read {} - {}", path.display(), e))?
+        .into_dimensions()
+        .map_err(|e| format!("Failed to decode {} - {}", path.display(), e))
+}
+
+/// Whether any pixel of the image is not fully opaque
+pub(crate) fn has_alpha(path: &Path) -> Result<bool, String> {
+    let image = open_image(path)?;
+    if !image.color().has_alpha() {
+        return Ok(false);
+    }
+    Ok(image.to_rgba8().pixels().any(|pixel| pixel.0[3] < u8::MAX))
+}
+
+/// Writes a decoded source image as a PNG file for backends that cannot read its format.
+/// Floating point images are stored as 16-bit, clamped to [0, 1].
+pub(crate) fn write_intermediate_png(
+    image: DynamicImage,
+    src_path: &Path,
+    dst_path: &Path,
+) -> Result<(), String> {
+    let image = match image {
+        image @ (DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)) => {
+            let rgba = image.to_rgba32f();
+            if rgba.pixels().any(|pixel| pixel.0.iter().any(|&c| c > 1.0)) {
+                warn!(
+                    "{} has values above 1.0, which are clamped in the intermediate PNG",
+                    src_path.display()
+                );
+            }
+            DynamicImage::ImageRgba16(image.to_rgba16())
+        }
+        image => image,
+    };
+    image
+        .save_with_format(dst_path, ::image::ImageFormat::Png)
+        .map_err(|e| format!("Failed to write {} - {}", dst_path.display(), e))
+}
+
+/// Writes a decoded HDR source image as a floating point KTX file
+pub(crate) fn write_intermediate_ktx(image: &DynamicImage, dst_path: &Path) -> Result<(), String> {
+    write_ktx1_rgba32f(dst_path, &image.to_rgba32f())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    #[test]
+    fn magic_bytes() {
+        let cases: [(&[u8], ImageFormat); 14] = [
+            (b"\x89PNG\r\n\x1a\n", ImageFormat::Png),
+            (b"\xFF\xD8\xFF\xE0", ImageFormat::Jpeg),
+            (b"RIFF\0\0\0\0WEBPVP8 ", ImageFormat::WebP),
+            (b"\xABKTX 20\xBB\r\n\x1a\n", ImageFormat::Ktx2),
+            (b"\xABKTX 11\xBB\r\n\x1a\n", ImageFormat::Ktx),
+            (b"sB\x13\0", ImageFormat::Basis),
+            (b"DDS |\0\0\0", ImageFormat::Dds),
+            (b"GIF89a", ImageFormat::Gif),
+            (b"BM6\0", ImageFormat::Bmp),
+            (b"II*\0", ImageFormat::Tiff),
+            (b"MM\0*", ImageFormat::Tiff),
+            (b"v/1\x01", ImageFormat::Exr),
+            (b"#?RADIANCE\n", ImageFormat::Hdr),
+            (b"\0\0\0\x1cftypavif", ImageFormat::Avif),
+        ];
+        for (bytes, format) in cases {
+            assert_eq!(ImageFormat::from_magic_bytes(bytes), Some(format));
+        }
+        assert_eq!(
+            ImageFormat::from_magic_bytes(b"#?RGBE"),
+            Some(ImageFormat::Hdr)
+        );
+        assert_eq!(ImageFormat::from_magic_bytes(b"\x89PNG"), None);
+        assert_eq!(ImageFormat::from_magic_bytes(&[]), None);
+    }
+
+    #[test]
+    fn sniffs_tga_footer() {
+        let dir = test_dir("sniffs_tga_footer");
+        let path = dir.join("image.tga");
+        let mut bytes = vec![0; 44];
+        bytes.extend_from_slice(TGA_FOOTER_SIGNATURE);
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(sniff(&path).unwrap(), Some(ImageFormat::Tga));
+
+        std::fs::write(&path, &bytes[..44]).unwrap();
+        assert_eq!(sniff(&path).unwrap(), None);
+        // Without magic bytes, the extension decides
+        assert_eq!(ImageFormat::detect(&path, None), Some(ImageFormat::Tga));
+    }
+
+    #[test]
+    fn content_overrides_declared_format() {
+        let dir = test_dir("content_overrides_declared_format");
+        let path = dir.join("image.png");
+        std::fs::write(&path, b"\xFF\xD8\xFF\xE0\0\x10JFIF").unwrap();
+        assert_eq!(
+            ImageFormat::detect(&path, Some("image/png")),
+            Some(ImageFormat::Jpeg)
+        );
+        assert_eq!(
+            ImageFormat::Jpeg.mismatches(&path, Some("image/png")),
+            [
+                format!(
+                    "{} is declared as image/png but contains jpeg",
+                    path.display()
+                ),
+                format!("{} has a .png extension but contains jpeg", path.display()),
+            ]
+        );
+        assert!(ImageFormat::Png
+            .mismatches(&path, Some("image/png"))
+            .is_empty());
+    }
 }
@@ -3,19 +3,30 @@ use std::{
     process::Command,
 };
 
+use image::{imageops, Rgba32FImage, RgbaImage};
+use tracing::warn;
 use which::which;
 
-use crate::{CompressionFormat, Compressor, ContainerFormat, TextureType};
+use crate::{
+    ktx::read_ktx1_rgba8, run_command, write_layers, CompressError, CompressionFormat,
+    CompressionOptions, Compressor, ContainerFormat, MipWrap, TextureShape, TextureType,
+};
 
-pub const SUPPORTED_COMPRESSION_FORMATS: [CompressionFormat; 13] = [
+pub(crate) const SUPPORTED_COMPRESSION_FORMATS: [CompressionFormat; 19] = [
     CompressionFormat::Astc4x4,
     CompressionFormat::Astc5x5,
     CompressionFormat::Astc6x6,
     CompressionFormat::Astc8x8,
+    CompressionFormat::Astc4x4hdr,
+    CompressionFormat::Astc5x5hdr,
+    CompressionFormat::Astc6x6hdr,
+    CompressionFormat::Astc8x8hdr,
     CompressionFormat::Bc1,
     CompressionFormat::Bc3,
     CompressionFormat::Bc4,
     CompressionFormat::Bc5,
+    CompressionFormat::Bc6h,
+    CompressionFormat::Bc6hs,
     CompressionFormat::Bc7,
     CompressionFormat::Etc2r,
     CompressionFormat::Etc2rg,
@@ -40,16 +51,17 @@ impl Kram {
     }
 }
 
-impl Compressor for Kram {
-    fn compress(
+impl Kram {
+    /// Builds the command for everything but the input file
+    fn command(
         &self,
         working_dir: &Path,
-        src_path: &Path,
         dst_path: &Path,
         texture_type: TextureType,
         compression_format: CompressionFormat,
         container_format: ContainerFormat,
-    ) -> Result<(), String> {
+        options: &CompressionOptions,
+    ) -> Result<Command, String> {
         if !SUPPORTED_COMPRESSION_FORMATS.contains(&compression_format)
             || !matches!(container_format, ContainerFormat::Ktx2)
         {
@@ -65,15 +77,29 @@ impl Compressor for Kram {
         command.current_dir(working_dir);
         command.args([
             "encode",
-            "-input",
-            src_path.to_str().unwrap(),
             "-output",
             dst_path.to_str().unwrap(),
-            "-mipmin",
-            "1",
             "-zstd",
             "0",
         ]);
+        let mipmaps = &options.mipmaps;
+        if mipmaps.enabled() {
+            command.args([
+                "-mipmin",
+                mipmaps.mip_min_size.unwrap_or(1).to_string().as_str(),
+            ]);
+            if matches!(mipmaps.mip_wrap, Some(MipWrap::Wrap)) {
+                command.arg("-wrap");
+            }
+            if let Some(mip_filter) = mipmaps.mip_filter {
+                warn!(
+                    "kram has no choice of mip filter, so --mip-filter {} is ignored",
+                    mip_filter
+                );
+            }
+        } else {
+            command.arg("-mipnone");
+        }
         match compression_format {
             CompressionFormat::Astc4x4 => {
                 command.args(["-format", "astc4x4", "-encoder", "astcenc"]);
@@ -87,6 +113,18 @@ impl Compressor for Kram {
             CompressionFormat::Astc8x8 => {
                 command.args(["-format", "astc8x8", "-encoder", "astcenc"]);
             }
+            CompressionFormat::Astc4x4hdr => {
+                command.args(["-format", "astc4x4", "-encoder", "astcenc", "-hdr"]);
+            }
+            CompressionFormat::Astc5x5hdr => {
+                command.args(["-format", "astc5x5", "-encoder", "astcenc", "-hdr"]);
+            }
+            CompressionFormat::Astc6x6hdr => {
+                command.args(["-format", "astc6x6", "-encoder", "astcenc", "-hdr"]);
+            }
+            CompressionFormat::Astc8x8hdr => {
+                command.args(["-format", "astc8x8", "-encoder", "astcenc", "-hdr"]);
+            }
             CompressionFormat::Bc1 => {
                 command.args(["-format", "bc1", "-encoder", "bcenc"]);
             }
@@ -99,6 +137,12 @@ impl Compressor for Kram {
             CompressionFormat::Bc5 => {
                 command.args(["-format", "bc5", "-encoder", "bcenc"]);
             }
+            CompressionFormat::Bc6h => {
+                command.args(["-format", "bc6"]);
+            }
+            CompressionFormat::Bc6hs => {
+                command.args(["-format", "bc6", "-signed"]);
+            }
             CompressionFormat::Bc7 => {
                 command.args(["-format", "bc7", "-encoder", "bcenc"]);
             }
@@ -124,6 +168,18 @@ impl Compressor for Kram {
                 ));
             }
         }
+        let quality = match compression_format {
+            CompressionFormat::Astc4x4
+            | CompressionFormat::Astc5x5
+            | CompressionFormat::Astc6x6
+            | CompressionFormat::Astc8x8
+            | CompressionFormat::Astc4x4hdr
+            | CompressionFormat::Astc5x5hdr
+            | CompressionFormat::Astc6x6hdr
+            | CompressionFormat::Astc8x8hdr => options.quality.astc_preset().quality(),
+            _ => options.quality.percentage(),
+        };
+        command.args(["-quality", quality.to_string().as_str()]);
         match texture_type {
             TextureType::Srgb => {
                 command.arg("-srgb");
@@ -138,22 +194,113 @@ impl Compressor for Kram {
                     | CompressionFormat::Astc8x8 => {
                         command.args(["-swizzle", "rrrg"]);
                     }
+                    CompressionFormat::Bc5 | CompressionFormat::Etc2rg => {
+                        command.args(["-swizzle", "rg01"]);
+                    }
                     _ => {}
                 }
             }
+            TextureType::Hdr => {}
         }
-        match command.output() {
-            Ok(output) => {
-                if output.status.success() {
-                    Ok(())
-                } else {
-                    Err(format!(
-                        "Failed to execute command:\n{}",
-                        std::str::from_utf8(&output.stderr).unwrap()
-                    ))
-                }
-            }
-            Err(e) => Err(format!("Failed to execute command: {:?}", e)),
+        Ok(command)
+    }
+}
+
+impl Compressor for Kram {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let mut command = self.command(
+            working_dir,
+            dst_path,
+            texture_type,
+            compression_format,
+            container_format,
+            options,
+        )?;
+        command.args(["-input", src_path.to_str().unwrap()]);
+        run_command(&mut command).map(|command_line| vec![command_line])
+    }
+
+    fn compress_layers(
+        &self,
+        working_dir: &Path,
+        layers: &[Rgba32FImage],
+        shape: TextureShape,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let mut command = self.command(
+            working_dir,
+            dst_path,
+            texture_type,
+            compression_format,
+            container_format,
+            options,
+        )?;
+        // kram reads all layers from a single image, stacked vertically
+        let (width, height) = layers[0].dimensions();
+        let mut strip = Rgba32FImage::new(width, height * layers.len() as u32);
+        for (index, layer) in layers.iter().enumerate() {
+            imageops::replace(&mut strip, layer, 0, index as i64 * height as i64);
         }
+        let layers_dir = dst_path.with_extension("layers");
+        let strip_path = write_layers(working_dir, &layers_dir, &[strip], texture_type)?.remove(0);
+        command.args([
+            "-type",
+            match shape {
+                TextureShape::Cubemap => "cube",
+                TextureShape::Array => "2darray",
+                TextureShape::Volume => "3d",
+            },
+            "-input",
+            strip_path.to_str().unwrap(),
+        ]);
+        let result = run_command(&mut command).map(|command_line| vec![command_line]);
+        let _ = std::fs::remove_dir_all(working_dir.join(&layers_dir));
+        result
+    }
+
+    fn normal_map_swizzle(&self, compression_format: CompressionFormat) -> Option<&'static str> {
+        match compression_format {
+            CompressionFormat::Bc5 | CompressionFormat::Etc2rg => Some("rg01"),
+            CompressionFormat::Astc4x4
+            | CompressionFormat::Astc5x5
+            | CompressionFormat::Astc6x6
+            | CompressionFormat::Astc8x8 => Some("ra01"),
+            _ => None,
+        }
+    }
+
+    fn decompress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        _compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        let decoded_path = src_path.with_extension("decoded.ktx");
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        command.args([
+            "decode",
+            "-input",
+            src_path.to_str().unwrap(),
+            "-output",
+            decoded_path.to_str().unwrap(),
+        ]);
+        run_command(&mut command)?;
+        let image = read_ktx1_rgba8(&working_dir.join(&decoded_path));
+        let _ = std::fs::remove_file(working_dir.join(&decoded_path));
+        image
     }
 }
@@ -0,0 +1,1137 @@
+use std::path::{Path, PathBuf};
+
+use image::{Rgba32FImage, RgbaImage};
+
+const KTX1_IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x31, 0x31, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+const KTX1_HEADER_SIZE: usize = 64;
+const KTX1_ENDIANNESS: u32 = 0x04030201;
+
+const GL_FLOAT: u32 = 0x1406;
+const GL_RGBA: u32 = 0x1908;
+const GL_RGBA8: u32 = 0x8058;
+const GL_RGBA32F: u32 = 0x8814;
+const GL_SRGB8_ALPHA8: u32 = 0x8C43;
+
+fn read_u32(bytes: &[u8], offset: usize, swap: bool) -> Result<u32, String> {
+    let value = bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| String::from("Unexpected end of KTX file"))?;
+    Ok(if swap { value.swap_bytes() } else { value })
+}
+
+/// Reads mip level 0 of an uncompressed RGBA8 KTX1 file, such as those written by `kram decode`
+pub(crate) fn read_ktx1_rgba8(path: &Path) -> Result<RgbaImage, String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("Failed to read KTX file {} - {}", path.display(), e))?;
+    if !bytes.starts_with(&KTX1_IDENTIFIER) {
+        return Err(format!("{} is not a KTX file", path.display()));
+    }
+    let swap = read_u32(&bytes, 12, false)? != KTX1_ENDIANNESS;
+    let gl_internal_format = read_u32(&bytes, 28, swap)?;
+    if !matches!(gl_internal_format, GL_RGBA8 | GL_SRGB8_ALPHA8) {
+        return Err(format!(
+            "Unsupported KTX internal format {:#x} - must be RGBA8",
+            gl_internal_format
+        ));
+    }
+    let width = read_u32(&bytes, 36, swap)?;
+    let height = read_u32(&bytes, 40, swap)?.max(1);
+    let bytes_of_key_value_data = read_u32(&bytes, 60, swap)? as usize;
+    let image_size_offset = KTX1_HEADER_SIZE + bytes_of_key_value_data;
+    let image_size = read_u32(&bytes, image_size_offset, swap)? as usize;
+    let data = bytes
+        .get(image_size_offset + 4..image_size_offset + 4 + image_size)
+        .ok_or_else(|| String::from("Unexpected end of KTX file"))?;
+    data.get(..(width * height * 4) as usize)
+        .and_then(|data| RgbaImage::from_raw(width, height, data.to_vec()))
+        .ok_or_else(|| String::from("KTX image data is smaller than its dimensions"))
+}
+
+/// Writes an uncompressed RGBA32F KTX1 file with a single mip level, which kram can read as
+/// input without losing HDR range
+pub(crate) fn write_ktx1_rgba32f(path: &Path, image: &Rgba32FImage) -> Result<(), String> {
+    let data = image
+        .as_raw()
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut bytes = Vec::with_capacity(KTX1_HEADER_SIZE + 4 + data.len());
+    bytes.extend_from_slice(&KTX1_IDENTIFIER);
+    for value in [
+        KTX1_ENDIANNESS,
+        GL_FLOAT,
+        4,
+        GL_RGBA,
+        GL_RGBA32F,
+        GL_RGBA,
+        image.width(),
+        image.height(),
+        0,
+        0,
+        1,
+        1,
+        0,
+        data.len() as u32,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes.extend_from_slice(&data);
+    std::fs::write(path, bytes)
+        .map_err(|e| format!("Failed to write KTX file {} - {}", path.display(), e))
+}
+
+const KTX2_IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+const KTX2_HEADER_SIZE: usize = 80;
+const KTX2_LEVEL_INDEX_ENTRY_SIZE: usize = 24;
+const KTX2_SUPERCOMPRESSION_BASIS_LZ: u32 = 1;
+const KTX2_SUPERCOMPRESSION_ZSTD: u32 = 2;
+/// Level data is aligned to 16 bytes, a multiple of every texel block size and of 4
+const KTX2_LEVEL_ALIGNMENT: usize = 16;
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, String> {
+    Ok(read_u32(bytes, offset, false)? as u64 | (read_u32(bytes, offset + 4, false)? as u64) << 32)
+}
+
+fn ktx2_section(bytes: &[u8], offset: u64, length: u64) -> Result<&[u8], String> {
+    bytes
+        .get(offset as usize..(offset + length) as usize)
+        .ok_or_else(|| String::from("Unexpected end of KTX2 file"))
+}
+
+fn pad_to(bytes: &mut Vec<u8>, alignment: usize) {
+    bytes.resize(bytes.len().div_ceil(alignment) * alignment, 0);
+}
+
+/// The sections of a KTX2 file, borrowed from its bytes except for the key/value data
+struct Ktx2<'a> {
+    /// The header up to the data format descriptor's location
+    header: &'a [u8],
+    dfd: &'a [u8],
+    kvd: Vec<u8>,
+    sgd: &'a [u8],
+    /// The data and uncompressed length of each mip level, largest first
+    levels: Vec<(&'a [u8], u64)>,
+}
+
+impl<'a> Ktx2<'a> {
+    fn read(bytes: &'a [u8], path: &Path) -> Result<Self, String> {
+        if !bytes.starts_with(&KTX2_IDENTIFIER) || bytes.len() < KTX2_HEADER_SIZE {
+            return Err(format!("{} is not a KTX2 file", path.display()));
+        }
+        let level_count = read_u32(bytes, 40, false)?.max(1) as usize;
+        let levels = (0..level_count)
+            .map(|level| {
+                let entry = KTX2_HEADER_SIZE + level * KTX2_LEVEL_INDEX_ENTRY_SIZE;
+                Ok((
+                    ktx2_section(bytes, read_u64(bytes, entry)?, read_u64(bytes, entry + 8)?)?,
+                    read_u64(bytes, entry + 16)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Ktx2 {
+            header: &bytes[..48],
+            dfd: ktx2_section(
+                bytes,
+                read_u32(bytes, 48, false)? as u64,
+                read_u32(bytes, 52, false)? as u64,
+            )?,
+            kvd: ktx2_section(
+                bytes,
+                read_u32(bytes, 56, false)? as u64,
+                read_u32(bytes, 60, false)? as u64,
+            )?
+            .to_vec(),
+            sgd: ktx2_section(bytes, read_u64(bytes, 64)?, read_u64(bytes, 72)?)?,
+            levels,
+        })
+    }
+
+    fn write(&self, path: &Path) -> Result<(), String> {
+        let index_size = self.levels.len() * KTX2_LEVEL_INDEX_ENTRY_SIZE;
+        let dfd_offset = KTX2_HEADER_SIZE + index_size;
+        let kvd_offset = dfd_offset + self.dfd.len();
+        let sgd_offset = (kvd_offset + self.kvd.len()).div_ceil(8) * 8;
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&self.header[..40]);
+        bytes.extend_from_slice(&(self.levels.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.header[44..48]);
+        for value in [
+            dfd_offset as u32,
+            self.dfd.len() as u32,
+            if self.kvd.is_empty() {
+                0
+            } else {
+                kvd_offset as u32
+            },
+            self.kvd.len() as u32,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [
+            if self.sgd.is_empty() {
+                0
+            } else {
+                sgd_offset as u64
+            },
+            self.sgd.len() as u64,
+        ] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.resize(dfd_offset, 0);
+        bytes.extend_from_slice(self.dfd);
+        bytes.extend_from_slice(&self.kvd);
+        if !self.sgd.is_empty() {
+            pad_to(&mut bytes, 8);
+            bytes.extend_from_slice(self.sgd);
+        }
+        // Level data is stored smallest first
+        let mut level_index = vec![(0, 0, 0); self.levels.len()];
+        for (level, (data, uncompressed_length)) in self.levels.iter().enumerate().rev() {
+            pad_to(&mut bytes, KTX2_LEVEL_ALIGNMENT);
+            level_index[level] = (bytes.len() as u64, data.len() as u64, *uncompressed_length);
+            bytes.extend_from_slice(data);
+        }
+        for (level, (offset, length, uncompressed_length)) in level_index.into_iter().enumerate() {
+            let entry = KTX2_HEADER_SIZE + level * KTX2_LEVEL_INDEX_ENTRY_SIZE;
+            for (i, value) in [offset, length, uncompressed_length]
+                .into_iter()
+                .enumerate()
+            {
+                bytes[entry + i * 8..entry + i * 8 + 8].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+        std::fs::write(path, bytes)
+            .map_err(|e| format!("Failed to write KTX2 file {} - {}", path.display(), e))
+    }
+}
+
+/// Combines KTX2 files holding one mip level each, largest first, into a single KTX2 file with
+/// all of them as its mip levels. The format, data format descriptor and key/value data are
+/// taken from the first file.
+pub(crate) fn merge_ktx2_levels(src_paths: &[PathBuf], dst_path: &Path) -> Result<(), String> {
+    let files = src_paths
+        .iter()
+        .map(|path| {
+            std::fs::read(path)
+                .map_err(|e| format!("Failed to read KTX2 file {} - {}", path.display(), e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let files = files
+        .iter()
+        .zip(src_paths)
+        .map(|(bytes, path)| Ktx2::read(bytes, path))
+        .collect::<Result<Vec<_>, _>>()?;
+    let first = files
+        .first()
+        .ok_or_else(|| String::from("No mip levels to merge"))?;
+    if read_u32(first.header, 44, false)? == KTX2_SUPERCOMPRESSION_BASIS_LZ {
+        return Err(String::from(
+            "Mip levels compressed with BasisLZ (ETC1S) cannot be merged",
+        ));
+    }
+    let width = read_u32(first.header, 20, false)?;
+    let height = read_u32(first.header, 24, false)?;
+    let mut levels = Vec::with_capacity(files.len());
+    for (level, (file, path)) in files.iter().zip(src_paths).enumerate() {
+        // vkFormat, typeSize, pixelDepth, layerCount, faceCount and supercompressionScheme
+        for offset in [12, 16, 28, 32, 36, 44] {
+            if read_u32(file.header, offset, false)? != read_u32(first.header, offset, false)? {
+                return Err(format!(
+                    "{} does not have the same format as {}",
+                    path.display(),
+                    src_paths[0].display()
+                ));
+            }
+        }
+        let expected = ((width >> level).max(1), (height >> level).max(1));
+        let actual = (
+            read_u32(file.header, 20, false)?,
+            read_u32(file.header, 24, false)?,
+        );
+        if actual != expected {
+            return Err(format!(
+                "{} is {}x{} but mip level {} must be {}x{}",
+                path.display(),
+                actual.0,
+                actual.1,
+                level,
+                expected.0,
+                expected.1
+            ));
+        }
+        levels.push(file.levels[0]);
+    }
+    Ktx2 {
+        header: first.header,
+        dfd: first.dfd,
+        kvd: first.kvd.clone(),
+        sgd: &[],
+        levels,
+    }
+    .write(dst_path)
+}
+
+/// Sets a key/value pair in the metadata of a KTX2 file, replacing any value the key already
+/// has. String values such as `KTXswizzle` are stored NUL-terminated.
+pub(crate) fn set_ktx2_value(path: &Path, key: &str, value: &str) -> Result<(), String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("Failed to read KTX2 file {} - {}", path.display(), e))?;
+    let mut ktx2 = Ktx2::read(&bytes, path)?;
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= ktx2.kvd.len() {
+        let length = read_u32(&ktx2.kvd, offset, false)? as usize;
+        let entry = ktx2
+            .kvd
+            .get(offset + 4..offset + 4 + length)
+            .ok_or_else(|| String::from("Unexpected end of KTX2 key/value data"))?;
+        let key_length = entry.iter().position(|&b| b == 0).unwrap_or(entry.len());
+        entries.push((
+            &entry[..key_length],
+            &entry[(key_length + 1).min(entry.len())..],
+        ));
+        offset = (offset + 4 + length).div_ceil(4) * 4;
+    }
+    let value = [value.as_bytes(), &[0]].concat();
+    entries.retain(|(k, _)| *k != key.as_bytes());
+    entries.push((key.as_bytes(), &value));
+    // Keys must be sorted by their UTF-8 bytes
+    entries.sort_by_key(|(key, _)| *key);
+    let mut kvd = Vec::new();
+    for (key, value) in entries {
+        kvd.extend_from_slice(&((key.len() + 1 + value.len()) as u32).to_le_bytes());
+        kvd.extend_from_slice(key);
+        kvd.push(0);
+        kvd.extend_from_slice(value);
+        pad_to(&mut kvd, 4);
+    }
+    ktx2.kvd = kvd;
+    ktx2.write(path)
+}
+
+/// Writes a KTX2 file for a 2D texture without supercompression, with the data of each mip level
+/// given largest first
+pub(crate) fn write_ktx2(
+    path: &Path,
+    vk_format: u32,
+    (width, height): (u32, u32),
+    dfd: &[u8],
+    levels: &[Vec<u8>],
+) -> Result<(), String> {
+    let mut header = KTX2_IDENTIFIER.to_vec();
+    // vkFormat, typeSize, pixelWidth, pixelHeight, pixelDepth, layerCount, faceCount, levelCount
+    // and supercompressionScheme
+    for value in [vk_format, 1, width, height, 0, 0, 1, levels.len() as u32, 0] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+    Ktx2 {
+        header: &header,
+        dfd,
+        kvd: Vec::new(),
+        sgd: &[],
+        levels: levels
+            .iter()
+            .map(|data| (data.as_slice(), data.len() as u64))
+            .collect(),
+    }
+    .write(path)
+}
+
+/// The first mip level of a KTX2 file
+pub(crate) struct Ktx2Level {
+    pub(crate) vk_format: u32,
+    pub(crate) dimensions: (u32, u32),
+    pub(crate) data: Vec<u8>,
+}
+
+/// Reads mip level 0 of a KTX2 file without supercompression
+pub(crate) fn read_ktx2_level0(path: &Path) -> Result<Ktx2Level, String> {
+    let mut texture = read_ktx2(path)?;
+    Ok(Ktx2Level {
+        vk_format: texture.vk_format,
+        dimensions: (texture.dimensions.0, texture.dimensions.1),
+        data: texture.levels.swap_remove(0),
+    })
+}
+
+/// The contents of a KTX2 file without supercompression
+pub(crate) struct Ktx2Texture {
+    pub(crate) vk_format: u32,
+    /// Width, height and depth, which is 0 for 1D and 2D textures
+    pub(crate) dimensions: (u32, u32, u32),
+    /// Number of array layers, which is 0 for textures that are not arrays
+    pub(crate) layer_count: u32,
+    pub(crate) face_count: u32,
+    /// The data of each mip level, largest first
+    pub(crate) levels: Vec<Vec<u8>>,
+}
+
+/// Reads a KTX2 file without supercompression
+pub(crate) fn read_ktx2(path: &Path) -> Result<Ktx2Texture, String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("Failed to read KTX2 file {} - {}", path.display(), e))?;
+    let ktx2 = Ktx2::read(&bytes, path)?;
+    if read_u32(ktx2.header, 44, false)? != 0 {
+        return Err(format!("{} is supercompressed", path.display()));
+    }
+    Ok(Ktx2Texture {
+        vk_format: read_u32(ktx2.header, 12, false)?,
+        dimensions: (
+            read_u32(ktx2.header, 20, false)?,
+            read_u32(ktx2.header, 24, false)?,
+            read_u32(ktx2.header, 28, false)?,
+        ),
+        layer_count: read_u32(ktx2.header, 32, false)?,
+        face_count: read_u32(ktx2.header, 36, false)?,
+        levels: ktx2.levels.iter().map(|(data, _)| data.to_vec()).collect(),
+    })
+}
+
+const KHR_DF_MODEL_BC1A: u32 = 128;
+const KHR_DF_MODEL_BC3: u32 = 130;
+const KHR_DF_MODEL_BC7: u32 = 134;
+const KHR_DF_MODEL_ETC2: u32 = 161;
+const KHR_DF_MODEL_ASTC: u32 = 162;
+const KHR_DF_PRIMARIES_BT709: u32 = 1;
+const KHR_DF_TRANSFER_LINEAR: u32 = 1;
+const KHR_DF_TRANSFER_SRGB: u32 = 2;
+/// KHR_DF_SAMPLE_DATATYPE_FLOAT and KHR_DF_SAMPLE_DATATYPE_SIGNED, in the channel type byte
+const KHR_DF_SAMPLE_DATATYPE_SIGNED_FLOAT: u32 = 0xC0;
+const KHR_DF_CHANNEL_COLOR: u32 = 0;
+const KHR_DF_CHANNEL_BC1A_ALPHAPRESENT: u32 = 1;
+const KHR_DF_CHANNEL_ETC2_COLOR: u32 = 2;
+const KHR_DF_CHANNEL_ALPHA: u32 = 15;
+
+/// A sample of a compressed block in a data format descriptor
+struct DfdSample {
+    bit_offset: u32,
+    bit_length: u32,
+    /// Channel id and KHR_DF_SAMPLE_DATATYPE flags
+    channel_type: u32,
+    lower: u32,
+    upper: u32,
+}
+
+/// Data format descriptor for a block compressed format with a single plane
+fn block_dfd(
+    model: u32,
+    (block_width, block_height): (u32, u32),
+    bytes_per_block: u32,
+    srgb: bool,
+    samples: &[DfdSample],
+) -> Vec<u8> {
+    let transfer = if srgb {
+        KHR_DF_TRANSFER_SRGB
+    } else {
+        KHR_DF_TRANSFER_LINEAR
+    };
+    let block_size = 24 + 16 * samples.len() as u32;
+    let mut words = vec![
+        // dfdTotalSize, then the basic descriptor block
+        4 + block_size,
+        0,
+        2 | block_size << 16,
+        model | KHR_DF_PRIMARIES_BT709 << 8 | transfer << 16,
+        (block_width - 1) | (block_height - 1) << 8,
+        bytes_per_block,
+        0,
+    ];
+    for sample in samples {
+        words.extend_from_slice(&[
+            sample.bit_offset | (sample.bit_length - 1) << 16 | sample.channel_type << 24,
+            0,
+            sample.lower,
+            sample.upper,
+        ]);
+    }
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
+
+/// Data format descriptor for ASTC with the given block size, as an LDR format in sRGB or linear
+/// space, or as an HDR format
+pub(crate) fn astc_dfd(block: (u32, u32), srgb: bool, hdr: bool) -> Vec<u8> {
+    let (channel_type, lower, upper) = if hdr {
+        // -1.0 and +infinity as IEEE 754 floats
+        (KHR_DF_SAMPLE_DATATYPE_SIGNED_FLOAT, 0xBF800000, 0x7F800000)
+    } else {
+        (0, 0, u32::MAX)
+    };
+    // The whole 128-bit block is one sample of ASTC data
+    block_dfd(
+        KHR_DF_MODEL_ASTC,
+        block,
+        16,
+        srgb,
+        &[DfdSample {
+            bit_offset: 0,
+            bit_length: 128,
+            channel_type,
+            lower,
+            upper,
+        }],
+    )
+}
+
+/// A compressed KTX1 internal format and the KTX2 format it corresponds to
+struct Ktx1BlockFormat {
+    gl_internal_formats: &'static [u32],
+    /// The UNORM vkFormat, which its sRGB counterpart directly follows
+    vk_format: u32,
+    model: u32,
+    bytes_per_block: u32,
+    /// Channels of the samples, which split the block evenly
+    channels: &'static [u32],
+}
+
+/// The 4x4 block formats that klafsa converts from KTX1 to KTX2
+const KTX1_BLOCK_FORMATS: [Ktx1BlockFormat; 6] = [
+    Ktx1BlockFormat {
+        // GL_COMPRESSED_RGB_S3TC_DXT1_EXT and GL_COMPRESSED_SRGB_S3TC_DXT1_EXT
+        gl_internal_formats: &[0x83F0, 0x8C4C],
+        vk_format: 131,
+        model: KHR_DF_MODEL_BC1A,
+        bytes_per_block: 8,
+        channels: &[KHR_DF_CHANNEL_COLOR],
+    },
+    Ktx1BlockFormat {
+        // GL_COMPRESSED_RGBA_S3TC_DXT1_EXT and GL_COMPRESSED_SRGB_ALPHA_S3TC_DXT1_EXT
+        gl_internal_formats: &[0x83F1, 0x8C4D],
+        vk_format: 133,
+        model: KHR_DF_MODEL_BC1A,
+        bytes_per_block: 8,
+        channels: &[KHR_DF_CHANNEL_BC1A_ALPHAPRESENT],
+    },
+    Ktx1BlockFormat {
+        // GL_COMPRESSED_RGBA_S3TC_DXT5_EXT and GL_COMPRESSED_SRGB_ALPHA_S3TC_DXT5_EXT
+        gl_internal_formats: &[0x83F3, 0x8C4F],
+        vk_format: 137,
+        model: KHR_DF_MODEL_BC3,
+        bytes_per_block: 16,
+        channels: &[KHR_DF_CHANNEL_ALPHA, KHR_DF_CHANNEL_COLOR],
+    },
+    Ktx1BlockFormat {
+        // GL_COMPRESSED_RGBA_BPTC_UNORM and GL_COMPRESSED_SRGB_ALPHA_BPTC_UNORM
+        gl_internal_formats: &[0x8E8C, 0x8E8D],
+        vk_format: 145,
+        model: KHR_DF_MODEL_BC7,
+        bytes_per_block: 16,
+        channels: &[KHR_DF_CHANNEL_COLOR],
+    },
+    Ktx1BlockFormat {
+        // GL_ETC1_RGB8_OES, whose blocks are valid ETC2, GL_COMPRESSED_RGB8_ETC2 and
+        // GL_COMPRESSED_SRGB8_ETC2
+        gl_internal_formats: &[0x8D64, 0x9274, 0x9275],
+        vk_format: 147,
+        model: KHR_DF_MODEL_ETC2,
+        bytes_per_block: 8,
+        channels: &[KHR_DF_CHANNEL_ETC2_COLOR],
+    },
+    Ktx1BlockFormat {
+        // GL_COMPRESSED_RGBA8_ETC2_EAC and GL_COMPRESSED_SRGB8_ALPHA8_ETC2_EAC
+        gl_internal_formats: &[0x9278, 0x9279],
+        vk_format: 151,
+        model: KHR_DF_MODEL_ETC2,
+        bytes_per_block: 16,
+        channels: &[KHR_DF_CHANNEL_ALPHA, KHR_DF_CHANNEL_ETC2_COLOR],
+    },
+];
+
+/// Converts a 2D KTX1 file in one of the BC1, BC3, BC7 or ETC2 formats into a KTX2 file, keeping
+/// at most `max_levels` of its mip levels. `srgb` selects the sRGB variant of the format.
+pub(crate) fn convert_ktx1_to_ktx2(
+    src_path: &Path,
+    dst_path: &Path,
+    srgb: bool,
+    max_levels: Option<u32>,
+) -> Result<(), String> {
+    let bytes = std::fs::read(src_path)
+        .map_err(|e| format!("Failed to read KTX file {} - {}", src_path.display(), e))?;
+    if !bytes.starts_with(&KTX1_IDENTIFIER) {
+        return Err(format!("{} is not a KTX file", src_path.display()));
+    }
+    let swap = read_u32(&bytes, 12, false)? != KTX1_ENDIANNESS;
+    let gl_internal_format = read_u32(&bytes, 28, swap)?;
+    let format = KTX1_BLOCK_FORMATS
+        .iter()
+        .find(|format| format.gl_internal_formats.contains(&gl_internal_format))
+        .ok_or_else(|| {
+            format!(
+                "Unsupported KTX internal format {:#x} - must be BC1, BC3, BC7 or ETC2",
+                gl_internal_format
+            )
+        })?;
+    let width = read_u32(&bytes, 36, swap)?;
+    let height = read_u32(&bytes, 40, swap)?.max(1);
+    let level_count = read_u32(&bytes, 56, swap)?
+        .max(1)
+        .min(max_levels.unwrap_or(u32::MAX));
+    let mut offset = KTX1_HEADER_SIZE + read_u32(&bytes, 60, swap)? as usize;
+    let mut levels = Vec::new();
+    for _ in 0..level_count {
+        let image_size = read_u32(&bytes, offset, swap)? as usize;
+        let data = bytes
+            .get(offset + 4..offset + 4 + image_size)
+            .ok_or_else(|| String::from("Unexpected end of KTX file"))?;
+        levels.push(data.to_vec());
+        // Each level is padded to 4 bytes
+        offset += 4 + image_size.div_ceil(4) * 4;
+    }
+    let sample_bits = format.bytes_per_block * 8 / format.channels.len() as u32;
+    let samples = format
+        .channels
+        .iter()
+        .enumerate()
+        .map(|(index, &channel_type)| DfdSample {
+            bit_offset: index as u32 * sample_bits,
+            bit_length: sample_bits,
+            channel_type,
+            lower: 0,
+            upper: u32::MAX,
+        })
+        .collect::<Vec<_>>();
+    write_ktx2(
+        dst_path,
+        format.vk_format + srgb as u32,
+        (width, height),
+        &block_dfd(format.model, (4, 4), format.bytes_per_block, srgb, &samples),
+        &levels,
+    )
+}
+
+/// vkFormats of BC codecs that have an sRGB counterpart, which directly follows them
+const VK_FORMATS_WITH_SRGB: [u32; 5] = [
+    131, // VK_FORMAT_BC1_RGB_UNORM_BLOCK
+    133, // VK_FORMAT_BC1_RGBA_UNORM_BLOCK
+    135, // VK_FORMAT_BC2_UNORM_BLOCK
+    137, // VK_FORMAT_BC3_UNORM_BLOCK
+    145, // VK_FORMAT_BC7_UNORM_BLOCK
+];
+
+/// Marks a KTX2 file written with a UNORM format as sRGB, for tools without an sRGB option.
+/// Formats without an sRGB counterpart are left as they are.
+pub(crate) fn set_ktx2_srgb(path: &Path) -> Result<(), String> {
+    let mut bytes = std::fs::read(path)
+        .map_err(|e| format!("Failed to read KTX2 file {} - {}", path.display(), e))?;
+    if !bytes.starts_with(&KTX2_IDENTIFIER) {
+        return Err(format!("{} is not a KTX2 file", path.display()));
+    }
+    let vk_format = read_u32(&bytes, 12, false)?;
+    if !VK_FORMATS_WITH_SRGB.contains(&vk_format) {
+        return Ok(());
+    }
+    // The transfer function is the third byte of the DFD's third word
+    let transfer_offset = read_u32(&bytes, 48, false)? as usize + 14;
+    if transfer_offset >= bytes.len() {
+        return Err(String::from("Unexpected end of KTX2 file"));
+    }
+    bytes[12..16].copy_from_slice(&(vk_format + 1).to_le_bytes());
+    bytes[transfer_offset] = KHR_DF_TRANSFER_SRGB as u8;
+    std::fs::write(path, bytes)
+        .map_err(|e| format!("Failed to write KTX2 file {} - {}", path.display(), e))
+}
+
+pub(crate) const KHR_DF_MODEL_ETC1S: u8 = 163;
+pub(crate) const KHR_DF_MODEL_UASTC: u8 = 166;
+/// KHR_DF_CHANNEL_UASTC_RGBA and KHR_DF_CHANNEL_UASTC_RRRG, the UASTC channels with alpha
+const KHR_DF_CHANNELS_UASTC_ALPHA: [u8; 2] = [3, 5];
+/// Size of the BasisLZ global data header, and of each of its image descriptions
+const KTX2_ETC1S_DESC_SIZE: usize = 20;
+
+/// Size of basis_file_header, with every field packed
+const BASIS_HEADER_SIZE: usize = 77;
+/// The transcoder only reads files of this version
+const BASIS_VERSION: u32 = 0x13;
+const BASIS_TEX_FORMAT_ETC1S: u32 = 0;
+const BASIS_TEX_FORMAT_UASTC: u32 = 1;
+const BASIS_HEADER_FLAG_ETC1S: u32 = 1;
+const BASIS_HEADER_FLAG_HAS_ALPHA_SLICES: u32 = 4;
+const BASIS_SLICE_FLAG_HAS_ALPHA: u32 = 1;
+const BASIS_TEX_TYPE_2D: u32 = 0;
+const BASIS_TEX_TYPE_2D_ARRAY: u32 = 1;
+const BASIS_TEX_TYPE_CUBEMAP_ARRAY: u32 = 2;
+
+/// The compressed data of one image and mip level in a .basis file, or of its alpha for ETC1S
+struct BasisSlice {
+    image: u32,
+    level: u32,
+    alpha: bool,
+    dimensions: (u32, u32),
+    data: Vec<u8>,
+}
+
+/// Appends the lowest `size` bytes of a value, as .basis files pack their fields
+fn put_packed(bytes: &mut Vec<u8>, value: u32, size: usize) {
+    bytes.extend_from_slice(&value.to_le_bytes()[..size]);
+}
+
+/// The CRC-16 the .basis format uses for its header and data
+fn basis_crc16(bytes: &[u8]) -> u16 {
+    let mut crc = !0u16;
+    for &byte in bytes {
+        let q = byte as u16 ^ (crc >> 8);
+        let k = (q >> 4) ^ q;
+        crc = (crc << 8) ^ k ^ (k << 5) ^ (k << 12);
+    }
+    !crc
+}
+
+/// Rewraps a KTX2 file holding ETC1S or UASTC into the .basis format, which is the only
+/// container the Basis transcoder library reads. Zstandard supercompression is undone.
+pub(crate) fn convert_ktx2_to_basis(path: &Path) -> Result<Vec<u8>, String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("Failed to read KTX2 file {} - {}", path.display(), e))?;
+    let ktx2 = Ktx2::read(&bytes, path)?;
+    let width = read_u32(ktx2.header, 20, false)?;
+    let height = read_u32(ktx2.header, 24, false)?.max(1);
+    if read_u32(ktx2.header, 28, false)? > 0 {
+        return Err(format!(
+            "{} is a 3D texture, which .basis files cannot hold",
+            path.display()
+        ));
+    }
+    let layer_count = read_u32(ktx2.header, 32, false)?;
+    let face_count = read_u32(ktx2.header, 36, false)?.max(1);
+    let image_count = layer_count.max(1) * face_count;
+    let level_dimensions = |level: usize| ((width >> level).max(1), (height >> level).max(1));
+    let sgd_u32 = |offset: usize| read_u32(ktx2.sgd, offset, false);
+
+    let supercompression = read_u32(ktx2.header, 44, false)?;
+    let etc1s = supercompression == KTX2_SUPERCOMPRESSION_BASIS_LZ;
+    let mut slices = Vec::new();
+    // The endpoint and selector counts, and the endpoint, selector, Huffman table and extended
+    // data lengths of ETC1S files, whose codebooks are shared by every slice
+    let mut codebook_counts = [0; 2];
+    let mut codebook_lengths = [0; 4];
+    let mut codebooks: &[u8] = &[];
+    if etc1s {
+        codebook_counts = [sgd_u32(0)? & 0xFFFF, sgd_u32(0)? >> 16];
+        for (i, length) in codebook_lengths.iter_mut().enumerate() {
+            *length = sgd_u32(4 + i * 4)?;
+        }
+        let descs_length = KTX2_ETC1S_DESC_SIZE * ktx2.levels.len() * image_count as usize;
+        codebooks = ktx2
+            .sgd
+            .get(KTX2_ETC1S_DESC_SIZE + descs_length..)
+            .ok_or_else(|| String::from("Unexpected end of KTX2 file"))?;
+        for image in 0..image_count {
+            for (level, (data, _)) in ktx2.levels.iter().enumerate() {
+                // Image descriptions are ordered by level, then by image
+                let desc =
+                    KTX2_ETC1S_DESC_SIZE * (1 + level * image_count as usize + image as usize);
+                for (alpha, offset) in [(false, desc + 4), (true, desc + 12)] {
+                    let length = sgd_u32(offset + 4)? as u64;
+                    if alpha && length == 0 {
+                        continue;
+                    }
+                    slices.push(BasisSlice {
+                        image,
+                        level: level as u32,
+                        alpha,
+                        dimensions: level_dimensions(level),
+                        data: ktx2_section(data, sgd_u32(offset)? as u64, length)?.to_vec(),
+                    });
+                }
+            }
+        }
+    } else {
+        if ktx2.dfd.get(12) != Some(&KHR_DF_MODEL_UASTC) {
+            return Err(format!(
+                "{} is neither ETC1S nor UASTC, which are the only formats the Basis \
+                 transcoder reads",
+                path.display()
+            ));
+        }
+        // The channel id of the first sample
+        let alpha = ktx2
+            .dfd
+            .get(31)
+            .is_some_and(|channel| KHR_DF_CHANNELS_UASTC_ALPHA.contains(&(channel & 0xF)));
+        let levels = ktx2
+            .levels
+            .iter()
+            .map(|&(data, uncompressed_length)| match supercompression {
+                0 => Ok(data.to_vec()),
+                KTX2_SUPERCOMPRESSION_ZSTD => {
+                    let mut level = vec![0; uncompressed_length as usize];
+                    ruzstd::decoding::FrameDecoder::new()
+                        .decode_all(data, &mut level)
+                        .map_err(|e| format!("Failed to decompress {} - {}", path.display(), e))?;
+                    Ok(level)
+                }
+                _ => Err(format!(
+                    "Unsupported supercompression scheme {} in {}",
+                    supercompression,
+                    path.display()
+                )),
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        for image in 0..image_count {
+            for (level, data) in levels.iter().enumerate() {
+                let size = data.len() / image_count as usize;
+                slices.push(BasisSlice {
+                    image,
+                    level: level as u32,
+                    alpha,
+                    dimensions: level_dimensions(level),
+                    data: data[image as usize * size..(image as usize + 1) * size].to_vec(),
+                });
+            }
+        }
+    }
+
+    let mut flags = if etc1s { BASIS_HEADER_FLAG_ETC1S } else { 0 };
+    if slices.iter().any(|slice| slice.alpha) {
+        flags |= BASIS_HEADER_FLAG_HAS_ALPHA_SLICES;
+    }
+    let tex_type = if face_count == 6 {
+        BASIS_TEX_TYPE_CUBEMAP_ARRAY
+    } else if layer_count > 0 {
+        BASIS_TEX_TYPE_2D_ARRAY
+    } else {
+        BASIS_TEX_TYPE_2D
+    };
+    // The slice descriptions follow the header, then the codebooks and the slice data
+    let codebooks_offset = BASIS_HEADER_SIZE + slices.len() * 23;
+    let mut codebook_offsets = [0; 4];
+    let mut offset = codebooks_offset as u32;
+    for (codebook_offset, length) in codebook_offsets.iter_mut().zip(codebook_lengths) {
+        *codebook_offset = if length > 0 { offset } else { 0 };
+        offset += length;
+    }
+    let mut basis = vec![0; BASIS_HEADER_SIZE];
+    let mut data_offset = codebooks_offset + codebooks.len();
+    for slice in &slices {
+        let (width, height) = slice.dimensions;
+        for (value, size) in [
+            (slice.image, 3),
+            (slice.level, 1),
+            (
+                if slice.alpha {
+                    BASIS_SLICE_FLAG_HAS_ALPHA
+                } else {
+                    0
+                },
+                1,
+            ),
+            (width, 2),
+            (height, 2),
+            (width.div_ceil(4), 2),
+            (height.div_ceil(4), 2),
+            (data_offset as u32, 4),
+            (slice.data.len() as u32, 4),
+            (basis_crc16(&slice.data) as u32, 2),
+        ] {
+            put_packed(&mut basis, value, size);
+        }
+        data_offset += slice.data.len();
+    }
+    basis.extend_from_slice(codebooks);
+    for slice in &slices {
+        basis.extend_from_slice(&slice.data);
+    }
+
+    let mut header = Vec::with_capacity(BASIS_HEADER_SIZE);
+    for (value, size) in [
+        // 'B' << 8 | 's'
+        (0x4273, 2),
+        (BASIS_VERSION, 2),
+        (BASIS_HEADER_SIZE as u32, 2),
+        // The header's CRC, set below
+        (0, 2),
+        ((basis.len() - BASIS_HEADER_SIZE) as u32, 4),
+        (basis_crc16(&basis[BASIS_HEADER_SIZE..]) as u32, 2),
+        (slices.len() as u32, 3),
+        (image_count, 3),
+        (
+            if etc1s {
+                BASIS_TEX_FORMAT_ETC1S
+            } else {
+                BASIS_TEX_FORMAT_UASTC
+            },
+            1,
+        ),
+        (flags, 2),
+        (tex_type, 1),
+        // Microseconds per frame, reserved and user data
+        (0, 3),
+        (0, 4),
+        (0, 4),
+        (0, 4),
+        (codebook_counts[0], 2),
+        (codebook_offsets[0], 4),
+        (codebook_lengths[0], 3),
+        (codebook_counts[1], 2),
+        (codebook_offsets[1], 4),
+        (codebook_lengths[1], 3),
+        (codebook_offsets[2], 4),
+        (codebook_lengths[2], 4),
+        (BASIS_HEADER_SIZE as u32, 4),
+        (codebook_offsets[3], 4),
+        (codebook_lengths[3], 4),
+    ] {
+        put_packed(&mut header, value, size);
+    }
+    let header_crc = basis_crc16(&header[8..]);
+    header[6..8].copy_from_slice(&header_crc.to_le_bytes());
+    basis[..BASIS_HEADER_SIZE].copy_from_slice(&header);
+    Ok(basis)
+}
+
+#[cfg(test)]
+mod tests {
+    use basis_universal::{
+        BasisTextureFormat, CompressorParams, TranscodeParameters, Transcoder,
+        TranscoderTextureFormat,
+    };
+
+    use super::*;
+    use crate::test_dir;
+
+    /// VK_FORMAT_ASTC_4x4_UNORM_BLOCK
+    const VK_FORMAT_ASTC_4X4: u32 = 157;
+
+    fn levels(sizes: &[usize]) -> Vec<Vec<u8>> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(level, &size)| (0..size).map(|i| (level * 64 + i) as u8).collect())
+            .collect()
+    }
+
+    #[test]
+    fn write_and_read_ktx2() {
+        let dir = test_dir("write_and_read_ktx2");
+        let path = dir.join("texture.ktx2");
+        let data = levels(&[64, 16, 16]);
+        write_ktx2(
+            &path,
+            VK_FORMAT_ASTC_4X4,
+            (8, 8),
+            &astc_dfd((4, 4), false, false),
+            &data,
+        )
+        .unwrap();
+
+        let texture = read_ktx2(&path).unwrap();
+        assert_eq!(texture.vk_format, VK_FORMAT_ASTC_4X4);
+        assert_eq!(texture.dimensions, (8, 8, 0));
+        assert_eq!((texture.layer_count, texture.face_count), (0, 1));
+        assert_eq!(texture.levels, data);
+
+        // Reading and writing the sections again gives the same file
+        let bytes = std::fs::read(&path).unwrap();
+        let copy_path = dir.join("copy.ktx2");
+        Ktx2::read(&bytes, &path)
+            .unwrap()
+            .write(&copy_path)
+            .unwrap();
+        assert_eq!(std::fs::read(&copy_path).unwrap(), bytes);
+    }
+
+    #[test]
+    fn set_ktx2_value_replaces_key() {
+        let dir = test_dir("set_ktx2_value_replaces_key");
+        let path = dir.join("texture.ktx2");
+        let data = levels(&[16]);
+        write_ktx2(
+            &path,
+            VK_FORMAT_ASTC_4X4,
+            (4, 4),
+            &astc_dfd((4, 4), false, false),
+            &data,
+        )
+        .unwrap();
+        set_ktx2_value(&path, "KTXswizzle", "rg01").unwrap();
+        set_ktx2_value(&path, "KTXswizzle", "ra01").unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        let ktx2 = Ktx2::read(&bytes, &path).unwrap();
+        assert_eq!(ktx2.kvd, b"\x10\0\0\0KTXswizzle\0ra01\0");
+        assert_eq!(read_ktx2(&path).unwrap().levels, data);
+    }
+
+    /// Encodes an 8x8 gradient to a .basis file
+    fn encode_basis(format: BasisTextureFormat, channels: u8) -> Vec<u8> {
+        let pixels = (0..8 * 8 * channels as usize)
+            .map(|i| (i * 7) as u8)
+            .collect::<Vec<_>>();
+        let mut params = CompressorParams::new();
+        params.set_basis_format(format);
+        params.source_image_mut(0).init(&pixels, 8, 8, channels);
+        let mut compressor = basis_universal::Compressor::default();
+        unsafe {
+            compressor.init(&params);
+            compressor.process().unwrap();
+            compressor.basis_file().to_vec()
+        }
+    }
+
+    /// Reads a little-endian field of a .basis header
+    fn basis_field(basis: &[u8], offset: usize, size: usize) -> u32 {
+        basis[offset..offset + size]
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u32)
+    }
+
+    /// The data of the first slice of a .basis file
+    fn first_slice(basis: &[u8]) -> &[u8] {
+        let offset = basis_field(basis, BASIS_HEADER_SIZE + 13, 4) as usize;
+        let length = basis_field(basis, BASIS_HEADER_SIZE + 17, 4) as usize;
+        &basis[offset..offset + length]
+    }
+
+    /// Checks that a .basis file rewrapped from KTX2 is valid and decodes as the original
+    fn assert_transcodes_as(converted: &[u8], basis: &[u8]) {
+        let mut transcoder = Transcoder::new();
+        assert!(transcoder.validate_file_checksums(converted, true));
+        let mut decode = |data: &[u8]| {
+            transcoder.prepare_transcoding(data).unwrap();
+            let rgba = transcoder
+                .transcode_image_level(
+                    data,
+                    TranscoderTextureFormat::RGBA32,
+                    TranscodeParameters::default(),
+                )
+                .unwrap();
+            transcoder.end_transcoding();
+            rgba
+        };
+        assert_eq!(decode(converted), decode(basis));
+    }
+
+    #[test]
+    fn convert_uastc_ktx2_to_basis() {
+        let basis = encode_basis(BasisTextureFormat::UASTC4x4, 4);
+        let dir = test_dir("convert_uastc_ktx2_to_basis");
+        let path = dir.join("texture.ktx2");
+        let mut dfd = vec![0; 44];
+        dfd[12] = KHR_DF_MODEL_UASTC;
+        dfd[31] = KHR_DF_CHANNELS_UASTC_ALPHA[0];
+        write_ktx2(&path, 0, (8, 8), &dfd, &[first_slice(&basis).to_vec()]).unwrap();
+        assert_transcodes_as(&convert_ktx2_to_basis(&path).unwrap(), &basis);
+    }
+
+    #[test]
+    fn convert_etc1s_ktx2_to_basis() {
+        let basis = encode_basis(BasisTextureFormat::ETC1S, 3);
+        let slice = first_slice(&basis);
+        // The offset and length of the endpoint, selector, Huffman table and extended data
+        // codebooks in the .basis header
+        let codebooks = [(41, 45, 3), (50, 54, 3), (57, 61, 4), (69, 73, 4)].map(
+            |(offset, length, length_size)| {
+                let offset = basis_field(&basis, offset, 4) as usize;
+                &basis[offset..offset + basis_field(&basis, length, length_size) as usize]
+            },
+        );
+        let mut sgd = Vec::new();
+        // The endpoint and selector counts
+        put_packed(&mut sgd, basis_field(&basis, 39, 2), 2);
+        put_packed(&mut sgd, basis_field(&basis, 48, 2), 2);
+        for codebook in codebooks {
+            put_packed(&mut sgd, codebook.len() as u32, 4);
+        }
+        // The image flags and the RGB and alpha slices of the only image
+        for value in [0, 0, slice.len() as u32, 0, 0] {
+            put_packed(&mut sgd, value, 4);
+        }
+        sgd.extend(codebooks.concat());
+
+        let dir = test_dir("convert_etc1s_ktx2_to_basis");
+        let path = dir.join("texture.ktx2");
+        let mut header = KTX2_IDENTIFIER.to_vec();
+        for value in [0, 1, 8, 8, 0, 0, 1, 1, KTX2_SUPERCOMPRESSION_BASIS_LZ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut dfd = vec![0; 44];
+        dfd[12] = KHR_DF_MODEL_ETC1S;
+        Ktx2 {
+            header: &header,
+            dfd: &dfd,
+            kvd: Vec::new(),
+            sgd: &sgd,
+            levels: vec![(slice, slice.len() as u64)],
+        }
+        .write(&path)
+        .unwrap();
+        assert_transcodes_as(&convert_ktx2_to_basis(&path).unwrap(), &basis);
+    }
+
+    #[test]
+    fn merge_levels() {
+        let dir = test_dir("merge_levels");
+        let data = levels(&[64, 16, 16]);
+        let level_paths = [(8, 0), (4, 1), (2, 2)]
+            .into_iter()
+            .map(|(size, level)| {
+                let path = dir.join(format!("level_{}.ktx2", level));
+                write_ktx2(
+                    &path,
+                    VK_FORMAT_ASTC_4X4,
+                    (size, size),
+                    &astc_dfd((4, 4), false, false),
+                    &data[level..level + 1],
+                )
+                .unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let path = dir.join("merged.ktx2");
+        merge_ktx2_levels(&level_paths, &path).unwrap();
+
+        let texture = read_ktx2(&path).unwrap();
+        assert_eq!(texture.dimensions, (8, 8, 0));
+        assert_eq!(texture.levels, data);
+
+        // Levels must halve in size
+        let result = merge_ktx2_levels(&[level_paths[0].clone(), level_paths[2].clone()], &path);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn convert_etcpak_ktx1() {
+        let dir = test_dir("convert_etcpak_ktx1");
+        let ktx1_path = dir.join("texture.ktx");
+        let path = dir.join("texture.ktx2");
+        // etcpak writes the full mip chain of an 8x8 BC1 image
+        let data = levels(&[32, 8, 8, 8]);
+        let mut ktx1 = KTX1_IDENTIFIER.to_vec();
+        // endianness, glType, glTypeSize, glFormat, glInternalFormat, glBaseInternalFormat,
+        // pixelWidth, pixelHeight, pixelDepth, numberOfArrayElements, numberOfFaces,
+        // numberOfMipmapLevels and bytesOfKeyValueData
+        for value in [
+            KTX1_ENDIANNESS,
+            0,
+            1,
+            0,
+            0x83F0,
+            0x1907,
+            8,
+            8,
+            0,
+            0,
+            1,
+            4,
+            0,
+        ] {
+            ktx1.extend_from_slice(&value.to_le_bytes());
+        }
+        for level in &data {
+            ktx1.extend_from_slice(&(level.len() as u32).to_le_bytes());
+            ktx1.extend_from_slice(level);
+        }
+        std::fs::write(&ktx1_path, ktx1).unwrap();
+        convert_ktx1_to_ktx2(&ktx1_path, &path, true, Some(2)).unwrap();
+
+        let texture = read_ktx2(&path).unwrap();
+        // VK_FORMAT_BC1_RGB_SRGB_BLOCK
+        assert_eq!(texture.vk_format, 132);
+        assert_eq!(texture.dimensions, (8, 8, 0));
+        assert_eq!(texture.levels, data[..2]);
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use image::{Rgba32FImage, RgbaImage};
+use which::which;
+
+use crate::{
+    run_command, toktx::DEFAULT_ZSTD_LEVEL, write_layers, CompressError, CompressionFormat,
+    CompressionOptions, Compressor, ContainerFormat, TextureShape, TextureType,
+};
+
+pub(crate) const SUPPORTED_COMPRESSION_FORMATS: [CompressionFormat; 26] = [
+    CompressionFormat::Astc,
+    CompressionFormat::Astc4x4,
+    CompressionFormat::Astc5x4,
+    CompressionFormat::Astc5x5,
+    CompressionFormat::Astc6x5,
+    CompressionFormat::Astc6x6,
+    CompressionFormat::Astc8x5,
+    CompressionFormat::Astc8x6,
+    CompressionFormat::Astc8x8,
+    CompressionFormat::Astc10x5,
+    CompressionFormat::Astc10x6,
+    CompressionFormat::Astc10x8,
+    CompressionFormat::Astc10x10,
+    CompressionFormat::Astc12x10,
+    CompressionFormat::Astc12x12,
+    CompressionFormat::Bc1,
+    CompressionFormat::Bc3,
+    CompressionFormat::Bc4,
+    CompressionFormat::Bc5,
+    CompressionFormat::Bc7,
+    CompressionFormat::Etc1s,
+    CompressionFormat::Etc2r,
+    CompressionFormat::Etc2rg,
+    CompressionFormat::Etc2rgb,
+    CompressionFormat::Etc2rgba,
+    CompressionFormat::Uastc,
+];
+
+/// The `ktx` CLI tool of KTX-Software 4.3 and later, which replaces `toktx` with `ktx create`
+pub struct KtxCreate {
+    cli_path: PathBuf,
+}
+
+impl KtxCreate {
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            cli_path: which("ktx").map_err(|e| {
+                format!(
+                    "Failed to find the ktx CLI tool. Make sure it is in your PATH. {:?}",
+                    e
+                )
+            })?,
+        })
+    }
+
+    /// Transcodes a KTX2 file holding ETC1S or UASTC to a `ktx transcode` target, keeping its mip
+    /// levels
+    pub(crate) fn transcode(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        target: &str,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        command.args(["transcode", "--target", target]);
+        command.args(zstd_args(options));
+        command.args([src_path, dst_path]);
+        run_command(&mut command).map(|command_line| vec![command_line])
+    }
+
+    /// Builds the `ktx create` command for everything but the input and output files. `src_path`
+    /// is the first input, which determines the number of mip levels. Codecs that `ktx create`
+    /// cannot encode are encoded as UASTC and then transcoded with `ktx transcode` to the returned
+    /// target.
+    fn command(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<(Command, Option<&'static str>), String> {
+        if !SUPPORTED_COMPRESSION_FORMATS.contains(&compression_format)
+            || !matches!(container_format, ContainerFormat::Ktx2)
+        {
+            return Err(format!(
+                "Unsupported format {:?} {:?} - must be one of {:?} and {}",
+                compression_format,
+                container_format,
+                SUPPORTED_COMPRESSION_FORMATS,
+                ContainerFormat::Ktx2,
+            ));
+        }
+        let transcode_target = transcode_target(compression_format);
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        command.arg("create");
+        let srgb = match texture_type {
+            TextureType::Srgb => true,
+            TextureType::Linear | TextureType::NormalMap => false,
+            TextureType::Hdr => {
+                return Err(String::from("HDR textures are not supported by ktx create"));
+            }
+        };
+        command.args([
+            "--format",
+            vk_format(compression_format, srgb).as_str(),
+            "--assign-oetf",
+            if srgb { "srgb" } else { "linear" },
+        ]);
+        if matches!(texture_type, TextureType::NormalMap) && transcode_target.is_none() {
+            command.arg("--normal-mode");
+        }
+        let mipmaps = &options.mipmaps;
+        if mipmaps.enabled() {
+            command.arg("--generate-mipmap");
+            if let Some(mip_filter) = mipmaps.mip_filter {
+                command.args(["--mipmap-filter", mip_filter.to_string().as_str()]);
+            }
+            if let Some(mip_wrap) = mipmaps.mip_wrap {
+                command.args(["--mipmap-wrap", mip_wrap.to_string().as_str()]);
+            }
+            if mipmaps.mip_min_size.is_some() {
+                let (width, height) = image::image_dimensions(working_dir.join(src_path))
+                    .map_err(|e| format!("Failed to read {} - {}", src_path.display(), e))?;
+                if let Some(levels) = mipmaps.levels(width, height) {
+                    command.args(["--levels", levels.to_string().as_str()]);
+                }
+            }
+        }
+        match compression_format {
+            CompressionFormat::Etc1s => {
+                command.args([
+                    "--encode",
+                    "basis-lz",
+                    "--clevel",
+                    options.quality.etc1s_effort().to_string().as_str(),
+                    "--qlevel",
+                    options.quality.etc1s_quality().to_string().as_str(),
+                ]);
+            }
+            CompressionFormat::Uastc => {
+                command.args([
+                    "--encode",
+                    "uastc",
+                    "--uastc-quality",
+                    options.quality.uastc_level().to_string().as_str(),
+                ]);
+                let supercompression = &options.supercompression;
+                if let Some(rdo_lambda) = supercompression.rdo_lambda {
+                    command.args([
+                        "--uastc-rdo",
+                        "--uastc-rdo-l",
+                        rdo_lambda.to_string().as_str(),
+                    ]);
+                }
+                if let Some(rdo_dict_size) = supercompression.rdo_dict_size {
+                    command.args(["--uastc-rdo-d", rdo_dict_size.to_string().as_str()]);
+                }
+            }
+            _ if transcode_target.is_some() => {
+                command.args([
+                    "--encode",
+                    "uastc",
+                    "--uastc-quality",
+                    options.quality.uastc_level().to_string().as_str(),
+                ]);
+            }
+            _ => {
+                command.args([
+                    "--astc-quality",
+                    options.quality.astc_preset().to_string().as_str(),
+                ]);
+                if srgb {
+                    command.arg("--astc-perceptual");
+                }
+            }
+        }
+        if !matches!(compression_format, CompressionFormat::Etc1s) && transcode_target.is_none() {
+            command.args(zstd_args(options));
+        }
+        Ok((command, transcode_target))
+    }
+
+    /// Runs `ktx create` on the inputs, followed by `ktx transcode` if needed
+    fn run(
+        &self,
+        working_dir: &Path,
+        (mut command, transcode_target): (Command, Option<&'static str>),
+        src_paths: &[PathBuf],
+        dst_path: &Path,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        command.args(src_paths);
+        let transcode_target = match transcode_target {
+            Some(transcode_target) => transcode_target,
+            None => {
+                command.arg(dst_path);
+                return run_command(&mut command).map(|command_line| vec![command_line]);
+            }
+        };
+        let uastc_path = dst_path.with_extension("uastc.ktx2");
+        command.arg(&uastc_path);
+        let mut transcode = Command::new(&self.cli_path);
+        transcode.current_dir(working_dir);
+        transcode.args(["transcode", "--target", transcode_target]);
+        transcode.args(zstd_args(options));
+        transcode.args([&uastc_path, dst_path]);
+        let result =
+            run_command(&mut command).and_then(|create_line| match run_command(&mut transcode) {
+                Ok(transcode_line) => Ok(vec![create_line, transcode_line]),
+                Err(e) => Err(e.after(&[create_line])),
+            });
+        let _ = std::fs::remove_file(working_dir.join(&uastc_path));
+        result
+    }
+}
+
+/// The name of the vkFormat, without its `VK_FORMAT_` prefix, that `ktx create` writes for a
+/// codec. Codecs that are transcoded from UASTC start out as RGBA8.
+fn vk_format(compression_format: CompressionFormat, srgb: bool) -> String {
+    let block = match compression_format {
+        CompressionFormat::Astc => Some((4, 4)),
+        format => format.astc_block(),
+    };
+    let encoding = if srgb { "SRGB" } else { "UNORM" };
+    match block {
+        Some((block_width, block_height)) => {
+            format!("ASTC_{}x{}_{}_BLOCK", block_width, block_height, encoding)
+        }
+        None => format!("R8G8B8A8_{}", encoding),
+    }
+}
+
+/// The `ktx transcode` target for codecs that `ktx create` cannot encode directly
+pub(crate) fn transcode_target(compression_format: CompressionFormat) -> Option<&'static str> {
+    Some(match compression_format {
+        CompressionFormat::Bc1 => "bc1",
+        CompressionFormat::Bc3 => "bc3",
+        CompressionFormat::Bc4 => "bc4",
+        CompressionFormat::Bc5 => "bc5",
+        CompressionFormat::Bc7 => "bc7",
+        CompressionFormat::Etc2r => "eac-r11",
+        CompressionFormat::Etc2rg => "eac-rg11",
+        CompressionFormat::Etc2rgb => "etc-rgb",
+        CompressionFormat::Etc2rgba => "etc-rgba",
+        _ => return None,
+    })
+}
+
+pub(crate) fn zstd_args(options: &CompressionOptions) -> Vec<String> {
+    match options
+        .supercompression
+        .zstd_level
+        .unwrap_or(DEFAULT_ZSTD_LEVEL)
+    {
+        0 => Vec::new(),
+        zstd_level => vec![String::from("--zstd"), zstd_level.to_string()],
+    }
+}
+
+impl Compressor for KtxCreate {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let command = self.command(
+            working_dir,
+            src_path,
+            texture_type,
+            compression_format,
+            container_format,
+            options,
+        )?;
+        self.run(
+            working_dir,
+            command,
+            &[src_path.to_path_buf()],
+            dst_path,
+            options,
+        )
+    }
+
+    fn compress_layers(
+        &self,
+        working_dir: &Path,
+        layers: &[Rgba32FImage],
+        shape: TextureShape,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let layers_dir = dst_path.with_extension("layers");
+        let layer_paths = write_layers(working_dir, &layers_dir, layers, texture_type)?;
+        let result = self
+            .command(
+                working_dir,
+                &layer_paths[0],
+                texture_type,
+                compression_format,
+                container_format,
+                options,
+            )
+            .map_err(CompressError::from)
+            .and_then(|(mut command, transcode_target)| {
+                match shape {
+                    TextureShape::Cubemap => command.arg("--cubemap"),
+                    TextureShape::Array => {
+                        command.args(["--layers", layer_paths.len().to_string().as_str()])
+                    }
+                    TextureShape::Volume => {
+                        command.args(["--depth", layer_paths.len().to_string().as_str()])
+                    }
+                };
+                self.run(
+                    working_dir,
+                    (command, transcode_target),
+                    &layer_paths,
+                    dst_path,
+                    options,
+                )
+            });
+        let _ = std::fs::remove_dir_all(working_dir.join(&layers_dir));
+        result
+    }
+
+    /// `--normal-mode` stores X in the color channels and Y in alpha, while transcoding to BC5 or
+    /// EAC RG11 keeps X and Y in red and green
+    fn normal_map_swizzle(&self, compression_format: CompressionFormat) -> Option<&'static str> {
+        match transcode_target(compression_format) {
+            None => Some("ra01"),
+            Some("bc5" | "eac-rg11") => Some("rg01"),
+            Some(_) => None,
+        }
+    }
+
+    fn decompress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        if !matches!(
+            compression_format,
+            CompressionFormat::Etc1s | CompressionFormat::Uastc
+        ) {
+            return Err(format!(
+                "Decompressing {} is not supported by ktx",
+                compression_format
+            ));
+        }
+        let decoded_path = src_path.with_extension("decoded.png");
+        let mut command = Command::new(&self.cli_path);
+        command.current_dir(working_dir);
+        command.args([
+            "extract",
+            "--transcode",
+            "rgba8",
+            src_path.to_str().unwrap(),
+            decoded_path.to_str().unwrap(),
+        ]);
+        run_command(&mut command)?;
+        let image = image::open(working_dir.join(&decoded_path))
+            .map(|image| image.to_rgba8())
+            .map_err(|e| format!("Failed to read {} - {}", decoded_path.display(), e));
+        let _ = std::fs::remove_file(working_dir.join(&decoded_path));
+        image
+    }
+}
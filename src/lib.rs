@@ -1,21 +1,119 @@
+mod assemble;
+mod astcenc;
+mod avifenc;
 mod basisu;
+mod compressonator;
 mod compressor;
+mod config;
+mod coverage;
+mod cubemap;
+mod custom;
+mod cwebp;
+mod dds;
+mod etcpak;
 mod gltf;
+mod ibl;
 mod image;
 mod kram;
+mod ktx;
+mod ktx_create;
+mod metrics;
+mod normal;
+mod options;
+mod pack;
+mod preview;
+mod report;
+mod resize;
 mod toktx;
+mod transcode;
 
 pub use crate::gltf::*;
+pub use assemble::*;
+pub use astcenc::*;
+pub use avifenc::*;
 pub use basisu::*;
 use clap::ArgEnum;
+pub use compressonator::*;
 pub use compressor::*;
+pub use custom::*;
+pub use cwebp::*;
+pub use etcpak::*;
+pub use ibl::*;
 pub use kram::*;
+pub use ktx_create::*;
+pub use metrics::*;
+pub use normal::*;
+pub use options::*;
+pub use preview::*;
+pub use report::*;
+pub use resize::*;
 pub use toktx::*;
 
-#[derive(Clone, Debug, ArgEnum, strum::Display, strum::EnumString, PartialEq, Eq, Hash)]
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    ArgEnum,
+    serde::Serialize,
+    strum::Display,
+    strum::EnumString,
+    PartialEq,
+    Eq,
+    Hash,
+)]
+#[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 pub enum Backend {
+    Astcenc,
+    Avifenc,
     Basisu,
+    Compressonator,
+    /// The tool configured as `custom_backend` in the project config file
+    Custom,
+    Cwebp,
+    Etcpak,
     Kram,
+    /// `ktx create` from KTX-Software 4.3 and later
+    Ktx,
     Toktx,
 }
+
+impl Backend {
+    /// The backend to use when none is given: `ktx create` if the `ktx` CLI tool is in the
+    /// `PATH` and encodes the codec itself, otherwise `toktx`. `ktx create` only reaches BC and
+    /// ETC2 through UASTC transcoding, which loses quality, so they stay with `toktx`.
+    pub fn detect(compression_format: CompressionFormat) -> Backend {
+        if ktx_create::SUPPORTED_COMPRESSION_FORMATS.contains(&compression_format)
+            && ktx_create::transcode_target(compression_format).is_none()
+            && which::which("ktx").is_ok()
+        {
+            Backend::Ktx
+        } else {
+            Backend::Toktx
+        }
+    }
+
+    pub fn compressor(&self) -> Result<Box<dyn Compressor>, String> {
+        Ok(match self {
+            Backend::Astcenc => Box::new(Astcenc::new()?),
+            Backend::Avifenc => Box::new(Avifenc::new()?),
+            Backend::Basisu => Box::new(Basisu::new()?),
+            Backend::Compressonator => Box::new(Compressonator::new()?),
+            Backend::Custom => Box::new(Custom::new()?),
+            Backend::Cwebp => Box::new(Cwebp::new()?),
+            Backend::Etcpak => Box::new(Etcpak::new()?),
+            Backend::Kram => Box::new(Kram::new()?),
+            Backend::Ktx => Box::new(KtxCreate::new()?),
+            Backend::Toktx => Box::new(Toktx::new()?),
+        })
+    }
+}
+
+/// An empty directory for the files of one test
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("klafsa-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Failed to create test directory");
+    dir
+}
@@ -1,5 +1,7 @@
 use clap::{Parser, Subcommand};
-use klafsa::{Backend, CompressionFormat, ContainerFormat, Gltf};
+use klafsa::{
+    Assemble, Backend, CompressionFormat, CompressionOptions, ContainerFormat, Gltf, Ibl, Preview,
+};
 use tracing::{error, subscriber};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter, Registry};
 
@@ -10,35 +12,66 @@ use tracing_subscriber::{fmt, prelude::*, EnvFilter, Registry};
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
-    /// Which tool to use for compression
-    #[clap(short, long, arg_enum, default_value_t = Backend::Toktx)]
-    backend: Backend,
+    /// Which tool to use for compression [default: ktx for ASTC, UASTC and ETC1S if it is in the
+    /// PATH, otherwise toktx, and kram for ibl]
+    #[clap(short, long, arg_enum)]
+    backend: Option<Backend>,
     /// Which codec to use for compression
     #[clap(long, arg_enum, default_value_t = CompressionFormat::Uastc)]
     codec: CompressionFormat,
     /// Which container format to use
     #[clap(long, arg_enum, default_value_t = ContainerFormat::Ktx2)]
     container: ContainerFormat,
+    #[clap(flatten)]
+    options: CompressionOptions,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Converts all JPEG/PNG textures referred to by a JSON-format glTF
     Gltf(Gltf),
+    /// Assembles cubemaps, texture arrays and 3D textures from several images
+    Assemble(Assemble),
+    /// Prefilters an equirectangular HDR environment map for image-based lighting
+    Ibl(Ibl),
+    /// Decodes an ETC1S or UASTC file as each GPU format the Basis transcoder turns it into at
+    /// runtime, with quality metrics
+    Preview(Preview),
 }
 
 fn main() {
     init_logging();
 
     let cli = Cli::parse();
+    let backend = cli.backend.unwrap_or_else(|| Backend::detect(cli.codec));
 
     match &cli.command {
         Commands::Gltf(gltf) => {
-            if let Err(e) = gltf.process(cli.backend, cli.codec, cli.container) {
+            if let Err(e) = gltf.process(backend, cli.codec, cli.container, &cli.options) {
                 error!("Failed to process '{}' - {}", gltf.file_path, e);
                 std::process::exit(1);
             }
         }
+        Commands::Assemble(assemble) => {
+            if let Err(e) = assemble.process(backend, cli.codec, cli.container, &cli.options) {
+                error!("Failed to assemble '{}' - {}", assemble.output, e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Ibl(ibl) => {
+            // Only kram compresses HDR cubemaps
+            let backend = cli.backend.unwrap_or(Backend::Kram);
+            if let Err(e) = ibl.process(backend, cli.codec, cli.container, &cli.options) {
+                error!("Failed to process '{}' - {}", ibl.file_path, e);
+                std::process::exit(1);
+            }
+        }
+        Commands::Preview(preview) => {
+            if let Err(e) = preview.process() {
+                error!("Failed to preview '{}' - {}", preview.file_path, e);
+                std::process::exit(1);
+            }
+        }
     }
 
     std::process::exit(0)
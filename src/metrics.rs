@@ -0,0 +1,157 @@
+use std::fmt;
+
+use image::RgbaImage;
+use serde::Serialize;
+
+/// Size of the square windows used to compute SSIM
+const SSIM_WINDOW_SIZE: u32 = 8;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct QualityMetrics {
+    /// Peak signal-to-noise ratio in dB over all RGBA channels
+    pub psnr: f64,
+    /// Mean structural similarity of the luma channel
+    pub ssim: f64,
+}
+
+impl QualityMetrics {
+    pub fn meets(&self, min_psnr: Option<f64>, min_ssim: Option<f64>) -> bool {
+        min_psnr.is_none_or(|min_psnr| self.psnr >= min_psnr)
+            && min_ssim.is_none_or(|min_ssim| self.ssim >= min_ssim)
+    }
+}
+
+impl fmt::Display for QualityMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PSNR {:.2} dB, SSIM {:.4}", self.psnr, self.ssim)
+    }
+}
+
+pub fn compare(reference: &RgbaImage, candidate: &RgbaImage) -> Result<QualityMetrics, String> {
+    if reference.dimensions() != candidate.dimensions() {
+        return Err(format!(
+            "Cannot compare images of different dimensions {:?} and {:?}",
+            reference.dimensions(),
+            candidate.dimensions()
+        ));
+    }
+    Ok(QualityMetrics {
+        psnr: psnr(reference, candidate),
+        ssim: ssim(reference, candidate),
+    })
+}
+
+fn psnr(reference: &RgbaImage, candidate: &RgbaImage) -> f64 {
+    let squared_error = reference
+        .as_raw()
+        .iter()
+        .zip(candidate.as_raw().iter())
+        .map(|(&a, &b)| {
+            let d = a as f64 - b as f64;
+            d * d
+        })
+        .sum::<f64>();
+    let mse = squared_error / reference.as_raw().len().max(1) as f64;
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
+
+fn luma(image: &RgbaImage, x: u32, y: u32) -> f64 {
+    let [r, g, b, _] = image.get_pixel(x, y).0;
+    0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64
+}
+
+fn ssim(reference: &RgbaImage, candidate: &RgbaImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = reference.dimensions();
+    let mut total = 0.0;
+    let mut windows = 0;
+    for window_y in (0..height).step_by(SSIM_WINDOW_SIZE as usize) {
+        for window_x in (0..width).step_by(SSIM_WINDOW_SIZE as usize) {
+            let x_end = (window_x + SSIM_WINDOW_SIZE).min(width);
+            let y_end = (window_y + SSIM_WINDOW_SIZE).min(height);
+            let n = ((x_end - window_x) * (y_end - window_y)) as f64;
+
+            let (mut sum_a, mut sum_b) = (0.0, 0.0);
+            let (mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0);
+            for y in window_y..y_end {
+                for x in window_x..x_end {
+                    let a = luma(reference, x, y);
+                    let b = luma(candidate, x, y);
+                    sum_a += a;
+                    sum_b += b;
+                    sum_aa += a * a;
+                    sum_bb += b * b;
+                    sum_ab += a * b;
+                }
+            }
+            let mean_a = sum_a / n;
+            let mean_b = sum_b / n;
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        1.0
+    } else {
+        total / windows as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    fn gradient(offset: u8) -> RgbaImage {
+        RgbaImage::from_fn(16, 16, |x, y| {
+            let value = (x * 8 + y * 4) as u8 + offset;
+            Rgba([value, value, value, 255 - offset])
+        })
+    }
+
+    #[test]
+    fn identical_images_are_lossless() {
+        let metrics = compare(&gradient(0), &gradient(0)).unwrap();
+        assert_eq!(metrics.psnr, f64::INFINITY);
+        assert!((metrics.ssim - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn constant_offset_psnr() {
+        let metrics = compare(&gradient(0), &gradient(16)).unwrap();
+        // Every channel is off by 16
+        let expected = 10.0 * (255.0f64 * 255.0 / 256.0).log10();
+        assert!((metrics.psnr - expected).abs() < 1e-9);
+        assert!(metrics.ssim < 1.0);
+    }
+
+    #[test]
+    fn rejects_different_dimensions() {
+        assert!(compare(&gradient(0), &RgbaImage::new(8, 8)).is_err());
+    }
+
+    #[test]
+    fn meets_targets() {
+        let metrics = QualityMetrics {
+            psnr: 40.0,
+            ssim: 0.95,
+        };
+        assert!(metrics.meets(None, None));
+        assert!(metrics.meets(Some(40.0), Some(0.95)));
+        assert!(!metrics.meets(Some(40.1), None));
+        assert!(!metrics.meets(None, Some(0.96)));
+        assert!(!metrics.meets(Some(30.0), Some(0.99)));
+    }
+}
@@ -0,0 +1,155 @@
+use clap::Args;
+use image::DynamicImage;
+use tracing::{debug, warn};
+
+use crate::resize::with_depth_of;
+
+/// Preparation of tangent-space normal maps before they are handed to a backend
+#[derive(Args, Clone, Debug)]
+pub struct NormalMaps {
+    /// Flip the green channel of normal maps, converting the DirectX convention (Y down) to the
+    /// OpenGL convention (Y up) that glTF expects
+    #[clap(long)]
+    pub flip_normal_green: bool,
+    /// Validate normal maps and rescale their vectors to unit length before compression
+    #[clap(long)]
+    pub renormalize_normals: bool,
+}
+
+impl NormalMaps {
+    pub fn enabled(&self) -> bool {
+        self.flip_normal_green || self.renormalize_normals
+    }
+
+    /// Decodes the normal vectors of an image, flips Y if requested, and rescales them to unit
+    /// length if requested. When renormalizing, vectors that are too short to have a direction
+    /// become +Z, and vectors pointing into the surface are mirrored so that two-channel codecs
+    /// can reconstruct Z from X and Y.
+    pub(crate) fn preprocess(&self, image: &DynamicImage, name: &str) -> DynamicImage {
+        let mut rgba = image.to_rgba32f();
+        let (mut renormalized, mut degenerate, mut inward) = (0, 0, 0);
+        for pixel in rgba.pixels_mut() {
+            let [r, g, b, _] = pixel.0;
+            let mut normal = [r * 2.0 - 1.0, g * 2.0 - 1.0, b * 2.0 - 1.0];
+            if self.flip_normal_green {
+                normal[1] = -normal[1];
+            }
+            if !self.renormalize_normals {
+                pixel.0[1] = normal[1] * 0.5 + 0.5;
+                continue;
+            }
+            let length = normal.iter().map(|c| c * c).sum::<f32>().sqrt();
+            if length < 1e-3 {
+                degenerate += 1;
+                normal = [0.0, 0.0, 1.0];
+            } else {
+                // 8-bit quantization alone is off by up to about 1%
+                if (length - 1.0).abs() > 0.02 {
+                    renormalized += 1;
+                }
+                normal = normal.map(|c| c / length);
+            }
+            if normal[2] < 0.0 {
+                inward += 1;
+                normal[2] = -normal[2];
+            }
+            for (value, c) in pixel.0.iter_mut().zip(normal) {
+                *value = c * 0.5 + 0.5;
+            }
+        }
+        let texels = rgba.width() as usize * rgba.height() as usize;
+        if renormalized > 0 {
+            debug!(
+                "Renormalized {} of {} normals in {}",
+                renormalized, texels, name
+            );
+        }
+        if renormalized * 2 > texels {
+            warn!(
+                "Most normals in {} are not unit length, so it may not be a normal map",
+                name
+            );
+        }
+        if degenerate > 0 || inward > 0 {
+            warn!(
+                "{} has {} zero-length normals and {} normals with negative Z, which were \
+                 replaced",
+                name, degenerate, inward
+            );
+        }
+        with_depth_of(image, rgba)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, Rgba32FImage};
+
+    use super::*;
+
+    fn normal_maps(flip_normal_green: bool, renormalize_normals: bool) -> NormalMaps {
+        NormalMaps {
+            flip_normal_green,
+            renormalize_normals,
+        }
+    }
+
+    /// Encodes normal vectors as a one row image and decodes the preprocessed vectors
+    fn preprocess(normal_maps: &NormalMaps, normals: &[[f32; 3]]) -> Vec<[f32; 3]> {
+        let image = Rgba32FImage::from_fn(normals.len() as u32, 1, |x, _| {
+            let [x, y, z] = normals[x as usize];
+            Rgba([x * 0.5 + 0.5, y * 0.5 + 0.5, z * 0.5 + 0.5, 1.0])
+        });
+        normal_maps
+            .preprocess(&DynamicImage::ImageRgba32F(image), "test")
+            .to_rgba32f()
+            .pixels()
+            .map(|pixel| [0, 1, 2].map(|c| pixel.0[c] * 2.0 - 1.0))
+            .collect()
+    }
+
+    fn assert_near(actual: &[[f32; 3]], expected: &[[f32; 3]]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(
+                a.iter().zip(e).all(|(a, e)| (a - e).abs() < 1e-5),
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert!(!normal_maps(false, false).enabled());
+        assert!(normal_maps(true, false).enabled());
+        assert!(normal_maps(false, true).enabled());
+    }
+
+    #[test]
+    fn renormalizes_to_unit_length() {
+        let normals = preprocess(
+            &normal_maps(false, true),
+            &[[0.3, 0.0, 0.4], [0.0, 0.0, 1.0]],
+        );
+        assert_near(&normals, &[[0.6, 0.0, 0.8], [0.0, 0.0, 1.0]]);
+    }
+
+    #[test]
+    fn flips_green() {
+        // Flipping alone leaves the length of the vectors as it is
+        let normals = preprocess(&normal_maps(true, false), &[[0.3, 0.2, 0.4]]);
+        assert_near(&normals, &[[0.3, -0.2, 0.4]]);
+        let normals = preprocess(&normal_maps(true, true), &[[0.0, 0.6, 0.8]]);
+        assert_near(&normals, &[[0.0, -0.6, 0.8]]);
+    }
+
+    #[test]
+    fn replaces_degenerate_and_inward_normals() {
+        let normals = preprocess(
+            &normal_maps(false, true),
+            &[[0.0, 0.0, 0.0], [0.6, 0.0, -0.8]],
+        );
+        assert_near(&normals, &[[0.0, 0.0, 1.0], [0.6, 0.0, 0.8]]);
+    }
+}
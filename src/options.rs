@@ -0,0 +1,277 @@
+use clap::{ArgEnum, Args};
+use serde::Serialize;
+
+/// Settings shared by all backends, which each translate them to their own flags
+#[derive(Args, Clone, Debug, Serialize)]
+pub struct CompressionOptions {
+    #[clap(flatten)]
+    pub quality: Quality,
+    #[clap(flatten)]
+    pub supercompression: Supercompression,
+    #[clap(flatten)]
+    pub mipmaps: Mipmaps,
+}
+
+#[derive(Clone, Copy, Debug, ArgEnum, Serialize, strum::Display, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum QualityLevel {
+    Fast,
+    Normal,
+    High,
+    Max,
+}
+
+#[derive(Clone, Copy, Debug, ArgEnum, Serialize, strum::Display, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum AstcPreset {
+    Fastest,
+    Fast,
+    Medium,
+    Thorough,
+    Exhaustive,
+}
+
+impl AstcPreset {
+    /// The numeric astcenc quality that this preset stands for
+    pub fn quality(&self) -> u8 {
+        match *self {
+            AstcPreset::Fastest => 0,
+            AstcPreset::Fast => 10,
+            AstcPreset::Medium => 60,
+            AstcPreset::Thorough => 98,
+            AstcPreset::Exhaustive => 100,
+        }
+    }
+}
+
+/// Speed versus quality trade-off, with optional codec-specific overrides
+#[derive(Args, Clone, Debug, Serialize)]
+pub struct Quality {
+    /// Speed versus quality trade-off for all codecs
+    #[clap(long = "quality", arg_enum, default_value_t = QualityLevel::Normal)]
+    pub level: QualityLevel,
+    /// ETC1S quality level from 1 to 255, overriding --quality
+    #[clap(long, parse(try_from_str = parse_etc1s_quality))]
+    pub etc1s_quality: Option<u8>,
+    /// UASTC quality level from 0 (fastest) to 4 (slowest), overriding --quality
+    #[clap(long, parse(try_from_str = parse_uastc_level))]
+    pub uastc_level: Option<u8>,
+    /// astcenc preset, overriding --quality
+    #[clap(long, arg_enum)]
+    pub astc_preset: Option<AstcPreset>,
+    /// Visual quality of lossy WebP and AVIF from 0 to 100 [default: 80]. --quality only sets
+    /// their encoding effort
+    #[clap(long, parse(try_from_str = parse_web_quality))]
+    pub web_quality: Option<u8>,
+}
+
+impl Quality {
+    pub fn etc1s_quality(&self) -> u8 {
+        self.etc1s_quality.unwrap_or(match self.level {
+            QualityLevel::Fast => 64,
+            QualityLevel::Normal => 128,
+            QualityLevel::High => 192,
+            QualityLevel::Max => 255,
+        })
+    }
+
+    /// ETC1S encoder effort from 0 to 5 as used by basisu and toktx
+    pub fn etc1s_effort(&self) -> u8 {
+        match self.level {
+            QualityLevel::Fast => 0,
+            QualityLevel::Normal => 1,
+            QualityLevel::High => 3,
+            QualityLevel::Max => 5,
+        }
+    }
+
+    pub fn uastc_level(&self) -> u8 {
+        self.uastc_level.unwrap_or(match self.level {
+            QualityLevel::Fast => 0,
+            QualityLevel::Normal => 2,
+            QualityLevel::High => 3,
+            QualityLevel::Max => 4,
+        })
+    }
+
+    pub fn astc_preset(&self) -> AstcPreset {
+        self.astc_preset.unwrap_or(match self.level {
+            QualityLevel::Fast => AstcPreset::Fast,
+            QualityLevel::Normal => AstcPreset::Medium,
+            QualityLevel::High => AstcPreset::Thorough,
+            QualityLevel::Max => AstcPreset::Exhaustive,
+        })
+    }
+
+    pub fn web_quality(&self) -> u8 {
+        self.web_quality.unwrap_or(80)
+    }
+
+    /// cwebp compression method from 0 (fastest) to 6 (slowest)
+    pub fn webp_method(&self) -> u8 {
+        match self.level {
+            QualityLevel::Fast => 0,
+            QualityLevel::Normal => 4,
+            QualityLevel::High => 5,
+            QualityLevel::Max => 6,
+        }
+    }
+
+    /// cwebp lossless preset from 0 (fastest) to 9 (slowest)
+    pub fn webp_lossless_level(&self) -> u8 {
+        match self.level {
+            QualityLevel::Fast => 0,
+            QualityLevel::Normal => 6,
+            QualityLevel::High => 8,
+            QualityLevel::Max => 9,
+        }
+    }
+
+    /// avifenc speed from 0 (slowest) to 10 (fastest)
+    pub fn avif_speed(&self) -> u8 {
+        match self.level {
+            QualityLevel::Fast => 10,
+            QualityLevel::Normal => 6,
+            QualityLevel::High => 4,
+            QualityLevel::Max => 0,
+        }
+    }
+
+    /// Quality from 0 to 100 for encoders without finer-grained controls
+    pub fn percentage(&self) -> u8 {
+        match self.level {
+            QualityLevel::Fast => 10,
+            QualityLevel::Normal => 50,
+            QualityLevel::High => 75,
+            QualityLevel::Max => 100,
+        }
+    }
+}
+
+/// UASTC rate-distortion optimization and KTX2 Zstandard supercompression
+#[derive(Args, Clone, Debug, Serialize)]
+pub struct Supercompression {
+    /// Enable UASTC rate-distortion optimization with this lambda. Higher values trade quality
+    /// for better compressibility
+    #[clap(long, parse(try_from_str = parse_rdo_lambda))]
+    pub rdo_lambda: Option<f32>,
+    /// UASTC RDO dictionary size in bytes
+    #[clap(long, requires = "rdo-lambda")]
+    pub rdo_dict_size: Option<u32>,
+    /// Zstandard supercompression level for KTX2 UASTC/ASTC outputs from 1 to 22, or 0 to disable
+    #[clap(long, parse(try_from_str = parse_zstd_level))]
+    pub zstd_level: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, ArgEnum, Serialize, strum::Display, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum MipmapMode {
+    /// Generate mipmaps unless the glTF sampler's minFilter does not use them
+    Auto,
+    On,
+    Off,
+}
+
+/// Mipmap downsampling filters, named as basisu and toktx name them
+#[derive(Clone, Copy, Debug, ArgEnum, Serialize, strum::Display, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum MipFilter {
+    Box,
+    Tent,
+    Bell,
+    #[clap(name = "b-spline")]
+    #[serde(rename = "b-spline")]
+    #[strum(serialize = "b-spline")]
+    BSpline,
+    Mitchell,
+    Lanczos3,
+    Lanczos4,
+    Kaiser,
+    Gaussian,
+    #[clap(name = "catmullrom")]
+    CatmullRom,
+}
+
+#[derive(Clone, Copy, Debug, ArgEnum, Serialize, strum::Display, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum MipWrap {
+    Clamp,
+    Wrap,
+    Reflect,
+}
+
+#[derive(Args, Clone, Debug, Serialize)]
+pub struct Mipmaps {
+    /// Whether to generate mipmaps
+    #[clap(long = "mipmaps", arg_enum, default_value_t = MipmapMode::Auto)]
+    pub mode: MipmapMode,
+    /// Filter used to downsample each mip level. Not supported by kram
+    #[clap(long, arg_enum)]
+    pub mip_filter: Option<MipFilter>,
+    /// Smallest mip level dimension to generate
+    #[clap(long)]
+    pub mip_min_size: Option<u32>,
+    /// How to sample across image borders while filtering, taken from the glTF sampler's wrapS
+    /// if not given
+    #[clap(long, arg_enum)]
+    pub mip_wrap: Option<MipWrap>,
+}
+
+impl Mipmaps {
+    pub fn enabled(&self) -> bool {
+        !matches!(self.mode, MipmapMode::Off)
+    }
+
+    /// Number of mip levels to generate for an image of the given dimensions, if limited by
+    /// `mip_min_size`
+    pub fn levels(&self, width: u32, height: u32) -> Option<u32> {
+        let min_size = self.mip_min_size?.max(1);
+        let mut size = width.max(height);
+        let mut levels = 1;
+        while size / 2 >= min_size {
+            size /= 2;
+            levels += 1;
+        }
+        Some(levels)
+    }
+}
+
+fn parse_etc1s_quality(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(quality) if quality >= 1 => Ok(quality),
+        _ => Err(String::from("must be a number from 1 to 255")),
+    }
+}
+
+fn parse_uastc_level(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(level) if level <= 4 => Ok(level),
+        _ => Err(String::from("must be a number from 0 to 4")),
+    }
+}
+
+fn parse_web_quality(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(quality) if quality <= 100 => Ok(quality),
+        _ => Err(String::from("must be a number from 0 to 100")),
+    }
+}
+
+fn parse_rdo_lambda(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(lambda) if lambda > 0.0 => Ok(lambda),
+        _ => Err(String::from("must be a number greater than 0")),
+    }
+}
+
+fn parse_zstd_level(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(level) if level <= 22 => Ok(level),
+        _ => Err(String::from("must be a number from 0 to 22")),
+    }
+}
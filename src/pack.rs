@@ -0,0 +1,485 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use gltf::json::{image::MimeType, texture::Texture, Image, Index, Root};
+use image::{imageops, DynamicImage, ImageBuffer, Rgba};
+use tracing::{info, warn};
+
+use crate::image::open_image;
+
+/// Packs occlusion into the unused red channel of metallic-roughness textures, so that materials
+/// sample one ORM texture instead of two. Packed images are written as PNG files next to the
+/// metallic-roughness images, and textures and images that are no longer used are removed. `json`
+/// is the glTF document `root` was read from.
+pub(crate) fn pack_orm(
+    working_dir: &Path,
+    root: &mut Root,
+    json: &serde_json::Value,
+) -> Result<(), String> {
+    let originally_used = used_textures(root);
+    let metallic_roughness_only = metallic_roughness_only(root);
+    // Packing reads the original images, even after a metallic-roughness texture has been
+    // pointed at its packed image
+    let sources = root
+        .textures
+        .iter()
+        .map(|texture| texture.source.value())
+        .collect::<Vec<_>>();
+    // The packed texture for each metallic-roughness and occlusion texture pair
+    let mut packed: HashMap<(usize, usize), usize> = HashMap::new();
+    // Metallic-roughness textures whose red channel already holds an occlusion texture
+    let mut red_channel: HashMap<usize, usize> = HashMap::new();
+    for material_index in 0..root.materials.len() {
+        let material = &root.materials[material_index];
+        let (occlusion, metallic_roughness) = match (
+            &material.occlusion_texture,
+            &material.pbr_metallic_roughness.metallic_roughness_texture,
+        ) {
+            (Some(occlusion), Some(metallic_roughness)) => (occlusion, metallic_roughness),
+            _ => continue,
+        };
+        let name = material
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("#{}", material_index));
+        let occlusion_texture = occlusion.index.value();
+        let metallic_roughness_texture = metallic_roughness.index.value();
+        if occlusion_texture == metallic_roughness_texture
+            || root.textures[occlusion_texture].source.value()
+                == root.textures[metallic_roughness_texture].source.value()
+        {
+            continue;
+        }
+        if occlusion.tex_coord != metallic_roughness.tex_coord {
+            info!(
+                "Not packing occlusion of material {} - it uses different UV sets",
+                name
+            );
+            continue;
+        }
+        if metallic_roughness
+            .extensions
+            .as_ref()
+            .is_some_and(|extensions| extensions.texture_transform.is_some())
+            || has_occlusion_transform(json, material_index)
+        {
+            info!(
+                "Not packing occlusion of material {} - its textures have transforms",
+                name
+            );
+            continue;
+        }
+        if sampler_json(root, occlusion_texture) != sampler_json(root, metallic_roughness_texture) {
+            info!(
+                "Not packing occlusion of material {} - its textures use different samplers",
+                name
+            );
+            continue;
+        }
+
+        let key = (metallic_roughness_texture, occlusion_texture);
+        let orm_texture = match packed.get(&key) {
+            Some(&orm_texture) => orm_texture,
+            None => {
+                // A texture only sampled as metallic-roughness ignores its red channel, so it can
+                // take the occlusion unless another occlusion texture is already there
+                let reuse = metallic_roughness_only.contains(&metallic_roughness_texture)
+                    && !red_channel.contains_key(&metallic_roughness_texture);
+                let image = match pack_images(
+                    working_dir,
+                    root,
+                    (
+                        sources[metallic_roughness_texture],
+                        sources[occlusion_texture],
+                    ),
+                    reuse,
+                ) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        warn!("Not packing occlusion of material {} - {}", name, e);
+                        continue;
+                    }
+                };
+                root.images.push(image);
+                let source = Index::new(root.images.len() as u32 - 1);
+                let orm_texture = if reuse {
+                    red_channel.insert(metallic_roughness_texture, occlusion_texture);
+                    root.textures[metallic_roughness_texture].source = source;
+                    metallic_roughness_texture
+                } else {
+                    let texture = Texture {
+                        source,
+                        ..root.textures[metallic_roughness_texture].clone()
+                    };
+                    root.textures.push(texture);
+                    root.textures.len() - 1
+                };
+                packed.insert(key, orm_texture);
+                orm_texture
+            }
+        };
+        info!(
+            "Packed occlusion of material {} into texture {}",
+            name, orm_texture
+        );
+        let material = &mut root.materials[material_index];
+        if let Some(occlusion) = material.occlusion_texture.as_mut() {
+            occlusion.index = Index::new(orm_texture as u32);
+        }
+        if let Some(metallic_roughness) = material
+            .pbr_metallic_roughness
+            .metallic_roughness_texture
+            .as_mut()
+        {
+            metallic_roughness.index = Index::new(orm_texture as u32);
+        }
+    }
+    if !packed.is_empty() {
+        let unused = originally_used
+            .difference(&used_textures(root))
+            .copied()
+            .collect();
+        remove_textures(root, &unused, &sources.into_iter().collect());
+    }
+    Ok(())
+}
+
+/// Writes the packed image of a metallic-roughness and occlusion image pair, at the larger of
+/// their sizes, and returns the image that refers to it
+fn pack_images(
+    working_dir: &Path,
+    root: &Root,
+    (metallic_roughness_image, occlusion_image): (usize, usize),
+    reuse: bool,
+) -> Result<Image, String> {
+    let metallic_roughness_uri = image_uri(root, metallic_roughness_image)?;
+    let occlusion_uri = image_uri(root, occlusion_image)?;
+    let metallic_roughness = open_image(&working_dir.join(metallic_roughness_uri))?;
+    let occlusion = open_image(&working_dir.join(occlusion_uri))?;
+    let width = metallic_roughness.width().max(occlusion.width());
+    let height = metallic_roughness.height().max(occlusion.height());
+    let resize = |image: &DynamicImage| {
+        let image = image.to_rgba16();
+        if image.dimensions() == (width, height) {
+            image
+        } else {
+            imageops::resize(&image, width, height, imageops::FilterType::Lanczos3)
+        }
+    };
+    let (metallic_roughness_rgba, occlusion_rgba) =
+        (resize(&metallic_roughness), resize(&occlusion));
+    let packed = ImageBuffer::from_fn(width, height, |x, y| {
+        let [_, roughness, metallic, _] = metallic_roughness_rgba.get_pixel(x, y).0;
+        Rgba([
+            occlusion_rgba.get_pixel(x, y).0[0],
+            roughness,
+            metallic,
+            u16::MAX,
+        ])
+    });
+    let is_16_bit =
+        |image: &DynamicImage| image.color().bytes_per_pixel() > image.color().channel_count();
+    let packed = if is_16_bit(&metallic_roughness) || is_16_bit(&occlusion) {
+        DynamicImage::ImageRgba16(packed)
+    } else {
+        DynamicImage::ImageRgba8(DynamicImage::ImageRgba16(packed).to_rgba8())
+    };
+
+    let metallic_roughness_path = Path::new(metallic_roughness_uri);
+    let stem = metallic_roughness_path
+        .file_stem()
+        .unwrap()
+        .to_str()
+        .unwrap();
+    let file_name = if reuse {
+        format!("{}_orm.png", stem)
+    } else {
+        let occlusion_stem = Path::new(occlusion_uri)
+            .file_stem()
+            .unwrap()
+            .to_str()
+            .unwrap();
+        format!("{}_{}_orm.png", stem, occlusion_stem)
+    };
+    let uri: PathBuf = metallic_roughness_path.with_file_name(file_name);
+    packed
+        .save_with_format(working_dir.join(&uri), ::image::ImageFormat::Png)
+        .map_err(|e| format!("Failed to write {} - {}", uri.display(), e))?;
+    Ok(Image {
+        buffer_view: None,
+        mime_type: Some(MimeType(String::from("image/png"))),
+        name: None,
+        uri: Some(uri.display().to_string()),
+        extensions: None,
+        extras: Default::default(),
+    })
+}
+
+fn image_uri(root: &Root, image: usize) -> Result<&str, String> {
+    root.images[image]
+        .uri
+        .as_deref()
+        .ok_or_else(|| format!("image {} is embedded in a buffer view", image))
+}
+
+fn sampler_json(root: &Root, texture: usize) -> Option<serde_json::Value> {
+    root.textures[texture]
+        .sampler
+        .and_then(|sampler| serde_json::to_value(&root.samplers[sampler.value()]).ok())
+}
+
+/// Whether the occlusion texture of a material has a `KHR_texture_transform`, which gltf-json does
+/// not read
+fn has_occlusion_transform(json: &serde_json::Value, material: usize) -> bool {
+    json.pointer(&format!(
+        "/materials/{}/occlusionTexture/extensions/KHR_texture_transform",
+        material
+    ))
+    .is_some()
+}
+
+/// Textures whose image no material samples in any other role than metallic-roughness
+fn metallic_roughness_only(root: &Root) -> HashSet<usize> {
+    let mut other_images = HashSet::new();
+    for material in &root.materials {
+        let others = [
+            material
+                .pbr_metallic_roughness
+                .base_color_texture
+                .as_ref()
+                .map(|info| info.index),
+            material.emissive_texture.as_ref().map(|info| info.index),
+            material.normal_texture.as_ref().map(|info| info.index),
+            material.occlusion_texture.as_ref().map(|info| info.index),
+        ];
+        other_images.extend(
+            others
+                .into_iter()
+                .flatten()
+                .map(|index| root.textures[index.value()].source.value()),
+        );
+    }
+    (0..root.textures.len())
+        .filter(|&texture| !other_images.contains(&root.textures[texture].source.value()))
+        .collect()
+}
+
+fn used_textures(root: &Root) -> HashSet<usize> {
+    let mut textures = HashSet::new();
+    for material in &root.materials {
+        let pbr = &material.pbr_metallic_roughness;
+        textures.extend(pbr.base_color_texture.iter().map(|info| info.index.value()));
+        textures.extend(
+            pbr.metallic_roughness_texture
+                .iter()
+                .map(|info| info.index.value()),
+        );
+        textures.extend(
+            material
+                .emissive_texture
+                .iter()
+                .map(|info| info.index.value()),
+        );
+        textures.extend(
+            material
+                .normal_texture
+                .iter()
+                .map(|info| info.index.value()),
+        );
+        textures.extend(
+            material
+                .occlusion_texture
+                .iter()
+                .map(|info| info.index.value()),
+        );
+    }
+    textures
+}
+
+/// Removes textures, and those of the images that textures used to refer to which no remaining
+/// texture refers to, renumbering the references to the rest
+fn remove_textures(root: &mut Root, unused: &HashSet<usize>, sources: &HashSet<usize>) {
+    let removed_images = sources
+        .iter()
+        .copied()
+        .filter(|&image| {
+            root.textures
+                .iter()
+                .enumerate()
+                .all(|(texture, t)| unused.contains(&texture) || t.source.value() != image)
+        })
+        .collect::<HashSet<_>>();
+    let texture_map = renumber(root.textures.len(), unused);
+    let image_map = renumber(root.images.len(), &removed_images);
+    let mut texture_index = 0;
+    root.textures.retain(|_| {
+        texture_index += 1;
+        !unused.contains(&(texture_index - 1))
+    });
+    let mut image_index = 0;
+    root.images.retain(|_| {
+        image_index += 1;
+        !removed_images.contains(&(image_index - 1))
+    });
+    for texture in root.textures.iter_mut() {
+        texture.source = Index::new(image_map[&texture.source.value()]);
+    }
+    let texture = |index: &mut Index<Texture>| *index = Index::new(texture_map[&index.value()]);
+    for material in root.materials.iter_mut() {
+        let pbr = &mut material.pbr_metallic_roughness;
+        pbr.base_color_texture
+            .iter_mut()
+            .for_each(|info| texture(&mut info.index));
+        pbr.metallic_roughness_texture
+            .iter_mut()
+            .for_each(|info| texture(&mut info.index));
+        material
+            .emissive_texture
+            .iter_mut()
+            .for_each(|info| texture(&mut info.index));
+        material
+            .normal_texture
+            .iter_mut()
+            .for_each(|info| texture(&mut info.index));
+        material
+            .occlusion_texture
+            .iter_mut()
+            .for_each(|info| texture(&mut info.index));
+    }
+}
+
+/// New indices of the elements of an array of `len` that are kept after removing `removed`
+fn renumber(len: usize, removed: &HashSet<usize>) -> HashMap<usize, u32> {
+    (0..len)
+        .filter(|index| !removed.contains(index))
+        .enumerate()
+        .map(|(new_index, old_index)| (old_index, new_index as u32))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir;
+
+    /// Writes a metallic-roughness and an occlusion image, and reads a glTF document using them
+    fn setup(name: &str, json: serde_json::Value) -> (PathBuf, Root) {
+        let dir = test_dir(name);
+        image::RgbaImage::from_pixel(2, 2, Rgba([10, 20, 30, 255]))
+            .save(dir.join("mr.png"))
+            .unwrap();
+        image::RgbaImage::from_pixel(2, 2, Rgba([200, 200, 200, 255]))
+            .save(dir.join("ao.png"))
+            .unwrap();
+        (dir, serde_json::from_value(json).unwrap())
+    }
+
+    fn texture_image(root: &Root, texture: Index<Texture>) -> &str {
+        root.images[root.textures[texture.value()].source.value()]
+            .uri
+            .as_deref()
+            .unwrap()
+    }
+
+    #[test]
+    fn packs_into_metallic_roughness_texture() {
+        let json = serde_json::json!({
+            "asset": { "version": "2.0" },
+            "images": [{ "uri": "mr.png" }, { "uri": "ao.png" }],
+            "textures": [{ "source": 0 }, { "source": 1 }],
+            "materials": [{
+                "pbrMetallicRoughness": { "metallicRoughnessTexture": { "index": 0 } },
+                "occlusionTexture": { "index": 1 }
+            }]
+        });
+        let (dir, mut root) = setup("packs_into_metallic_roughness_texture", json.clone());
+        pack_orm(&dir, &mut root, &json).unwrap();
+
+        assert_eq!(root.textures.len(), 1);
+        assert_eq!(root.images.len(), 1);
+        assert_eq!(root.images[0].uri.as_deref(), Some("mr_orm.png"));
+        let material = &root.materials[0];
+        let metallic_roughness = material
+            .pbr_metallic_roughness
+            .metallic_roughness_texture
+            .as_ref()
+            .unwrap();
+        assert_eq!(metallic_roughness.index.value(), 0);
+        assert_eq!(
+            material.occlusion_texture.as_ref().unwrap().index.value(),
+            0
+        );
+        let packed = open_image(&dir.join("mr_orm.png")).unwrap().to_rgba8();
+        assert_eq!(packed.get_pixel(1, 1).0, [200, 20, 30, 255]);
+    }
+
+    #[test]
+    fn keeps_metallic_roughness_image_used_as_occlusion() {
+        // The second material uses a pre-packed ORM texture, whose red channel is occlusion
+        let json = serde_json::json!({
+            "asset": { "version": "2.0" },
+            "images": [{ "uri": "mr.png" }, { "uri": "ao.png" }],
+            "textures": [{ "source": 0 }, { "source": 1 }],
+            "materials": [
+                {
+                    "pbrMetallicRoughness": { "metallicRoughnessTexture": { "index": 0 } },
+                    "occlusionTexture": { "index": 1 }
+                },
+                {
+                    "pbrMetallicRoughness": { "metallicRoughnessTexture": { "index": 0 } },
+                    "occlusionTexture": { "index": 0 }
+                }
+            ]
+        });
+        let (dir, mut root) = setup(
+            "keeps_metallic_roughness_image_used_as_occlusion",
+            json.clone(),
+        );
+        pack_orm(&dir, &mut root, &json).unwrap();
+
+        let first = &root.materials[0];
+        let packed = first.occlusion_texture.as_ref().unwrap().index;
+        assert_eq!(texture_image(&root, packed), "mr_ao_orm.png");
+        let second = &root.materials[1];
+        assert_eq!(
+            texture_image(&root, second.occlusion_texture.as_ref().unwrap().index),
+            "mr.png"
+        );
+        assert_eq!(
+            texture_image(
+                &root,
+                second
+                    .pbr_metallic_roughness
+                    .metallic_roughness_texture
+                    .as_ref()
+                    .unwrap()
+                    .index
+            ),
+            "mr.png"
+        );
+    }
+
+    #[test]
+    fn skips_occlusion_with_transform() {
+        let json = serde_json::json!({
+            "asset": { "version": "2.0" },
+            "images": [{ "uri": "mr.png" }, { "uri": "ao.png" }],
+            "textures": [{ "source": 0 }, { "source": 1 }],
+            "materials": [{
+                "pbrMetallicRoughness": { "metallicRoughnessTexture": { "index": 0 } },
+                "occlusionTexture": {
+                    "index": 1,
+                    "extensions": { "KHR_texture_transform": { "scale": [2.0, 2.0] } }
+                }
+            }]
+        });
+        let (dir, mut root) = setup("skips_occlusion_with_transform", json.clone());
+        pack_orm(&dir, &mut root, &json).unwrap();
+
+        assert_eq!(root.textures.len(), 2);
+        assert_eq!(root.images.len(), 2);
+        assert!(!dir.join("mr_orm.png").exists());
+    }
+}
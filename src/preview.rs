@@ -0,0 +1,410 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use basis_universal::TranscoderTextureFormat;
+use clap::Args;
+use image::{Rgba, RgbaImage};
+use serde::Serialize;
+use tracing::{error, info};
+
+use crate::{
+    image::{open_image, ImageFormat},
+    metrics,
+    transcode::BasisSource,
+    CompressionFormat, QualityMetrics,
+};
+
+/// Decodes blocks to pixels in texture2ddecoder's BGRA order
+type BlockDecoder = fn(&[u8], usize, usize, &mut [u32]) -> Result<(), &'static str>;
+
+/// A GPU format that the Basis transcoder can produce at runtime
+struct TranscodeTarget {
+    codec: CompressionFormat,
+    format: TranscoderTextureFormat,
+    decode: BlockDecoder,
+    /// Number of channels the format keeps, starting at red. Two channel formats hold the
+    /// source's red and alpha, as the transcoder expects normal maps with Y in alpha.
+    channels: usize,
+}
+
+const TRANSCODE_TARGETS: [TranscodeTarget; 10] = [
+    TranscodeTarget {
+        codec: CompressionFormat::Astc4x4,
+        format: TranscoderTextureFormat::ASTC_4x4_RGBA,
+        decode: texture2ddecoder::decode_astc_4_4,
+        channels: 4,
+    },
+    TranscodeTarget {
+        codec: CompressionFormat::Bc1,
+        format: TranscoderTextureFormat::BC1_RGB,
+        decode: texture2ddecoder::decode_bc1,
+        channels: 3,
+    },
+    TranscodeTarget {
+        codec: CompressionFormat::Bc3,
+        format: TranscoderTextureFormat::BC3_RGBA,
+        decode: texture2ddecoder::decode_bc3,
+        channels: 4,
+    },
+    TranscodeTarget {
+        codec: CompressionFormat::Bc4,
+        format: TranscoderTextureFormat::BC4_R,
+        decode: texture2ddecoder::decode_bc4,
+        channels: 1,
+    },
+    TranscodeTarget {
+        codec: CompressionFormat::Bc5,
+        format: TranscoderTextureFormat::BC5_RG,
+        decode: texture2ddecoder::decode_bc5,
+        channels: 2,
+    },
+    TranscodeTarget {
+        codec: CompressionFormat::Bc7,
+        format: TranscoderTextureFormat::BC7_RGBA,
+        decode: texture2ddecoder::decode_bc7,
+        channels: 4,
+    },
+    TranscodeTarget {
+        codec: CompressionFormat::Etc2r,
+        format: TranscoderTextureFormat::ETC2_EAC_R11,
+        decode: decode_eac_r11,
+        channels: 1,
+    },
+    TranscodeTarget {
+        codec: CompressionFormat::Etc2rg,
+        format: TranscoderTextureFormat::ETC2_EAC_RG11,
+        decode: decode_eac_rg11,
+        channels: 2,
+    },
+    // ETC1, which the transcoder writes for devices with ETC2 RGB
+    TranscodeTarget {
+        codec: CompressionFormat::Etc2rgb,
+        format: TranscoderTextureFormat::ETC1_RGB,
+        decode: texture2ddecoder::decode_etc1,
+        channels: 3,
+    },
+    TranscodeTarget {
+        codec: CompressionFormat::Etc2rgba,
+        format: TranscoderTextureFormat::ETC2_RGBA,
+        decode: texture2ddecoder::decode_etc2_rgba8,
+        channels: 4,
+    },
+];
+
+#[derive(Args, Debug)]
+pub struct Preview {
+    /// Path to the .ktx2 or .basis file holding ETC1S or UASTC
+    pub file_path: String,
+    /// Directory to write <name>_<codec>.png for each target and <name>_preview.json to
+    #[clap(short, long)]
+    output: String,
+    /// Formats to transcode to [default: astc4x4, bc1, bc3, bc4, bc5, bc7, etc2r, etc2rg,
+    /// etc2rgb, etc2rgba]
+    #[clap(long, arg_enum, use_value_delimiter = true)]
+    targets: Vec<CompressionFormat>,
+    /// The original image to measure quality against, rather than the file decoded without loss
+    /// of precision to RGBA
+    #[clap(long)]
+    reference: Option<String>,
+}
+
+/// Quality of each transcoded result, written to <name>_preview.json
+#[derive(Debug, Serialize)]
+struct PreviewReport {
+    file_path: String,
+    source_codec: CompressionFormat,
+    /// The image the metrics are measured against
+    reference_path: PathBuf,
+    entries: Vec<PreviewEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct PreviewEntry {
+    codec: CompressionFormat,
+    png_path: PathBuf,
+    /// Measured over the channels the format keeps
+    quality: QualityMetrics,
+}
+
+impl Preview {
+    pub fn process(&self) -> Result<(), String> {
+        let src_path = Path::new(&self.file_path);
+        let image_format = ImageFormat::detect(src_path, None)
+            .ok_or_else(|| format!("Unsupported image format for {}", self.file_path))?;
+        let source = BasisSource::detect(src_path, image_format)?.ok_or_else(|| {
+            format!(
+                "{} is {} rather than KTX2 or Basis",
+                self.file_path, image_format
+            )
+        })?;
+        let targets = if self.targets.is_empty() {
+            TRANSCODE_TARGETS.iter().collect::<Vec<_>>()
+        } else {
+            self.targets
+                .iter()
+                .map(|codec| {
+                    TRANSCODE_TARGETS
+                        .iter()
+                        .find(|target| target.codec == *codec)
+                        .ok_or_else(|| format!("The Basis transcoder cannot produce {}", codec))
+                })
+                .collect::<Result<Vec<_>, String>>()?
+        };
+
+        info!("Processing {} ({})", self.file_path, source.codec);
+        let output_dir = Path::new(&self.output);
+        std::fs::create_dir_all(output_dir).map_err(|e| {
+            format!(
+                "Failed to recursively create directory: {} - {}",
+                output_dir.display(),
+                e
+            )
+        })?;
+        let stem = src_path.file_stem().unwrap().to_str().unwrap();
+
+        let transcoder = source.transcoder(src_path)?;
+        let (width, height) = transcoder.dimensions;
+        let decoded = transcoder.decode()?;
+        let decoded_path = output_dir.join(format!("{}_rgba32.png", stem));
+        save_png(&decoded, &decoded_path)?;
+        let (reference, reference_path) = match &self.reference {
+            Some(reference_path) => (
+                open_image(Path::new(reference_path))?.to_rgba8(),
+                PathBuf::from(reference_path),
+            ),
+            None => (decoded, decoded_path),
+        };
+
+        let mut report = PreviewReport {
+            file_path: self.file_path.clone(),
+            source_codec: source.codec,
+            reference_path,
+            entries: Vec::new(),
+        };
+        for target in targets {
+            let png_path = output_dir.join(format!("{}_{}.png", stem, target.codec));
+            let result = transcoder
+                .transcode(target.format)
+                .and_then(|blocks| decode_blocks(&blocks, width, height, target.decode))
+                .and_then(|transcoded| {
+                    save_png(&transcoded, &png_path)?;
+                    let quality = metrics::compare(
+                        &keep_channels(
+                            &as_transcoded(&reference, target.channels),
+                            target.channels,
+                        ),
+                        &keep_channels(&transcoded, target.channels),
+                    )?;
+                    Ok(PreviewEntry {
+                        codec: target.codec,
+                        png_path: png_path.clone(),
+                        quality,
+                    })
+                });
+            match result {
+                Ok(entry) => {
+                    info!(
+                        "{} -> {} ({})",
+                        target.codec,
+                        png_path.display(),
+                        entry.quality
+                    );
+                    report.entries.push(entry);
+                }
+                Err(e) => error!("{} - {}", target.codec, e),
+            }
+        }
+
+        let report_path = output_dir.join(format!("{}_preview.json", stem));
+        let file = File::create(&report_path).map_err(|e| {
+            format!(
+                "Failed to create preview report {} - {}",
+                report_path.display(),
+                e
+            )
+        })?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &report).map_err(|e| {
+            format!(
+                "Failed to write preview report {} - {}",
+                report_path.display(),
+                e
+            )
+        })?;
+        info!("Wrote preview report at: {:?}", report_path);
+        Ok(())
+    }
+}
+
+/// Modifier tables of EAC blocks, which they share with ETC2 alpha
+const EAC_MODIFIERS: [[i32; 8]; 16] = [
+    [-3, -6, -9, -15, 2, 5, 8, 14],
+    [-3, -7, -10, -13, 2, 6, 9, 12],
+    [-2, -5, -8, -13, 1, 4, 7, 12],
+    [-2, -4, -6, -13, 1, 3, 5, 12],
+    [-3, -6, -8, -12, 2, 5, 7, 11],
+    [-3, -7, -9, -11, 2, 6, 8, 10],
+    [-4, -7, -8, -11, 3, 6, 7, 10],
+    [-3, -5, -8, -11, 2, 4, 7, 10],
+    [-2, -6, -8, -10, 1, 5, 7, 9],
+    [-2, -5, -8, -10, 1, 4, 7, 9],
+    [-2, -4, -8, -10, 1, 3, 7, 9],
+    [-2, -5, -7, -10, 1, 4, 6, 9],
+    [-3, -4, -7, -10, 2, 3, 6, 9],
+    [-1, -2, -3, -10, 0, 1, 2, 9],
+    [-4, -6, -8, -9, 3, 5, 7, 8],
+    [-3, -5, -7, -9, 2, 4, 6, 8],
+];
+
+fn decode_eac_r11(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    image: &mut [u32],
+) -> Result<(), &'static str> {
+    decode_eac(data, width, height, image, 1)
+}
+
+fn decode_eac_rg11(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    image: &mut [u32],
+) -> Result<(), &'static str> {
+    decode_eac(data, width, height, image, 2)
+}
+
+/// Decodes unsigned EAC R11 or RG11 blocks, with an 8 byte block for each channel, in
+/// texture2ddecoder's BGRA order. texture2ddecoder's own EAC decoders read the pixel indices,
+/// which are stored big-endian, in the wrong byte order.
+fn decode_eac(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    image: &mut [u32],
+    channels: usize,
+) -> Result<(), &'static str> {
+    let blocks_x = width.div_ceil(4);
+    let block_count = blocks_x * height.div_ceil(4);
+    if data.len() < block_count * 8 * channels {
+        return Err("Not enough data to decode image!");
+    }
+    image.fill(u32::from_le_bytes([0, 0, 0, u8::MAX]));
+    for (index, blocks) in data
+        .chunks_exact(8 * channels)
+        .take(block_count)
+        .enumerate()
+    {
+        for (channel, block) in blocks.chunks_exact(8).enumerate() {
+            let base = block[0] as i32 * 8 + 4;
+            // A multiplier of 0 applies the modifiers unscaled
+            let multiplier = (block[1] >> 4) as i32 * 8;
+            let modifiers = EAC_MODIFIERS[(block[1] & 0xF) as usize];
+            let indices = u64::from_be_bytes(block.try_into().unwrap());
+            // Pixels are stored column by column, starting at the most significant bits
+            for pixel_index in 0..16 {
+                let x = index % blocks_x * 4 + pixel_index / 4;
+                let y = index / blocks_x * 4 + pixel_index % 4;
+                if x >= width || y >= height {
+                    continue;
+                }
+                let modifier = modifiers[(indices >> (45 - 3 * pixel_index)) as usize & 7];
+                let value = (base + modifier * multiplier.max(1)).clamp(0, 2047);
+                let mut pixel = image[y * width + x].to_le_bytes();
+                // Red, then green
+                pixel[2 - channel] = ((value * 255 + 1023) / 2047) as u8;
+                image[y * width + x] = u32::from_le_bytes(pixel);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes transcoded blocks to an image
+fn decode_blocks(
+    blocks: &[u8],
+    width: u32,
+    height: u32,
+    decode: BlockDecoder,
+) -> Result<RgbaImage, String> {
+    let mut pixels = vec![0; width as usize * height as usize];
+    decode(blocks, width as usize, height as usize, &mut pixels)
+        .map_err(|e| format!("Failed to decode the transcoded blocks - {}", e))?;
+    let bytes = pixels
+        .into_iter()
+        .flat_map(|pixel| {
+            let [b, g, r, a] = pixel.to_le_bytes();
+            [r, g, b, a]
+        })
+        .collect();
+    Ok(RgbaImage::from_raw(width, height, bytes).unwrap())
+}
+
+fn save_png(image: &RgbaImage, path: &Path) -> Result<(), String> {
+    image
+        .save_with_format(path, ::image::ImageFormat::Png)
+        .map_err(|e| format!("Failed to write {} - {}", path.display(), e))
+}
+
+/// Moves alpha to green for two channel formats, as the transcoder does
+fn as_transcoded(image: &RgbaImage, channels: usize) -> RgbaImage {
+    let mut image = image.clone();
+    if channels == 2 {
+        for Rgba(pixel) in image.pixels_mut() {
+            pixel[1] = pixel[3];
+        }
+    }
+    image
+}
+
+/// Clears the channels a format does not keep, with alpha made opaque, so that they do not count
+/// against it
+fn keep_channels(image: &RgbaImage, channels: usize) -> RgbaImage {
+    let mut image = image.clone();
+    for Rgba(pixel) in image.pixels_mut() {
+        for (channel, value) in pixel.iter_mut().enumerate().skip(channels) {
+            *value = if channel == 3 { u8::MAX } else { 0 };
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Base 128 with a multiplier of 1 and the first modifier table. The first pixel uses -15, the
+    /// one below it 14 and the others 2.
+    const R11_BLOCK: [u8; 8] = [0x80, 0x10, 0x7E, 0x49, 0x24, 0x92, 0x49, 0x24];
+    /// Base 255 with a multiplier of 15 and 14 everywhere, which clamps to the maximum
+    const R11_WHITE_BLOCK: [u8; 8] = [0xFF, 0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+    /// Base 0 with a multiplier of 0, which applies table 13 unscaled. The first pixel uses 9 and
+    /// the others -10, which clamps to the minimum.
+    const G11_BLOCK: [u8; 8] = [0x00, 0x0D, 0xED, 0xB6, 0xDB, 0x6D, 0xB6, 0xDB];
+
+    #[test]
+    fn decodes_eac_r11() {
+        let blocks = [R11_BLOCK, R11_WHITE_BLOCK].concat();
+        let image = decode_blocks(&blocks, 8, 4, decode_eac_r11).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [113, 0, 0, 255]);
+        // Pixels are stored column by column
+        assert_eq!(image.get_pixel(0, 1).0, [142, 0, 0, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [130, 0, 0, 255]);
+        assert_eq!(image.get_pixel(3, 3).0, [130, 0, 0, 255]);
+        assert_eq!(image.get_pixel(4, 0).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(7, 3).0, [255, 0, 0, 255]);
+        assert!(decode_blocks(&R11_BLOCK, 8, 4, decode_eac_r11).is_err());
+    }
+
+    #[test]
+    fn decodes_eac_rg11() {
+        let blocks = [R11_BLOCK, G11_BLOCK].concat();
+        // Only the top left 3x3 pixels of the block are inside the image
+        let image = decode_blocks(&blocks, 3, 3, decode_eac_rg11).unwrap();
+        assert_eq!(image.get_pixel(0, 0).0, [113, 2, 0, 255]);
+        assert_eq!(image.get_pixel(0, 1).0, [142, 0, 0, 255]);
+        assert_eq!(image.get_pixel(2, 2).0, [130, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+    Backend, CompressionFormat, CompressionOptions, ContainerFormat, QualityMetrics, TextureType,
+};
+
+/// Machine-readable record of everything a run compressed
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Path to the glTF file that was processed
+    pub file_path: String,
+    /// Paths to the glTF files that were written
+    pub outputs: Vec<String>,
+    /// Settings passed to the backends, including RDO and supercompression
+    pub options: Option<CompressionOptions>,
+    pub entries: Vec<ReportEntry>,
+    /// Textures whose images could not be compressed at all
+    pub skipped: Vec<SkippedTexture>,
+}
+
+/// One attempt at compressing an image to a format. Paths are relative to the glTF file.
+#[derive(Debug, Serialize)]
+pub struct ReportEntry {
+    pub src_path: PathBuf,
+    pub dst_path: PathBuf,
+    pub backend: Backend,
+    pub compression_format: CompressionFormat,
+    pub container_format: ContainerFormat,
+    pub texture_type: TextureType,
+    /// Command lines of the external tools that were run
+    pub commands: Vec<String>,
+    pub duration_secs: f64,
+    pub src_size: Option<u64>,
+    pub dst_size: Option<u64>,
+    /// Quality of the decoded output, when it was measured
+    pub quality: Option<QualityMetrics>,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// A texture that is left as it is in every output
+#[derive(Debug, Serialize)]
+pub struct SkippedTexture {
+    /// Index of the texture in the glTF file
+    pub texture: usize,
+    /// URI of the image, or the buffer view holding it
+    pub source: String,
+    pub reason: String,
+}
+
+impl Report {
+    pub(crate) fn skip(&mut self, texture: usize, source: &str, reason: String) {
+        self.skipped.push(SkippedTexture {
+            texture,
+            source: source.to_string(),
+            reason,
+        });
+    }
+
+    pub fn write(&self, dst_path: &str) -> Result<(), String> {
+        let file = File::create(dst_path)
+            .map_err(|e| format!("Failed to create report file {} - {}", dst_path, e))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)
+            .map_err(|e| format!("Failed to write report file {} - {}", dst_path, e))?;
+        info!("Wrote report at: {:?}", dst_path);
+        Ok(())
+    }
+}
@@ -0,0 +1,413 @@
+use clap::{ArgEnum, Args};
+use image::{imageops, DynamicImage, Rgba32FImage};
+
+use crate::TextureType;
+
+/// How a material uses a texture
+#[derive(Clone, Copy, Debug, ArgEnum, strum::Display, PartialEq, Eq, Hash)]
+#[strum(serialize_all = "kebab-case")]
+pub enum TextureRole {
+    BaseColor,
+    Emissive,
+    MetallicRoughness,
+    Normal,
+    Occlusion,
+}
+
+/// Per-role size limits for common target platforms
+#[derive(Clone, Copy, Debug, ArgEnum, strum::Display, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+pub enum SizePreset {
+    /// Color textures up to 1024, other maps up to 512
+    Mobile,
+    /// Color textures up to 4096, other maps up to 2048
+    Desktop,
+}
+
+impl SizePreset {
+    pub fn max_size(&self, role: TextureRole) -> u32 {
+        let color = matches!(role, TextureRole::BaseColor | TextureRole::Emissive);
+        match (*self, color) {
+            (SizePreset::Mobile, true) => 1024,
+            (SizePreset::Mobile, false) => 512,
+            (SizePreset::Desktop, true) => 4096,
+            (SizePreset::Desktop, false) => 2048,
+        }
+    }
+}
+
+/// How to handle image dimensions that backends or GPUs may not accept
+#[derive(Clone, Copy, Debug, ArgEnum, strum::Display, PartialEq, Eq)]
+#[strum(serialize_all = "lowercase")]
+pub enum DimensionPolicy {
+    /// Pass images on as they are, warning about dimensions that are not block multiples
+    Keep,
+    /// Resize to the nearest power of two in each dimension
+    Pot,
+    /// Pad to a multiple of the codec's block size, scaling UVs with KHR_texture_transform. Falls
+    /// back to resizing to the nearest block multiple where UVs cannot be adjusted
+    Pad,
+}
+
+impl DimensionPolicy {
+    /// How an image of the given dimensions must be changed to conform to this policy, if at all
+    pub(crate) fn conform(
+        &self,
+        (width, height): (u32, u32),
+        (block_width, block_height): (u32, u32),
+        can_pad: bool,
+    ) -> Option<Conform> {
+        match *self {
+            DimensionPolicy::Keep => None,
+            DimensionPolicy::Pot if width.is_power_of_two() && height.is_power_of_two() => None,
+            DimensionPolicy::Pot => Some(Conform::Resize(
+                nearest_power_of_two(width),
+                nearest_power_of_two(height),
+            )),
+            _ if width.is_multiple_of(block_width) && height.is_multiple_of(block_height) => None,
+            DimensionPolicy::Pad if can_pad => Some(Conform::Pad(
+                width.div_ceil(block_width) * block_width,
+                height.div_ceil(block_height) * block_height,
+            )),
+            DimensionPolicy::Pad => Some(Conform::Resize(
+                nearest_multiple(width, block_width),
+                nearest_multiple(height, block_height),
+            )),
+        }
+    }
+}
+
+/// A change to the dimensions of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Conform {
+    Resize(u32, u32),
+    /// Extends the right and bottom edges, leaving the image in the top left corner
+    Pad(u32, u32),
+}
+
+impl Conform {
+    pub(crate) fn apply(&self, image: &DynamicImage, texture_type: TextureType) -> DynamicImage {
+        match *self {
+            Conform::Resize(width, height) => resize_image(image, width, height, texture_type),
+            Conform::Pad(width, height) => pad_image(image, width, height),
+        }
+    }
+
+    /// UV scale that maps the original image into a padded one
+    pub(crate) fn uv_scale(&self, (width, height): (u32, u32)) -> Option<[f32; 2]> {
+        match *self {
+            Conform::Resize(..) => None,
+            Conform::Pad(padded_width, padded_height) => Some([
+                width as f32 / padded_width as f32,
+                height as f32 / padded_height as f32,
+            ]),
+        }
+    }
+}
+
+impl std::fmt::Display for Conform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Conform::Resize(width, height) => write!(f, "resize_{}x{}", width, height),
+            Conform::Pad(width, height) => write!(f, "pad_{}x{}", width, height),
+        }
+    }
+}
+
+fn nearest_power_of_two(size: u32) -> u32 {
+    let upper = size.next_power_of_two();
+    let lower = upper / 2;
+    if lower > 0 && size - lower < upper - size {
+        lower
+    } else {
+        upper
+    }
+}
+
+fn nearest_multiple(size: u32, block_size: u32) -> u32 {
+    ((size + block_size / 2) / block_size * block_size).max(block_size)
+}
+
+/// Downscaling applied by klafsa before images are handed to a backend
+#[derive(Args, Clone, Debug)]
+pub struct Resize {
+    /// Downscale textures so that neither dimension exceeds this many texels
+    #[clap(long, parse(try_from_str = parse_max_size))]
+    pub max_size: Option<u32>,
+    /// Downscale textures by this factor, greater than 0 and at most 1, before --max-size applies
+    #[clap(long, parse(try_from_str = parse_scale))]
+    pub scale: Option<f32>,
+    /// Maximum size for textures with a role, as role=size, for example normal=512. Overrides
+    /// --max-size
+    #[clap(
+        long,
+        parse(try_from_str = parse_role_max_size),
+        use_value_delimiter = true
+    )]
+    pub role_max_size: Vec<(TextureRole, u32)>,
+    /// Scale factor for textures with a role, as role=factor, for example occlusion=0.5.
+    /// Overrides --scale
+    #[clap(long, parse(try_from_str = parse_role_scale), use_value_delimiter = true)]
+    pub role_scale: Vec<(TextureRole, f32)>,
+    /// Maximum sizes per role for a target platform, for roles without another limit
+    #[clap(long, arg_enum)]
+    pub size_preset: Option<SizePreset>,
+    /// How to handle dimensions that are not powers of two or block multiples
+    #[clap(long, arg_enum, default_value_t = DimensionPolicy::Keep)]
+    pub dimensions: DimensionPolicy,
+}
+
+impl Resize {
+    pub fn enabled(&self) -> bool {
+        self.max_size.is_some()
+            || self.scale.is_some()
+            || !self.role_max_size.is_empty()
+            || !self.role_scale.is_empty()
+            || self.size_preset.is_some()
+    }
+
+    fn max_size(&self, role: Option<TextureRole>) -> Option<u32> {
+        role.and_then(|role| {
+            self.role_max_size
+                .iter()
+                .rev()
+                .find(|(r, _)| *r == role)
+                .map(|(_, max_size)| *max_size)
+        })
+        .or(self.max_size)
+        .or_else(|| {
+            self.size_preset
+                .zip(role)
+                .map(|(preset, role)| preset.max_size(role))
+        })
+    }
+
+    fn scale(&self, role: Option<TextureRole>) -> Option<f32> {
+        role.and_then(|role| {
+            self.role_scale
+                .iter()
+                .rev()
+                .find(|(r, _)| *r == role)
+                .map(|(_, scale)| *scale)
+        })
+        .or(self.scale)
+    }
+
+    /// Dimensions to downscale an image to, or `None` if it can be kept as is. A texture with
+    /// several roles gets the largest size that any of them allows.
+    pub fn target_size(
+        &self,
+        width: u32,
+        height: u32,
+        roles: &[TextureRole],
+    ) -> Option<(u32, u32)> {
+        let roles = if roles.is_empty() {
+            vec![None]
+        } else {
+            roles.iter().copied().map(Some).collect()
+        };
+        let (target_width, target_height) = roles
+            .into_iter()
+            .map(|role| {
+                let scale = self.scale(role).unwrap_or(1.0);
+                let (mut w, mut h) = (scaled(width, scale), scaled(height, scale));
+                if let Some(max_size) = self.max_size(role) {
+                    let longest = w.max(h);
+                    if longest > max_size {
+                        let factor = max_size as f32 / longest as f32;
+                        w = scaled(w, factor).min(max_size);
+                        h = scaled(h, factor).min(max_size);
+                    }
+                }
+                (w, h)
+            })
+            .max()?;
+        if (target_width, target_height) == (width, height) {
+            None
+        } else {
+            Some((target_width, target_height))
+        }
+    }
+}
+
+fn scaled(size: u32, factor: f32) -> u32 {
+    ((size as f32 * factor).round() as u32).max(1)
+}
+
+/// Downscales an image with a Lanczos filter. Color textures are filtered in linear light and HDR
+/// textures at full range, and the bit depth of the source is kept.
+pub(crate) fn resize_image(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    texture_type: TextureType,
+) -> DynamicImage {
+    let mut rgba = image.to_rgba32f();
+    if matches!(texture_type, TextureType::Srgb) {
+        for pixel in rgba.pixels_mut() {
+            for value in pixel.0.iter_mut().take(3) {
+                *value = srgb_to_linear(*value);
+            }
+        }
+    }
+    let mut resized: Rgba32FImage =
+        imageops::resize(&rgba, width, height, imageops::FilterType::Lanczos3);
+    for pixel in resized.pixels_mut() {
+        for (channel, value) in pixel.0.iter_mut().enumerate() {
+            // Lanczos rings around sharp edges, which must not leave the valid range
+            *value = match texture_type {
+                TextureType::Hdr if channel < 3 => value.max(0.0),
+                TextureType::Srgb if channel < 3 => linear_to_srgb(value.clamp(0.0, 1.0)),
+                _ => value.clamp(0.0, 1.0),
+            };
+        }
+    }
+    with_depth_of(image, resized)
+}
+
+/// Pads an image to the given dimensions by repeating its right and bottom edges
+pub(crate) fn pad_image(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let rgba = image.to_rgba32f();
+    let padded = Rgba32FImage::from_fn(width, height, |x, y| {
+        *rgba.get_pixel(x.min(rgba.width() - 1), y.min(rgba.height() - 1))
+    });
+    with_depth_of(image, padded)
+}
+
+/// Converts a processed image back to 8 or 16 bits, or keeps floating point, like its source
+pub(crate) fn with_depth_of(source: &DynamicImage, image: Rgba32FImage) -> DynamicImage {
+    let image = DynamicImage::ImageRgba32F(image);
+    match source {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => image,
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgba16(image.to_rgba16()),
+        _ => DynamicImage::ImageRgba8(image.to_rgba8()),
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn parse_scale(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(scale) if scale > 0.0 && scale <= 1.0 => Ok(scale),
+        _ => Err(String::from(
+            "must be a number greater than 0 and at most 1",
+        )),
+    }
+}
+
+fn parse_max_size(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(size) if size > 0 => Ok(size),
+        _ => Err(String::from("must be a number greater than 0")),
+    }
+}
+
+fn parse_role<T>(
+    value: &str,
+    parse_value: fn(&str) -> Result<T, String>,
+) -> Result<(TextureRole, T), String> {
+    let (role, value) = value
+        .split_once('=')
+        .ok_or_else(|| String::from("must be given as role=value"))?;
+    let role = TextureRole::from_str(role, true).map_err(|_| {
+        format!(
+            "unknown role {} - must be one of base-color, emissive, metallic-roughness, normal \
+             and occlusion",
+            role
+        )
+    })?;
+    Ok((role, parse_value(value)?))
+}
+
+fn parse_role_max_size(value: &str) -> Result<(TextureRole, u32), String> {
+    parse_role(value, parse_max_size)
+}
+
+fn parse_role_scale(value: &str) -> Result<(TextureRole, f32), String> {
+    parse_role(value, parse_scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conform_to_blocks() {
+        assert_eq!(DimensionPolicy::Keep.conform((30, 17), (4, 4), true), None);
+        assert_eq!(
+            DimensionPolicy::Pot.conform((300, 64), (4, 4), true),
+            Some(Conform::Resize(256, 64))
+        );
+        assert_eq!(DimensionPolicy::Pad.conform((32, 16), (4, 4), true), None);
+        assert_eq!(
+            DimensionPolicy::Pad.conform((30, 17), (4, 4), true),
+            Some(Conform::Pad(32, 20))
+        );
+        assert_eq!(
+            DimensionPolicy::Pad.conform((30, 17), (4, 4), false),
+            Some(Conform::Resize(32, 16))
+        );
+        assert_eq!(
+            Conform::Pad(32, 20).uv_scale((30, 17)),
+            Some([30.0 / 32.0, 17.0 / 20.0])
+        );
+    }
+
+    #[test]
+    fn largest_size_of_roles() {
+        let resize = Resize {
+            max_size: Some(1024),
+            scale: None,
+            role_max_size: vec![(TextureRole::Normal, 256)],
+            role_scale: vec![(TextureRole::Occlusion, 0.25)],
+            size_preset: None,
+            dimensions: DimensionPolicy::Keep,
+        };
+        assert_eq!(resize.target_size(512, 512, &[]), None);
+        assert_eq!(
+            resize.target_size(2048, 1024, &[TextureRole::BaseColor]),
+            Some((1024, 512))
+        );
+        assert_eq!(
+            resize.target_size(512, 512, &[TextureRole::Normal]),
+            Some((256, 256))
+        );
+        assert_eq!(
+            resize.target_size(512, 512, &[TextureRole::Normal, TextureRole::Occlusion]),
+            Some((256, 256))
+        );
+        assert_eq!(
+            resize.target_size(512, 512, &[TextureRole::Normal, TextureRole::BaseColor]),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_zero_sizes() {
+        assert!(parse_max_size("0").is_err());
+        assert_eq!(parse_max_size("512"), Ok(512));
+        assert!(parse_scale("0").is_err());
+        assert!(parse_scale("1.5").is_err());
+        assert_eq!(
+            parse_role_max_size("normal=512"),
+            Ok((TextureRole::Normal, 512))
+        );
+    }
+}
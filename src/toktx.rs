@@ -3,18 +3,26 @@ use std::{
     process::Command,
 };
 
+use image::{Rgba32FImage, RgbaImage};
 use which::which;
 
-use crate::{CompressionFormat, Compressor, ContainerFormat, TextureType};
+use crate::{
+    run_command, write_layers, CompressError, CompressionFormat, CompressionOptions, Compressor,
+    ContainerFormat, TextureShape, TextureType,
+};
 
-pub const SUPPORTED_COMPRESSION_FORMATS: [CompressionFormat; 3] = [
+pub(crate) const SUPPORTED_COMPRESSION_FORMATS: [CompressionFormat; 3] = [
     CompressionFormat::Astc,
     CompressionFormat::Etc1s,
     CompressionFormat::Uastc,
 ];
 
+pub(crate) const DEFAULT_ZSTD_LEVEL: u8 = 18;
+
 pub struct Toktx {
     cli_path: PathBuf,
+    /// The `ktx` CLI tool from the same KTX-Software release, used for decoding
+    ktx_cli_path: Option<PathBuf>,
 }
 
 impl Toktx {
@@ -26,20 +34,23 @@ impl Toktx {
                     e
                 )
             })?,
+            ktx_cli_path: which("ktx").ok(),
         })
     }
 }
 
-impl Compressor for Toktx {
-    fn compress(
+impl Toktx {
+    /// Builds the command for everything but the output and input files. `src_path` is the first
+    /// input, which determines the number of mip levels.
+    fn command(
         &self,
         working_dir: &Path,
         src_path: &Path,
-        dst_path: &Path,
         texture_type: TextureType,
         compression_format: CompressionFormat,
         container_format: ContainerFormat,
-    ) -> Result<(), String> {
+        options: &CompressionOptions,
+    ) -> Result<Command, String> {
         if !SUPPORTED_COMPRESSION_FORMATS.contains(&compression_format)
             || !matches!(container_format, ContainerFormat::Ktx2)
         {
@@ -57,34 +68,173 @@ impl Compressor for Toktx {
             TextureType::Srgb => command.args(["--assign_oetf", "srgb"]),
             TextureType::Linear => command.args(["--assign_oetf", "linear"]),
             TextureType::NormalMap => command.args(["--normal_mode"]),
+            TextureType::Hdr => {
+                return Err(String::from("HDR textures are not supported by toktx"));
+            }
         };
-        command.args([
-            "--2d",
-            "--genmipmap",
-            "--encode",
-            compression_format.to_string().as_str(),
-            "--t2",
-        ]);
+        let mipmaps = &options.mipmaps;
+        if mipmaps.enabled() {
+            command.arg("--genmipmap");
+            if let Some(mip_filter) = mipmaps.mip_filter {
+                command.args(["--filter", mip_filter.to_string().as_str()]);
+            }
+            if let Some(mip_wrap) = mipmaps.mip_wrap {
+                command.args(["--wmode", mip_wrap.to_string().as_str()]);
+            }
+            if mipmaps.mip_min_size.is_some() {
+                let (width, height) = image::image_dimensions(working_dir.join(src_path))
+                    .map_err(|e| format!("Failed to read {} - {}", src_path.display(), e))?;
+                if let Some(levels) = mipmaps.levels(width, height) {
+                    command.args(["--levels", levels.to_string().as_str()]);
+                }
+            }
+        }
+        command.args(["--encode", compression_format.to_string().as_str(), "--t2"]);
         match compression_format {
-            CompressionFormat::Etc1s => {}
-            _ => {
-                command.args(["--zcmp", "18"]);
+            CompressionFormat::Etc1s => command.args([
+                "--clevel",
+                options.quality.etc1s_effort().to_string().as_str(),
+                "--qlevel",
+                options.quality.etc1s_quality().to_string().as_str(),
+            ]),
+            CompressionFormat::Uastc => command.args([
+                "--uastc_quality",
+                options.quality.uastc_level().to_string().as_str(),
+            ]),
+            _ => command.args([
+                "--astc_quality",
+                options.quality.astc_preset().to_string().as_str(),
+            ]),
+        };
+        if matches!(compression_format, CompressionFormat::Uastc) {
+            let supercompression = &options.supercompression;
+            if let Some(rdo_lambda) = supercompression.rdo_lambda {
+                command.args(["--uastc_rdo_l", rdo_lambda.to_string().as_str()]);
+            }
+            if let Some(rdo_dict_size) = supercompression.rdo_dict_size {
+                command.args(["--uastc_rdo_d", rdo_dict_size.to_string().as_str()]);
             }
         }
-        command.args([dst_path.to_str().unwrap(), src_path.to_str().unwrap()]);
-        match command.output() {
-            Ok(output) => {
-                if output.status.success() {
-                    Ok(())
-                } else {
-                    Err(format!(
-                        "Failed to execute command: {:?}\n{}",
-                        command.get_args(),
-                        std::str::from_utf8(&output.stderr).unwrap()
-                    ))
+        match compression_format {
+            CompressionFormat::Etc1s => {}
+            _ => match options
+                .supercompression
+                .zstd_level
+                .unwrap_or(DEFAULT_ZSTD_LEVEL)
+            {
+                0 => {}
+                zstd_level => {
+                    command.args(["--zcmp", zstd_level.to_string().as_str()]);
                 }
-            }
-            Err(e) => Err(format!("Failed to execute command: {:?}", e)),
+            },
+        }
+        Ok(command)
+    }
+}
+
+impl Compressor for Toktx {
+    fn compress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let mut command = self.command(
+            working_dir,
+            src_path,
+            texture_type,
+            compression_format,
+            container_format,
+            options,
+        )?;
+        command.arg("--2d");
+        command.args([dst_path.to_str().unwrap(), src_path.to_str().unwrap()]);
+        run_command(&mut command).map(|command_line| vec![command_line])
+    }
+
+    fn compress_layers(
+        &self,
+        working_dir: &Path,
+        layers: &[Rgba32FImage],
+        shape: TextureShape,
+        dst_path: &Path,
+        texture_type: TextureType,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+        options: &CompressionOptions,
+    ) -> Result<Vec<String>, CompressError> {
+        let layers_dir = dst_path.with_extension("layers");
+        let layer_paths = write_layers(working_dir, &layers_dir, layers, texture_type)?;
+        let result = self
+            .command(
+                working_dir,
+                &layer_paths[0],
+                texture_type,
+                compression_format,
+                container_format,
+                options,
+            )
+            .map_err(CompressError::from)
+            .and_then(|mut command| {
+                match shape {
+                    TextureShape::Cubemap => command.arg("--cubemap"),
+                    TextureShape::Array => {
+                        command.args(["--layers", layer_paths.len().to_string().as_str()])
+                    }
+                    TextureShape::Volume => {
+                        command.args(["--depth", layer_paths.len().to_string().as_str()])
+                    }
+                };
+                command.arg(dst_path);
+                command.args(&layer_paths);
+                run_command(&mut command).map(|command_line| vec![command_line])
+            });
+        let _ = std::fs::remove_dir_all(working_dir.join(&layers_dir));
+        result
+    }
+
+    /// `--normal_mode` stores X in the color channels and Y in alpha for every codec
+    fn normal_map_swizzle(&self, _compression_format: CompressionFormat) -> Option<&'static str> {
+        Some("ra01")
+    }
+
+    fn decompress(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        compression_format: CompressionFormat,
+    ) -> Result<RgbaImage, String> {
+        if !matches!(
+            compression_format,
+            CompressionFormat::Etc1s | CompressionFormat::Uastc
+        ) {
+            return Err(format!(
+                "Decompressing {} is not supported by toktx",
+                compression_format
+            ));
         }
+        let ktx_cli_path = self.ktx_cli_path.as_ref().ok_or_else(|| {
+            String::from("Failed to find the ktx CLI tool. Make sure it is in your PATH.")
+        })?;
+        let decoded_path = src_path.with_extension("decoded.png");
+        let mut command = Command::new(ktx_cli_path);
+        command.current_dir(working_dir);
+        command.args([
+            "extract",
+            "--transcode",
+            "rgba8",
+            src_path.to_str().unwrap(),
+            decoded_path.to_str().unwrap(),
+        ]);
+        run_command(&mut command)?;
+        let image = image::open(working_dir.join(&decoded_path))
+            .map(|image| image.to_rgba8())
+            .map_err(|e| format!("Failed to read {} - {}", decoded_path.display(), e));
+        let _ = std::fs::remove_file(working_dir.join(&decoded_path));
+        image
     }
 }
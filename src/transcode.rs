@@ -0,0 +1,255 @@
+use std::path::{Path, PathBuf};
+
+use ::image::{DynamicImage, RgbaImage};
+use basis_universal::{TranscodeParameters, Transcoder, TranscoderTextureFormat};
+
+use crate::{
+    image::{write_intermediate_png, ImageFormat},
+    ktx::{convert_ktx2_to_basis, KHR_DF_MODEL_ETC1S, KHR_DF_MODEL_UASTC},
+    ktx_create::transcode_target,
+    CompressionFormat, ContainerFormat,
+};
+
+/// Offset of basis_file_header::m_tex_format, which is 0 for ETC1S and 1 for UASTC
+const BASIS_TEX_FORMAT_OFFSET: usize = 20;
+
+/// A KTX2 or Basis source image that is already compressed to ETC1S or UASTC, which GPUs cannot
+/// sample until it is transcoded
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BasisSource {
+    pub codec: CompressionFormat,
+    pub container: ContainerFormat,
+}
+
+/// A Basis source prepared for the Basis Universal transcoder, with the dimensions of its first
+/// image
+pub(crate) struct BasisTranscoder {
+    transcoder: Transcoder,
+    path: PathBuf,
+    /// The source as a .basis file, which is the only container the transcoder reads
+    data: Vec<u8>,
+    pub dimensions: (u32, u32),
+}
+
+impl BasisTranscoder {
+    /// Transcodes the first mip level to a GPU format
+    pub(crate) fn transcode(&self, format: TranscoderTextureFormat) -> Result<Vec<u8>, String> {
+        self.transcoder
+            .transcode_image_level(&self.data, format, TranscodeParameters::default())
+            .map_err(|e| format!("Failed to transcode {} - {:?}", self.path.display(), e))
+    }
+
+    /// Decodes the first mip level without further loss
+    pub(crate) fn decode(&self) -> Result<RgbaImage, String> {
+        let (width, height) = self.dimensions;
+        self.transcode(TranscoderTextureFormat::RGBA32)
+            .and_then(|pixels| {
+                RgbaImage::from_raw(width, height, pixels)
+                    .ok_or_else(|| format!("Failed to decode {}", self.path.display()))
+            })
+    }
+}
+
+/// How an output is made from a Basis source without decoding and encoding it again
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Reuse {
+    /// The source is already in the requested codec and container, and is copied unchanged
+    PassThrough,
+    /// The source is transcoded with `ktx transcode` to the target
+    Transcode(&'static str),
+}
+
+impl BasisSource {
+    /// Reads the codec of a KTX2 or Basis file, or returns `None` for other formats. KTX2 files
+    /// in other codecs are rejected, as klafsa cannot decode them.
+    pub(crate) fn detect(path: &Path, image_format: ImageFormat) -> Result<Option<Self>, String> {
+        let container = match image_format {
+            ImageFormat::Basis => ContainerFormat::Basis,
+            ImageFormat::Ktx2 => ContainerFormat::Ktx2,
+            _ => return Ok(None),
+        };
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("Failed to read {} - {}", path.display(), e))?;
+        let read_u32 = |offset: usize| {
+            bytes
+                .get(offset..offset + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or_else(|| format!("Unexpected end of {}", path.display()))
+        };
+        let codec = match container {
+            ContainerFormat::Basis => match bytes.get(BASIS_TEX_FORMAT_OFFSET) {
+                Some(0) => Some(CompressionFormat::Etc1s),
+                Some(1) => Some(CompressionFormat::Uastc),
+                _ => None,
+            },
+            _ => {
+                // The color model of the data format descriptor's first block
+                let color_model = bytes.get(read_u32(48)? as usize + 12).copied();
+                match (read_u32(12)?, color_model) {
+                    (0, Some(KHR_DF_MODEL_ETC1S)) => Some(CompressionFormat::Etc1s),
+                    (0, Some(KHR_DF_MODEL_UASTC)) => Some(CompressionFormat::Uastc),
+                    _ => None,
+                }
+            }
+        };
+        match codec {
+            Some(codec) => Ok(Some(Self { codec, container })),
+            None => Err(format!(
+                "{} is neither ETC1S nor UASTC, which are the only compressed sources supported",
+                path.display()
+            )),
+        }
+    }
+
+    /// Reads the source and prepares it for transcoding. KTX2 files are rewrapped as .basis
+    /// files in memory.
+    pub(crate) fn transcoder(&self, path: &Path) -> Result<BasisTranscoder, String> {
+        let data = match self.container {
+            ContainerFormat::Basis => std::fs::read(path)
+                .map_err(|e| format!("Failed to read {} - {}", path.display(), e))?,
+            _ => convert_ktx2_to_basis(path)?,
+        };
+        let mut transcoder = Transcoder::new();
+        transcoder
+            .prepare_transcoding(&data)
+            .map_err(|_| format!("Failed to prepare {} for transcoding", path.display()))?;
+        let description = transcoder
+            .image_level_description(&data, 0, 0)
+            .ok_or_else(|| format!("{} has no images", path.display()))?;
+        Ok(BasisTranscoder {
+            transcoder,
+            path: path.to_path_buf(),
+            data,
+            dimensions: (description.original_width, description.original_height),
+        })
+    }
+
+    /// Decodes the first mip level to a PNG file with the Basis Universal transcoder
+    pub(crate) fn decode(
+        &self,
+        working_dir: &Path,
+        src_path: &Path,
+        dst_path: &Path,
+    ) -> Result<(), String> {
+        let image = self.transcoder(&working_dir.join(src_path))?.decode()?;
+        write_intermediate_png(
+            DynamicImage::ImageRgba8(image),
+            src_path,
+            &working_dir.join(dst_path),
+        )
+    }
+
+    /// How to make an output in a codec and container from the source as it is, if possible.
+    /// DDS files are rewrapped from a transcoded KTX2 file.
+    pub(crate) fn reuse(
+        &self,
+        compression_format: CompressionFormat,
+        container_format: ContainerFormat,
+    ) -> Option<Reuse> {
+        if compression_format == self.codec && container_format == self.container {
+            return Some(Reuse::PassThrough);
+        }
+        if !matches!(self.container, ContainerFormat::Ktx2)
+            || !matches!(
+                container_format,
+                ContainerFormat::Ktx2 | ContainerFormat::Dds
+            )
+        {
+            return None;
+        }
+        match compression_format {
+            // The transcoders only produce 4x4 ASTC blocks
+            CompressionFormat::Astc | CompressionFormat::Astc4x4 => Some("astc"),
+            format => transcode_target(format),
+        }
+        .map(Reuse::Transcode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ktx::write_ktx2, test_dir};
+
+    /// Writes a KTX2 file whose data format descriptor has the given color model
+    fn ktx2_source(dir: &Path, name: &str, vk_format: u32, color_model: u8) -> Option<BasisSource> {
+        let path = dir.join(name);
+        let mut dfd = vec![0; 44];
+        dfd[12] = color_model;
+        write_ktx2(&path, vk_format, (4, 4), &dfd, &[vec![0; 16]]).unwrap();
+        BasisSource::detect(&path, ImageFormat::Ktx2).unwrap()
+    }
+
+    #[test]
+    fn detects_basis_codecs() {
+        let dir = test_dir("detects_basis_codecs");
+        let uastc = ktx2_source(&dir, "uastc.ktx2", 0, KHR_DF_MODEL_UASTC).unwrap();
+        assert_eq!(uastc.codec, CompressionFormat::Uastc);
+        let etc1s = ktx2_source(&dir, "etc1s.ktx2", 0, KHR_DF_MODEL_ETC1S).unwrap();
+        assert_eq!(etc1s.codec, CompressionFormat::Etc1s);
+
+        // VK_FORMAT_BC1_RGB_UNORM_BLOCK, whose color model is BC1A
+        let path = dir.join("bc1.ktx2");
+        write_ktx2(&path, 131, (4, 4), &[0; 44], &[vec![0; 8]]).unwrap();
+        assert!(BasisSource::detect(&path, ImageFormat::Ktx2).is_err());
+
+        let path = dir.join("uastc.basis");
+        let mut basis = vec![0; 77];
+        basis[BASIS_TEX_FORMAT_OFFSET] = 1;
+        std::fs::write(&path, basis).unwrap();
+        let source = BasisSource::detect(&path, ImageFormat::Basis)
+            .unwrap()
+            .unwrap();
+        assert_eq!(source.codec, CompressionFormat::Uastc);
+        assert!(matches!(source.container, ContainerFormat::Basis));
+
+        let path = dir.join("image.png");
+        std::fs::write(&path, []).unwrap();
+        assert!(BasisSource::detect(&path, ImageFormat::Png)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn passes_through_or_transcodes() {
+        let ktx2 = BasisSource {
+            codec: CompressionFormat::Uastc,
+            container: ContainerFormat::Ktx2,
+        };
+        assert!(matches!(
+            ktx2.reuse(CompressionFormat::Uastc, ContainerFormat::Ktx2),
+            Some(Reuse::PassThrough)
+        ));
+        assert!(matches!(
+            ktx2.reuse(CompressionFormat::Bc7, ContainerFormat::Dds),
+            Some(Reuse::Transcode("bc7"))
+        ));
+        assert!(matches!(
+            ktx2.reuse(CompressionFormat::Astc4x4, ContainerFormat::Ktx2),
+            Some(Reuse::Transcode("astc"))
+        ));
+        // Other block sizes and codecs are encoded again
+        assert!(ktx2
+            .reuse(CompressionFormat::Astc6x6, ContainerFormat::Ktx2)
+            .is_none());
+        assert!(ktx2
+            .reuse(CompressionFormat::Etc1s, ContainerFormat::Ktx2)
+            .is_none());
+        assert!(ktx2
+            .reuse(CompressionFormat::Bc7, ContainerFormat::Basis)
+            .is_none());
+
+        // ktx transcode only reads KTX2
+        let basis = BasisSource {
+            codec: CompressionFormat::Etc1s,
+            container: ContainerFormat::Basis,
+        };
+        assert!(matches!(
+            basis.reuse(CompressionFormat::Etc1s, ContainerFormat::Basis),
+            Some(Reuse::PassThrough)
+        ));
+        assert!(basis
+            .reuse(CompressionFormat::Bc7, ContainerFormat::Ktx2)
+            .is_none());
+    }
+}